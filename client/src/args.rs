@@ -1,12 +1,20 @@
-use std::sync::Arc;
+use std::{str::FromStr, sync::Arc, time::Duration};
 
 use crate::{
     cache::Cache,
     completion::{codex::CodexClientBuilder, ArcCompletionEngine, CompletionClientBuilder},
     completion::{local::LocalModelClientBuilder, ArcCompletionModel},
     get_path_from_rootdir,
-    langserver::{py::PyServer, ts::TsServer, ArcLangServer, LangServer},
+    langserver::{
+        generic::{GenericLangServerBuilder, LangServerConfig},
+        pool::LangServerPool,
+        py::{PyServerBuilder, PyTypeChecker},
+        ts::TsServerBuilder,
+        AnnotateType, ArcLangServer, CompilerOptions, ProblemWeights,
+    },
     main_strategies::{MainStrategy, SimpleStrategy, TreeStrategy},
+    socket::{exit, SocketTimeouts, Transport},
+    tree::merge::MergeMode,
 };
 use tokio::sync::Mutex;
 
@@ -22,10 +30,15 @@ pub struct Args {
     pub tokens: Option<String>,
 
     /// The target language.
-    /// Either {"ts", "py"}.
+    /// Either {"ts", "py", "js"}. "js" writes the types as JSDoc comments.
     #[clap(short, long, value_parser, default_value = "ts")]
     pub lang: String,
 
+    /// Path to a TOML or JSON config of an external language server, used instead of the
+    /// built-in server of `--lang`. See `GenericLangServer` for the format.
+    #[clap(long, value_parser)]
+    pub lang_config: Option<String>,
+
     /// The target file path
     #[clap(short, long, value_parser)]
     pub file: String,
@@ -82,6 +95,14 @@ pub struct Args {
     #[clap(long, short, value_parser, default_value_t = 1000)]
     pub max_type_quality: u16,
 
+    /// Score penalties for the problems found by the heuristics, instead of rejecting the
+    /// completion (comma-separated `Problem=weight`, where the weight may be `reject`).
+    /// By default, UsedAnyType, RenamedIdentifier and ChangedFormattingOnly have weight 0,
+    /// and the other problems reject the completion.
+    /// e.g. "UsedAnyType=100,AddedImports=300,RenamedIdentifier=reject"
+    #[clap(long, value_parser)]
+    pub problem_weights: Option<String>,
+
     /// Disables type-checking and just outputs all candidates
     #[clap(long, value_parser, default_value_t = false)]
     pub disable_type_check: bool,
@@ -94,6 +115,32 @@ pub struct Args {
     #[clap(long, value_parser)]
     pub depth_limit: Option<usize>,
 
+    /// How the tree strategy merges the completions of the children into their parent. Either:
+    /// {"Poisson": all combinations within the budget, a random sample otherwise,
+    /// "Beam": the best-ranked combinations within the budget,
+    /// "AllCombinations": all combinations regardless of the budget}
+    #[clap(long, value_parser, default_value = "Poisson")]
+    pub merge: MergeMode,
+
+    /// Seed for the random choices of the tree strategy, such that a run with the same model
+    /// outputs can be reproduced. By default, the choices are random
+    #[clap(long, value_parser)]
+    pub seed: Option<u64>,
+
+    /// Type checks the completions of each node of the tree strategy in its parent, and drops
+    /// the ones that add type errors before merging them into the level above
+    #[clap(long, value_parser, default_value_t = false)]
+    pub enable_pruning: bool,
+
+    /// Writes a checkpoint of the tree strategy to the given file after every completed level
+    #[clap(long, value_parser)]
+    pub checkpoint: Option<String>,
+
+    /// Resumes the tree strategy from the deepest unfinished level of the `--checkpoint` file.
+    /// Fails if the checkpoint can't be loaded or is for another input or other parameters
+    #[clap(long, value_parser, default_value_t = false, requires = "checkpoint")]
+    pub resume: bool,
+
     /// Disables the usage blocks in the tree strategy prompts
     #[clap(long, value_parser, default_value_t = false)]
     pub disable_usages: bool,
@@ -102,34 +149,267 @@ pub struct Args {
     #[clap(long, value_parser, default_value_t = false)]
     pub disable_stubbing: bool,
 
+    /// Timeout in seconds for language server commands. By default, there is no timeout.
+    #[clap(long, value_parser)]
+    pub ls_timeout: Option<u64>,
+
+    /// Timeout in seconds for the type checking command of the language server.
+    /// Overrides `--ls-timeout` for type checking.
+    #[clap(long, value_parser)]
+    pub typecheck_timeout: Option<u64>,
+
+    /// Timeout in seconds for a single sampling request to a local model.
+    /// By default, there is no timeout.
+    #[clap(long, value_parser)]
+    pub model_timeout: Option<u64>,
+
+    /// How to talk to the language server. Either: {"socket": through a unix socket file,
+    /// "stdio": through the stdin and stdout of the server}. Local model servers only support
+    /// sockets.
+    #[clap(long, value_parser, default_value = "socket")]
+    pub ls_transport: String,
+
+    /// The type checker used for Python files. Either {"mypy", "pyright"}
+    #[clap(long, value_parser, default_value = "mypy")]
+    pub py_checker: String,
+
+    /// Prints the diagnostics of the type checker for each of the written completions
+    #[clap(long, value_parser, default_value_t = false)]
+    pub diagnostics: bool,
+
+    /// The number of language servers to spawn. Commands are distributed across them.
+    #[clap(long, value_parser, default_value_t = 1)]
+    pub ls_pool_size: usize,
+
+    /// Type checks the target file as part of a project, such that imports of other files of
+    /// the project resolve. Either the root directory of the project, or a path to a
+    /// `tsconfig.json` for typescript. The target file must be inside the project.
+    /// Not supported with `--lang-config`.
+    #[clap(long, value_parser)]
+    pub project: Option<String>,
+
+    /// The compiler options that candidates are type checked with, either a JSON object
+    /// (e.g. '{"strict": true}') or the path to a `tsconfig.json`. They override the defaults
+    /// of the server, or the options of the project with `--project`. Not used for python.
+    #[clap(long, value_parser)]
+    pub compiler_options: Option<String>,
+
     /// List of statements to exclude from being annotated (comma-separated).
-    /// You can exclude the following types: {"VarDecl", "FuncDecl", "FuncExpr", "ClassProp", "ClassMethod", "TypeDecl"}
+    /// You can exclude the following types: {"VarDecl", "FuncDecl", "FuncExpr", "ClassProp", "ClassMethod", "TypeDecl",
+    /// "ObjectLiteralMember"}, and the following positions:
+    /// {"Param", "Return", "Destructuring"}, e.g. excluding "Return" only annotates parameters.
+    /// At least one of the positions has to be kept.
     #[clap(long, value_parser)]
     pub exclude: Option<String>,
+
+    /// List of optional statements to annotate as well (comma-separated), which are not
+    /// annotated by default: {"TypeParam", "CatchClause"}.
+    #[clap(long, value_parser)]
+    pub include: Option<String>,
+
+    /// Audits the existing annotations of the file instead of completing it: they are stripped,
+    /// inferred again with the strategy, and compared with the written ones. The report is
+    /// written to `audit.json` in the output directory.
+    #[clap(long, value_parser, default_value_t = false)]
+    pub audit: bool,
 }
 
 impl Args {
+    /// Builds the timeouts for the language server commands from the given arguments.
+    pub fn ls_timeouts(&self) -> SocketTimeouts {
+        let mut timeouts = SocketTimeouts::new();
+        if let Some(secs) = self.ls_timeout {
+            timeouts = timeouts.default_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.typecheck_timeout {
+            timeouts = timeouts.cmd_timeout("typecheck", Duration::from_secs(secs));
+        }
+        timeouts
+    }
+
+    /// Computes the path of the target file relative to the root of the project, if
+    /// `--project` is given. Exits if the target file is not inside the project.
+    pub fn project_file(&self) -> Option<String> {
+        let project = self.project.as_ref()?;
+        let canon = |p: &str| {
+            std::fs::canonicalize(p).unwrap_or_else(|e| {
+                eprintln!("Failed to resolve {p}: {e}");
+                exit(1);
+            })
+        };
+        let mut root = canon(project);
+        if root.is_file() {
+            // a path to the tsconfig.json, the project is its directory
+            root.pop();
+        }
+        let file = canon(&self.file);
+        match file.strip_prefix(&root) {
+            Ok(rel) => Some(rel.to_string_lossy().to_string()),
+            Err(_) => {
+                eprintln!(
+                    "The target file {} is not inside the project {project}",
+                    self.file
+                );
+                exit(1);
+            }
+        }
+    }
+
+    /// Parses the given transport argument, exiting if it is unknown.
+    fn parse_transport(transport: &str) -> Transport {
+        transport.parse().unwrap_or_else(|_| {
+            eprintln!("Unknown transport, {transport}");
+            exit(1);
+        })
+    }
+
+    /// Parses a comma-separated list of annotate types, exiting if one is unknown.
+    fn parse_annotate_types(list: &str) -> Vec<AnnotateType> {
+        list.split(',')
+            .map(|s| {
+                AnnotateType::from_str(s).unwrap_or_else(|_| {
+                    eprintln!("Unknown type to annotate, {s}");
+                    exit(1);
+                })
+            })
+            .collect()
+    }
+
+    /// The types to annotate: the default ones, plus the ones in `--include`, minus the ones
+    /// in `--exclude`. Exits if one of the lists has an unknown type.
+    pub fn annotate_types(&self) -> Vec<AnnotateType> {
+        let mut types = AnnotateType::all();
+        if let Some(include) = &self.include {
+            for ty in Self::parse_annotate_types(include) {
+                if !AnnotateType::optional().contains(&ty) {
+                    eprintln!("{ty:?} is annotated by default, it can't be included");
+                    exit(1);
+                }
+                if !types.contains(&ty) {
+                    types.push(ty);
+                }
+            }
+        }
+        if let Some(exclude) = &self.exclude {
+            let exclude = Self::parse_annotate_types(exclude);
+            types.retain(|ty| !exclude.contains(ty));
+        }
+        // a list without positions would be read as one that annotates all of them
+        if !AnnotateType::positions().iter().any(|p| types.contains(p)) {
+            eprintln!("At least one of Param, Return and Destructuring has to be annotated");
+            exit(1);
+        }
+        types
+    }
+
+    /// Loads the config given with `--lang-config`, exiting if it is invalid.
+    pub fn load_lang_config(&self) -> Option<LangServerConfig> {
+        let path = self.lang_config.as_ref()?;
+        Some(LangServerConfig::load(path).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            exit(1);
+        }))
+    }
+
+    /// The extension of the output files.
+    pub fn output_extension(&self) -> String {
+        match self.load_lang_config() {
+            Some(config) => config.extension().to_string(),
+            None => self.lang.clone(),
+        }
+    }
+
+    /// Parses the options given with `--compiler-options`, exiting if they are invalid.
+    pub fn parse_compiler_options(&self) -> Option<CompilerOptions> {
+        let options = self.compiler_options.as_ref()?;
+        Some(CompilerOptions::from_str(options).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            exit(1);
+        }))
+    }
+
     pub async fn lang_client_factory(&self) -> ArcLangServer {
+        let compiler_options = self.parse_compiler_options();
+        if let Some(config) = self.load_lang_config() {
+            // the config has no way to start the server in a project
+            if self.project.is_some() {
+                eprintln!("--project can't be used with --lang-config");
+                exit(1);
+            }
+            let timeouts = self.ls_timeouts();
+            let transport = Self::parse_transport(&self.ls_transport);
+            return Arc::new(
+                LangServerPool::spawn(self.ls_pool_size, || {
+                    let mut builder = GenericLangServerBuilder::new(config.clone())
+                        .timeouts(timeouts.clone())
+                        .transport(transport);
+                    if let Some(options) = &compiler_options {
+                        builder = builder.compiler_options(options.clone());
+                    }
+                    builder.build()
+                })
+                .await
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to make {} server: {e}", config.name);
+                    exit(1);
+                }),
+            );
+        }
         match self.lang.as_str() {
-            "ts" => {
+            "ts" | "js" => {
                 let path = get_path_from_rootdir("ts-compiler".to_string());
+                let timeouts = self.ls_timeouts();
+                let transport = Self::parse_transport(&self.ls_transport);
                 Arc::new(
-                    TsServer::make(&path)
-                        .await
-                        .expect("failed to make ts server"),
+                    LangServerPool::spawn(self.ls_pool_size, || {
+                        let mut builder = TsServerBuilder::new(&path)
+                            .timeouts(timeouts.clone())
+                            .transport(transport)
+                            .jsdoc(self.lang == "js");
+                        if let Some(project) = &self.project {
+                            builder = builder.project(project);
+                        }
+                        if let Some(options) = &compiler_options {
+                            builder = builder.compiler_options(options.clone());
+                        }
+                        builder.build()
+                    })
+                    .await
+                    .unwrap_or_else(|e| {
+                        eprintln!("Failed to make ts server: {e}");
+                        exit(1);
+                    }),
                 )
             }
             "py" => {
                 let path = get_path_from_rootdir("py-ast".to_string());
+                let checker: PyTypeChecker = self.py_checker.parse().unwrap_or_else(|_| {
+                    eprintln!("Unknown python type checker, {}", self.py_checker);
+                    exit(1);
+                });
+                let timeouts = self.ls_timeouts();
+                let transport = Self::parse_transport(&self.ls_transport);
                 Arc::new(
-                    PyServer::make(&path)
-                        .await
-                        .expect("failed to make py server"),
+                    LangServerPool::spawn(self.ls_pool_size, || {
+                        let mut builder = PyServerBuilder::new(&path)
+                            .timeouts(timeouts.clone())
+                            .transport(transport)
+                            .checker(checker);
+                        if let Some(project) = &self.project {
+                            builder = builder.project(project);
+                        }
+                        builder.build()
+                    })
+                    .await
+                    .unwrap_or_else(|e| {
+                        eprintln!("Failed to make py server: {e}");
+                        exit(1);
+                    }),
                 )
             }
             _ => {
                 eprintln!("Unknown language, {}", self.lang);
-                std::process::exit(1);
+                exit(1);
             }
         }
     }
@@ -147,7 +427,7 @@ impl Args {
                     .as_ref()
                     .unwrap_or_else(|| {
                         eprintln!("Codex tokens are required");
-                        std::process::exit(1);
+                        exit(1);
                     })
                     .split(',')
                     .map(|s| s.to_string())
@@ -164,22 +444,30 @@ impl Args {
                 if let Some(endpoint) = &self.endpoint {
                     builder = builder.socket_path(endpoint.clone());
                 }
+                if let Some(secs) = self.model_timeout {
+                    builder = builder.timeout(Duration::from_secs(secs));
+                }
 
-                Arc::new(
-                    builder
-                        .build()
-                        .await
-                        .unwrap_or_else(|_| panic!("failed to make {} client", self.engine)),
-                )
+                Arc::new(builder.build().await.unwrap_or_else(|e| {
+                    eprintln!("Failed to make {} client: {e}", self.engine);
+                    exit(1);
+                }))
             }
             _ => {
                 eprintln!("Unknown engine, {}", self.engine);
-                std::process::exit(1);
+                exit(1);
             }
         };
         let mut engine = CompletionClientBuilder::new(ls, model)
             .temperature(self.temp)
             .max_type_score(self.max_type_quality);
+        if let Some(weights) = &self.problem_weights {
+            let weights = ProblemWeights::from_str(weights).unwrap_or_else(|e| {
+                eprintln!("Invalid problem weights: {e}");
+                exit(1);
+            });
+            engine = engine.problem_weights(weights);
+        }
         if let Some(cache) = cache {
             engine = engine.cache(cache);
         }
@@ -195,7 +483,7 @@ impl Args {
             "tree" => Box::new(TreeStrategy { stats: None }),
             _ => {
                 eprintln!("Unknown strategy, {}", self.strategy);
-                std::process::exit(1);
+                exit(1);
             }
         }
     }
@@ -0,0 +1,66 @@
+//! Audit mode, which uses the completion engine as a reviewer of code that is already typed.
+//! The existing annotations are stripped and inferred again with one of the strategies, then
+//! the language server compares every inferred type with the one that was written in its place.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    completion::{sort_completions, CompletionError},
+    langserver::{AnnotationAudit, TypeRelation},
+    main_strategies::{MainCtx, MainStrategy},
+};
+
+/// The result of an audit: the written annotations compared with the ones of the best
+/// completion.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditReport {
+    pub annotations: Vec<AnnotationAudit>,
+    /// the number of type errors of the completion that the annotations were inferred in
+    pub num_type_errors: usize,
+    /// the score of the completion that the annotations were inferred in
+    pub score: u16,
+}
+
+impl AuditReport {
+    /// The annotations where the inferred type is not the same as the written one.
+    pub fn disagreements(&self) -> impl Iterator<Item = &AnnotationAudit> {
+        self.annotations
+            .iter()
+            .filter(|a| a.relation != TypeRelation::Same)
+    }
+
+    /// The number of annotations with the given relation.
+    pub fn count(&self, relation: TypeRelation) -> usize {
+        self.annotations
+            .iter()
+            .filter(|a| a.relation == relation)
+            .count()
+    }
+}
+
+/// Audits the annotations of the kinds in `ctx.types` that are written in `ctx.file_contents`,
+/// inferring them again with the given strategy. Requires the `strip` and `audit` commands.
+pub async fn run_audit(
+    strategy: &dyn MainStrategy,
+    mut ctx: MainCtx,
+) -> Result<AuditReport, CompletionError> {
+    ctx.check_capabilities(&["strip", "audit"]).await?;
+    let ls = ctx.engine.get_ls();
+    let types = ctx.types.clone();
+    let original = std::mem::take(&mut ctx.file_contents);
+    ctx.file_contents = ls.strip(&original, &types).await?;
+
+    let mut comps = strategy.run(ctx).await?;
+    sort_completions(&mut comps);
+    let best = comps
+        .into_iter()
+        .next()
+        .ok_or(CompletionError::CouldNotComplete)?;
+
+    let annotations = ls.audit(&original, &best.code, &types).await?;
+    Ok(AuditReport {
+        annotations,
+        num_type_errors: best.num_type_errors,
+        score: best.score,
+    })
+}
@@ -39,7 +39,7 @@ impl Cache {
         let key = self.to_key(query);
         let value = serde_json::json!(result).to_string();
 
-        self.redis.set(key, value)?;
+        self.redis.set::<_, _, ()>(key, value)?;
         Ok(())
     }
 
@@ -7,7 +7,10 @@ use tokio::{sync::Mutex, task::JoinHandle};
 use crate::{
     cache::Cache,
     debug,
-    langserver::{ArcLangServer, CheckProblem, LangServer, LangServerError},
+    langserver::{
+        ArcLangServer, CheckProblem, Diagnostic, LangServer, LangServerError, ProblemWeights,
+        TypeQuality,
+    },
     socket::SocketError,
 };
 
@@ -42,6 +45,9 @@ pub trait CompletionEngine: std::fmt::Debug {
     /// Gets the maximum type score allowed for a completion.
     fn get_max_type_score(&self) -> u16;
 
+    /// Gets the score penalties of the check problems of a completion.
+    fn get_problem_weights(&self) -> ProblemWeights;
+
     /// Gets a mutex guard to the cache from the codex client.
     /// If the given completion engine does not use a cache, this will return None.
     async fn get_cache(&self) -> Option<tokio::sync::MutexGuard<Cache>>;
@@ -57,7 +63,7 @@ pub trait CompletionModel: std::fmt::Debug {
         &self,
         query: &CompletionQuery,
         engine: &dyn CompletionEngine,
-        filtered_completions: Arc<Mutex<Vec<(String, u16)>>>,
+        filtered_completions: Arc<Mutex<Vec<Completion>>>,
     ) -> JoinHandle<Result<(), ModelResponseError>>;
 }
 
@@ -163,6 +169,9 @@ pub struct Completion {
     pub score: u16,
     /// is this completion from fallback?
     pub fallbacked: bool,
+    /// the breakdown of the score, if the language server reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<TypeQuality>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -173,8 +182,14 @@ pub struct TypecheckedCompletion {
     pub score: u16,
     /// is this completion from fallback?
     pub fallbacked: bool,
+    /// the breakdown of the score, if the language server reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<TypeQuality>,
     /// the number of type errors in the completion. if 0, no type errors.
     pub num_type_errors: usize,
+    /// the diagnostics of the type checker, if they were requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<Vec<Diagnostic>>,
 }
 
 impl TypecheckedCompletion {
@@ -183,7 +198,19 @@ impl TypecheckedCompletion {
             code: completion.code,
             score: completion.score,
             fallbacked: completion.fallbacked,
+            quality: completion.quality,
             num_type_errors,
+            diagnostics: None,
+        }
+    }
+
+    /// Makes a typechecked completion from the diagnostics of the type checker,
+    /// counting the errors among them.
+    pub fn with_diagnostics(completion: Completion, diagnostics: Vec<Diagnostic>) -> Self {
+        let num_type_errors = diagnostics.iter().filter(|d| d.is_error()).count();
+        Self {
+            diagnostics: Some(diagnostics),
+            ..Self::new(completion, num_type_errors)
         }
     }
 }
@@ -194,6 +221,7 @@ impl From<TypecheckedCompletion> for Completion {
             code: tc.code,
             score: tc.score,
             fallbacked: tc.fallbacked,
+            quality: tc.quality,
         }
     }
 }
@@ -221,6 +249,8 @@ pub enum CompletionError {
     Socket(#[from] SocketError),
     #[error("Completion engine could not complete")]
     CouldNotComplete,
+    #[error("Could not resume from the checkpoint: {0}")]
+    Checkpoint(String),
 }
 
 #[derive(Debug, Error)]
@@ -239,13 +269,15 @@ pub enum ModelResponseError {
     Socket(#[from] SocketError),
 }
 
-/// Filters out completions that don't follow certain rules.
-async fn filter_comps(
-    filtered_completions: Arc<Mutex<Vec<(String, u16)>>>,
+/// Filters out completions that don't follow certain rules. Check problems that have a weight
+/// are added to the score of the completion instead.
+pub(crate) async fn filter_comps(
+    filtered_completions: Arc<Mutex<Vec<Completion>>>,
     lang_client: ArcLangServer,
     input_text: &str,
     comp_text: String,
     problem_whitelist: Vec<CheckProblem>,
+    problem_weights: ProblemWeights,
     max_type_score: u16,
 ) -> Result<(), ModelResponseError> {
     // check first if it's duplicate in our filtered completions
@@ -253,10 +285,10 @@ async fn filter_comps(
         .lock()
         .await
         .iter()
-        .any(|(c, _)| c == &comp_text)
+        .any(|c| c.code == comp_text)
     {
-        let (problems, score) = lang_client
-            .check_complete(input_text, &comp_text)
+        let (problems, score, quality) = lang_client
+            .check_complete_detailed(input_text, &comp_text)
             .await
             .map_err(|e| {
                 println!("Error checking completion: {e}");
@@ -264,10 +296,21 @@ async fn filter_comps(
             })?;
 
         // we don't want completions with higher type score than the max
-        if problems.iter().all(|p| problem_whitelist.contains(p)) && score <= max_type_score {
-            filtered_completions.lock().await.push((comp_text, score));
-        } else {
-            debug!("Filtered out completion (Problems: {problems:?}):\n{comp_text}");
+        let score = problem_weights
+            .penalty(&problems, &problem_whitelist)
+            .map(|penalty| std::cmp::min(score.saturating_add(penalty), 1000));
+        match score {
+            Some(score) if score <= max_type_score => {
+                filtered_completions.lock().await.push(Completion {
+                    code: comp_text,
+                    score,
+                    fallbacked: false,
+                    quality,
+                });
+            }
+            _ => {
+                debug!("Filtered out completion (Problems: {problems:?}):\n{comp_text}");
+            }
         }
     }
     Ok(())
@@ -284,13 +327,15 @@ pub struct CompletionClient {
     pub temperature: f64,
     // the maxmimum type score
     pub max_type_score: u16,
+    // the score penalties of the check problems
+    pub problem_weights: ProblemWeights,
     // The cache to use for the completions
     cache: Option<Arc<Mutex<Cache>>>,
     // The model that we are using
     pub model: ArcCompletionModel,
 }
 
-const HOLE_IDENTIFIER: &str = "_hole_";
+pub(crate) const HOLE_IDENTIFIER: &str = "_hole_";
 const INSTRUCTIONS: &str = "Substitute the identifier _hole_ with the correct type.";
 
 #[async_trait::async_trait]
@@ -308,7 +353,7 @@ impl CompletionEngine for CompletionClient {
         // we filter incomplete completions
         // scored vec: implemented scoring, sort resulting vec by score,
         //             and fall back to all "any" in worst case (if enabled)
-        let filtered_completions: Arc<Mutex<Vec<(String, u16)>>> = Arc::new(Mutex::new(Vec::new()));
+        let filtered_completions: Arc<Mutex<Vec<Completion>>> = Arc::new(Mutex::new(Vec::new()));
         let mut handles: Vec<JoinHandle<Result<(), ModelResponseError>>> = Vec::new();
 
         // check cache first, if the cache is set
@@ -321,7 +366,12 @@ impl CompletionEngine for CompletionClient {
                 filtered_completions
                     .lock()
                     .await
-                    .extend(cached_completions.into_iter().map(|c| (c, 0)));
+                    .extend(cached_completions.into_iter().map(|code| Completion {
+                        code,
+                        score: 0,
+                        fallbacked: false,
+                        quality: None,
+                    }));
                 query.retries = 0; // so we don't make any requests to codex
             }
         }
@@ -335,6 +385,7 @@ impl CompletionEngine for CompletionClient {
         }
 
         let mut rate_limit = false;
+        let mut timed_out = None;
 
         for handle in handles {
             let res = handle.await.unwrap();
@@ -349,6 +400,11 @@ impl CompletionEngine for CompletionClient {
                         println!("Socket IO error in completion thread: {e:?}");
                         return Err(CompletionError::Socket(e));
                     }
+                    ModelResponseError::Socket(SocketError::Timeout(d)) => {
+                        // the other threads may still have produced completions
+                        println!("Completion thread timed out after {d:?}");
+                        timed_out = Some(d);
+                    }
                     _ => {
                         println!("Error in completion thread: {e:?}");
                     }
@@ -356,22 +412,14 @@ impl CompletionEngine for CompletionClient {
             }
         }
 
-        // sort the vec by score, low..high
+        // sort the vec by score, low..high. the completions are pushed in the order that the
+        // threads finished, so ties are broken by the code to keep the order reproducible
         filtered_completions
             .lock()
             .await
-            .sort_by(|(_, s1), (_, s2)| s1.cmp(s2));
+            .sort_by(|a, b| a.score.cmp(&b.score).then_with(|| a.code.cmp(&b.code)));
 
-        let mut final_completions = filtered_completions
-            .lock()
-            .await
-            .iter()
-            .map(|(c, i)| Completion {
-                code: c.to_string(),
-                score: *i,
-                fallbacked: false,
-            })
-            .collect::<Vec<Completion>>();
+        let mut final_completions = filtered_completions.lock().await.clone();
 
         if query.fallback {
             // NOTE: we add the fallback despite the type score limit
@@ -381,6 +429,7 @@ impl CompletionEngine for CompletionClient {
                     .replace(HOLE_IDENTIFIER, &self.lang_server.any_type()),
                 score: 1000,
                 fallbacked: true,
+                quality: None,
             });
         }
 
@@ -391,14 +440,17 @@ impl CompletionEngine for CompletionClient {
 
         // if we have no completions, we return an error
         if final_completions.is_empty() {
+            if let Some(d) = timed_out {
+                return Err(CompletionError::Socket(SocketError::Timeout(d)));
+            }
             return Err(CompletionError::CouldNotComplete);
         }
 
         // print out scores
         print!("Score(s): ");
         let lock = filtered_completions.lock().await;
-        for (i, (_, score)) in lock.iter().enumerate() {
-            print!("{score}");
+        for (i, comp) in lock.iter().enumerate() {
+            print!("{}", comp.score);
             if i != lock.len() - 1 {
                 print!(", ");
             }
@@ -428,6 +480,11 @@ impl CompletionEngine for CompletionClient {
         self.max_type_score
     }
 
+    /// Gets the score penalties of the check problems of a completion.
+    fn get_problem_weights(&self) -> ProblemWeights {
+        self.problem_weights.clone()
+    }
+
     /// Gets a mutex guard to the cache from the codex client, if a cache is being used
     async fn get_cache(&self) -> Option<tokio::sync::MutexGuard<Cache>> {
         if let Some(cache) = &self.cache {
@@ -443,6 +500,7 @@ pub struct CompletionClientBuilder {
     endpoint: Option<String>,
     temperature: Option<f64>,
     max_type_score: Option<u16>,
+    problem_weights: Option<ProblemWeights>,
     cache: Option<Arc<Mutex<Cache>>>,
     model: ArcCompletionModel,
 }
@@ -454,6 +512,7 @@ impl CompletionClientBuilder {
             endpoint: None,
             temperature: None,
             max_type_score: None,
+            problem_weights: None,
             cache: None,
             model,
         }
@@ -479,12 +538,18 @@ impl CompletionClientBuilder {
         self
     }
 
+    pub fn problem_weights(mut self, problem_weights: ProblemWeights) -> Self {
+        self.problem_weights = Some(problem_weights);
+        self
+    }
+
     pub fn build(self) -> CompletionClient {
         CompletionClient {
             lang_server: self.lang_server,
             endpoint: self.endpoint,
             temperature: self.temperature.unwrap_or(1.0),
             max_type_score: self.max_type_score.unwrap_or(1000),
+            problem_weights: self.problem_weights.unwrap_or_default(),
             cache: self.cache,
             model: self.model,
         }
@@ -9,7 +9,10 @@ use crate::{
     socket::{SendToSocket, SingleThreadedSocket, SocketAbstraction, SocketPool},
 };
 
-use super::{filter_comps, CompletionEngine, CompletionModel, CompletionQuery, ModelResponseError};
+use super::{
+    filter_comps, Completion, CompletionEngine, CompletionModel, CompletionQuery,
+    ModelResponseError,
+};
 
 #[derive(Debug, Clone)]
 pub struct BuiltinClient {}
@@ -31,10 +34,11 @@ impl CompletionModel for BuiltinClient {
         &self,
         query: &CompletionQuery,
         engine: &dyn CompletionEngine,
-        filtered_completions: Arc<Mutex<Vec<(String, u16)>>>,
+        filtered_completions: Arc<Mutex<Vec<Completion>>>,
     ) -> JoinHandle<Result<(), ModelResponseError>> {
         let lang_client = engine.get_ls();
         let max_type_score = engine.get_max_type_score();
+        let problem_weights = engine.get_problem_weights();
         let mut code = query.input.clone();
         // replace all `: _hole_` with nothing
         code = code.replace(": _hole_", "");
@@ -49,6 +53,7 @@ impl CompletionModel for BuiltinClient {
                 &code,
                 completion,
                 problem_whitelist.clone(),
+                problem_weights.clone(),
                 max_type_score,
             )
             .await?;
@@ -5,7 +5,10 @@ use tokio::{sync::Mutex, task::JoinHandle};
 
 use crate::completion::filter_comps;
 
-use super::{CompletionEngine, CompletionModel, CompletionQuery, ModelResponseError, INSTRUCTIONS};
+use super::{
+    Completion, CompletionEngine, CompletionModel, CompletionQuery, ModelResponseError,
+    INSTRUCTIONS,
+};
 
 mod rl {
     use dashmap::DashMap;
@@ -133,7 +136,7 @@ impl CompletionModel for CodexClient {
         &self,
         query: &CompletionQuery,
         engine: &dyn CompletionEngine,
-        filtered_completions: Arc<Mutex<Vec<(String, u16)>>>,
+        filtered_completions: Arc<Mutex<Vec<Completion>>>,
     ) -> JoinHandle<Result<(), ModelResponseError>> {
         // clones for the closure
 
@@ -146,6 +149,7 @@ impl CompletionModel for CodexClient {
         let temp = engine.get_temperature();
         let rl = self.rate_limiter.clone();
         let max_type_score = engine.get_max_type_score();
+        let problem_weights = engine.get_problem_weights();
 
         // from query:
         let num_comps = query.num_comps;
@@ -203,6 +207,7 @@ impl CompletionModel for CodexClient {
                     &input,
                     text,
                     problem_whitelist.clone(),
+                    problem_weights.clone(),
                     max_type_score,
                 )
                 .await?;
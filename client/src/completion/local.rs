@@ -1,14 +1,17 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use tokio::{sync::Mutex, task::JoinHandle};
 
 use crate::{
     debug, get_path_from_rootdir,
-    socket::{SendToSocket, SingleThreadedSocket, SocketAbstraction, SocketPool},
+    socket::{SendToSocket, SingleThreadedSocket, SocketAbstraction, SocketPool, SocketTimeouts},
 };
 
-use super::{filter_comps, CompletionEngine, CompletionModel, CompletionQuery, ModelResponseError};
+use super::{
+    filter_comps, Completion, CompletionEngine, CompletionModel, CompletionQuery,
+    ModelResponseError,
+};
 
 #[derive(Debug, Clone)]
 pub struct LocalModelClient {
@@ -19,6 +22,7 @@ pub struct LocalModelClient {
 pub struct LocalModelClientBuilder {
     kind: String, // e.g. incoder, or santacoder
     socket_path: Option<String>,
+    timeout: Option<Duration>,
 }
 
 impl LocalModelClientBuilder {
@@ -26,6 +30,7 @@ impl LocalModelClientBuilder {
         Self {
             kind,
             socket_path: None,
+            timeout: None,
         }
     }
 
@@ -34,12 +39,23 @@ impl LocalModelClientBuilder {
         self
     }
 
+    /// Sets the timeout for a single sampling request to the model server.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     pub async fn build(self) -> Result<LocalModelClient, ModelResponseError> {
+        let mut timeouts = SocketTimeouts::new();
+        if let Some(timeout) = self.timeout {
+            timeouts = timeouts.default_timeout(timeout);
+        }
+
         // if we have a socket path, use that. open a pool. split on
         // comma.
         if let Some(socket_path) = self.socket_path {
             let socket_paths = socket_path.split(',').map(|s| s.to_string()).collect();
-            let pool = SocketPool::make(socket_paths).await;
+            let pool = SocketPool::make(socket_paths).await.with_timeouts(timeouts);
             return Ok(LocalModelClient {
                 socket: Arc::new(pool),
             });
@@ -50,11 +66,13 @@ impl LocalModelClientBuilder {
             "{}/main.py",
             get_path_from_rootdir(format!("{}-server", self.kind))
         );
+        // the model servers only listen on a socket, they don't support `--stdio`
         let server_command_prefix = vec!["python3", &model_path, "--socket_path"];
         let socket = Arc::new(SingleThreadedSocket::new(
             SocketAbstraction::spawn_server(&self.kind, &server_command_prefix, false)
                 .await
-                .map_err(|e| ModelResponseError::InvalidResponse(e.to_string()))?,
+                .map_err(|e| ModelResponseError::InvalidResponse(e.to_string()))?
+                .with_timeouts(timeouts),
         ));
         Ok(LocalModelClient { socket })
     }
@@ -86,10 +104,11 @@ impl CompletionModel for LocalModelClient {
         &self,
         query: &CompletionQuery,
         engine: &dyn CompletionEngine,
-        filtered_completions: Arc<Mutex<Vec<(String, u16)>>>,
+        filtered_completions: Arc<Mutex<Vec<Completion>>>,
     ) -> JoinHandle<Result<(), ModelResponseError>> {
         let lang_client = engine.get_ls();
         let max_type_score = engine.get_max_type_score();
+        let problem_weights = engine.get_problem_weights();
         let num_comps = query.num_comps;
         let code = query.input.clone();
         let problem_whitelist = query.problem_whitelist.clone();
@@ -109,6 +128,7 @@ impl CompletionModel for LocalModelClient {
                     &code,
                     code.clone(),
                     problem_whitelist.clone(),
+                    problem_weights.clone(),
                     max_type_score,
                 )
                 .await;
@@ -188,6 +208,7 @@ impl CompletionModel for LocalModelClient {
                     &code,
                     completion,
                     problem_whitelist.clone(),
+                    problem_weights.clone(),
                     max_type_score,
                 )
                 .await?;
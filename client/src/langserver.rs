@@ -1,11 +1,13 @@
-use std::{str::FromStr, sync::Arc};
+use std::{collections::HashMap, str::FromStr, sync::Arc};
 
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::{socket::SocketError, tree::CodeBlockTree, typedef_gen::ObjectInfoMap};
 
+pub mod generic; // a server described by a config file
+pub mod pool; // a pool of servers of the same kind
 pub mod py; // the python server
 pub mod ts; // the typescript server
 
@@ -18,6 +20,16 @@ pub enum CheckProblem {
     ChangedCode,
     /// The completion added/removed comments.
     ChangedComments,
+    /// The completion used the any type in an annotation.
+    UsedAnyType,
+    /// The completion renamed identifiers, without otherwise changing the code.
+    RenamedIdentifier,
+    /// The completion added import statements.
+    AddedImports,
+    /// The completion changed whitespace outside of the types, without changing the code.
+    ChangedFormattingOnly,
+    /// The completion has types that can't be parsed.
+    InvalidTypeSyntax,
 }
 
 impl CheckProblem {
@@ -27,28 +39,231 @@ impl CheckProblem {
             CheckProblem::NotComplete,
             CheckProblem::ChangedCode,
             CheckProblem::ChangedComments,
+            CheckProblem::UsedAnyType,
+            CheckProblem::RenamedIdentifier,
+            CheckProblem::AddedImports,
+            CheckProblem::ChangedFormattingOnly,
+            CheckProblem::InvalidTypeSyntax,
         ]
     }
 }
 
+impl FromStr for CheckProblem {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "NotComplete" => Ok(CheckProblem::NotComplete),
+            "ChangedCode" => Ok(CheckProblem::ChangedCode),
+            "ChangedComments" => Ok(CheckProblem::ChangedComments),
+            "UsedAnyType" => Ok(CheckProblem::UsedAnyType),
+            "RenamedIdentifier" => Ok(CheckProblem::RenamedIdentifier),
+            "AddedImports" => Ok(CheckProblem::AddedImports),
+            "ChangedFormattingOnly" => Ok(CheckProblem::ChangedFormattingOnly),
+            "InvalidTypeSyntax" => Ok(CheckProblem::InvalidTypeSyntax),
+            _ => Err(()),
+        }
+    }
+}
+
 impl<'a> Deserialize<'a> for CheckProblem {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'a>,
     {
         let s = String::deserialize(deserializer)?;
-        match s.as_str() {
-            "NotComplete" => Ok(CheckProblem::NotComplete),
-            "ChangedCode" => Ok(CheckProblem::ChangedCode),
-            "ChangedComments" => Ok(CheckProblem::ChangedComments),
-            _ => Err(serde::de::Error::custom(format!(
-                "invalid CheckProblem: {s}"
-            ))),
+        CheckProblem::from_str(&s)
+            .map_err(|_| serde::de::Error::custom(format!("invalid CheckProblem: {s}")))
+    }
+}
+
+impl Serialize for CheckProblem {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            CheckProblem::NotComplete => serializer.serialize_str("NotComplete"),
+            CheckProblem::ChangedCode => serializer.serialize_str("ChangedCode"),
+            CheckProblem::ChangedComments => serializer.serialize_str("ChangedComments"),
+            CheckProblem::UsedAnyType => serializer.serialize_str("UsedAnyType"),
+            CheckProblem::RenamedIdentifier => serializer.serialize_str("RenamedIdentifier"),
+            CheckProblem::AddedImports => serializer.serialize_str("AddedImports"),
+            CheckProblem::ChangedFormattingOnly => {
+                serializer.serialize_str("ChangedFormattingOnly")
+            }
+            CheckProblem::InvalidTypeSyntax => serializer.serialize_str("InvalidTypeSyntax"),
         }
     }
 }
 
+/// The score penalties of the check problems. A problem with a weight does not reject the
+/// completion, instead the weight is added to its score. Problems without a weight reject the
+/// completion, unless the query whitelists them, in which case they are not penalized either.
+///
+/// By default, `UsedAnyType`, `RenamedIdentifier` and `ChangedFormattingOnly` have a weight of 0,
+/// and every other problem rejects the completion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProblemWeights {
+    weights: HashMap<CheckProblem, u16>,
+}
+
+impl ProblemWeights {
+    /// Weights where every problem rejects the completion.
+    pub fn none() -> Self {
+        Self {
+            weights: HashMap::new(),
+        }
+    }
+
+    /// Sets the weight of the given problem, `None` makes the problem reject the completion.
+    pub fn weight(mut self, problem: CheckProblem, weight: Option<u16>) -> Self {
+        match weight {
+            Some(w) => self.weights.insert(problem, w),
+            None => self.weights.remove(&problem),
+        };
+        self
+    }
+
+    /// The weight of the given problem, `None` if the problem rejects the completion.
+    pub fn get(&self, problem: CheckProblem) -> Option<u16> {
+        self.weights.get(&problem).copied()
+    }
+
+    /// The total penalty of the given problems, ignoring the whitelisted ones.
+    /// Returns `None` if any of the problems rejects the completion.
+    pub fn penalty(&self, problems: &[CheckProblem], whitelist: &[CheckProblem]) -> Option<u16> {
+        problems
+            .iter()
+            .filter(|p| !whitelist.contains(p))
+            .try_fold(0u16, |acc, p| Some(acc.saturating_add(self.get(*p)?)))
+    }
+}
+
+impl Default for ProblemWeights {
+    fn default() -> Self {
+        Self::none()
+            .weight(CheckProblem::UsedAnyType, Some(0))
+            .weight(CheckProblem::RenamedIdentifier, Some(0))
+            .weight(CheckProblem::ChangedFormattingOnly, Some(0))
+    }
+}
+
+impl FromStr for ProblemWeights {
+    type Err = String;
+
+    /// Parses comma-separated `Problem=weight` pairs, on top of the default weights.
+    /// The weight is either a number or `reject`, e.g. `UsedAnyType=100,AddedImports=reject`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut weights = Self::default();
+        for pair in s.split(',').filter(|p| !p.trim().is_empty()) {
+            let (problem, weight) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected `Problem=weight`, got {pair}"))?;
+            let problem = CheckProblem::from_str(problem.trim())
+                .map_err(|_| format!("unknown check problem: {problem}"))?;
+            let weight = match weight.trim() {
+                "reject" => None,
+                w => Some(
+                    w.parse::<u16>()
+                        .map_err(|_| format!("invalid weight for {problem:?}: {w}"))?,
+                ),
+            };
+            weights = weights.weight(problem, weight);
+        }
+        Ok(weights)
+    }
+}
+
+impl Serialize for ProblemWeights {
+    /// Serializes to a map from every problem to its weight, `null` for the rejected ones.
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_map(CheckProblem::all().into_iter().map(|p| (p, self.get(p))))
+    }
+}
+
+impl<'a> Deserialize<'a> for ProblemWeights {
+    /// Deserializes a map from problems to weights, on top of the default weights.
+    /// A `null` weight makes the problem reject the completion.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'a>,
+    {
+        let overrides = HashMap::<CheckProblem, Option<u16>>::deserialize(deserializer)?;
+        Ok(overrides
+            .into_iter()
+            .fold(Self::default(), |w, (p, weight)| w.weight(p, weight)))
+    }
+}
+
+/// The breakdown of the type-quality score of a completion, as reported by the `check`
+/// command. The counts are over the leaf types of the annotations of the completion.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TypeQuality {
+    /// The number of type annotations that the completion should have.
+    pub annotations: usize,
+    /// The number of those annotations that are filled in.
+    pub filled: usize,
+    /// The number of `any` types.
+    pub any: usize,
+    /// The number of `unknown` types.
+    pub unknown: usize,
+    /// The number of `Function` types, or `Callable` without a signature in Python.
+    pub function: usize,
+    /// The number of `object` types, or `object` and `{}` in TypeScript.
+    pub object: usize,
+    /// The deepest nesting of types, e.g. 2 for `Array<number>`.
+    pub depth: usize,
+    /// The share of the leaf types that are none of the above, in [0, 1000].
+    pub specificity: u16,
+}
+
+impl TypeQuality {
+    /// A short label of the quality, that can be used in file names.
+    /// Counts of zero are left out, e.g. `filled_3of4_any_1_depth_2_spec_750`.
+    pub fn label(&self) -> String {
+        let mut label = format!("filled_{}of{}", self.filled, self.annotations);
+        for (name, count) in [
+            ("any", self.any),
+            ("unknown", self.unknown),
+            ("function", self.function),
+            ("object", self.object),
+        ] {
+            if count > 0 {
+                label.push_str(&format!("_{name}_{count}"));
+            }
+        }
+        label.push_str(&format!("_depth_{}_spec_{}", self.depth, self.specificity));
+        label
+    }
+}
+
+impl std::fmt::Display for TypeQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} filled, {} any, {} unknown, {} Function, {} object, depth {}, specificity {}",
+            self.filled,
+            self.annotations,
+            self.any,
+            self.unknown,
+            self.function,
+            self.object,
+            self.depth,
+            self.specificity
+        )
+    }
+}
+
 /// The kinds of statements that can be annotated by the language server.
+///
+/// `Param`, `Return` and `Destructuring` are positions rather than kinds: they select which
+/// slots of the other kinds get annotated, e.g. excluding `Return` annotates only the
+/// parameters of the selected functions. A list that names none of the positions is read as
+/// it was before they existed, see [`AnnotateType::with_legacy_positions`].
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum AnnotateType {
     /// Variable declaration
@@ -63,6 +278,18 @@ pub enum AnnotateType {
     ClassMethod,
     /// Type declaration
     TypeDecl,
+    /// Method of an object literal
+    ObjectLiteralMember,
+    /// Constraint of a generic type parameter
+    TypeParam,
+    /// Variable of a catch clause
+    CatchClause,
+    /// Parameters of the selected functions
+    Param,
+    /// Return types of the selected functions
+    Return,
+    /// Destructuring patterns of the selected parameters and variables
+    Destructuring,
 }
 
 impl FromStr for AnnotateType {
@@ -76,6 +303,12 @@ impl FromStr for AnnotateType {
             "ClassProp" => Ok(AnnotateType::ClassProp),
             "ClassMethod" => Ok(AnnotateType::ClassMethod),
             "TypeDecl" => Ok(AnnotateType::TypeDecl),
+            "ObjectLiteralMember" => Ok(AnnotateType::ObjectLiteralMember),
+            "TypeParam" => Ok(AnnotateType::TypeParam),
+            "CatchClause" => Ok(AnnotateType::CatchClause),
+            "Param" => Ok(AnnotateType::Param),
+            "Return" => Ok(AnnotateType::Return),
+            "Destructuring" => Ok(AnnotateType::Destructuring),
             _ => Err(()),
         }
     }
@@ -105,12 +338,19 @@ impl Serialize for AnnotateType {
             AnnotateType::ClassProp => serializer.serialize_str("ClassProp"),
             AnnotateType::ClassMethod => serializer.serialize_str("ClassMethod"),
             AnnotateType::TypeDecl => serializer.serialize_str("TypeDecl"),
+            AnnotateType::ObjectLiteralMember => serializer.serialize_str("ObjectLiteralMember"),
+            AnnotateType::TypeParam => serializer.serialize_str("TypeParam"),
+            AnnotateType::CatchClause => serializer.serialize_str("CatchClause"),
+            AnnotateType::Param => serializer.serialize_str("Param"),
+            AnnotateType::Return => serializer.serialize_str("Return"),
+            AnnotateType::Destructuring => serializer.serialize_str("Destructuring"),
         }
     }
 }
 
 impl AnnotateType {
-    /// Returns a list of all the annotate types.
+    /// Returns a list of all the annotate types that are annotated by default. The types in
+    /// [`AnnotateType::optional`] are left out.
     pub fn all() -> Vec<AnnotateType> {
         vec![
             AnnotateType::VarDecl,
@@ -119,9 +359,54 @@ impl AnnotateType {
             AnnotateType::ClassProp,
             AnnotateType::ClassMethod,
             AnnotateType::TypeDecl,
+            AnnotateType::ObjectLiteralMember,
+            AnnotateType::Param,
+            AnnotateType::Return,
+            AnnotateType::Destructuring,
         ]
     }
 
+    /// Returns the annotate types that are only annotated when asked for. Type parameter
+    /// constraints change the signature of generics, and catch clause variables can only be
+    /// typed with `any` or `unknown`, so holes there are rarely useful.
+    pub fn optional() -> Vec<AnnotateType> {
+        vec![AnnotateType::TypeParam, AnnotateType::CatchClause]
+    }
+
+    /// Returns a list of all the annotate types, including the optional ones.
+    pub fn all_with_optional() -> Vec<AnnotateType> {
+        let mut types = AnnotateType::all();
+        types.extend(AnnotateType::optional());
+        types
+    }
+
+    /// Returns the positions, which select the slots of the other kinds.
+    pub fn positions() -> Vec<AnnotateType> {
+        vec![
+            AnnotateType::Param,
+            AnnotateType::Return,
+            AnnotateType::Destructuring,
+        ]
+    }
+
+    /// Expands a list that names none of the positions, like the lists written before the
+    /// positions were added: every position is annotated, and the methods of object literals
+    /// are annotated along with `ClassMethod`. Other lists are returned as they are. The
+    /// language servers read the lists they get in the same way.
+    pub fn with_legacy_positions(types: &[AnnotateType]) -> Vec<AnnotateType> {
+        let mut types = types.to_vec();
+        if AnnotateType::positions().iter().any(|p| types.contains(p)) {
+            return types;
+        }
+        types.extend(AnnotateType::positions());
+        if types.contains(&AnnotateType::ClassMethod)
+            && !types.contains(&AnnotateType::ObjectLiteralMember)
+        {
+            types.push(AnnotateType::ObjectLiteralMember);
+        }
+        types
+    }
+
     /// Returns a list of all the annotate types, except for the given types in `exclude`.
     pub fn all_except(exclude: &[AnnotateType]) -> Vec<AnnotateType> {
         AnnotateType::all()
@@ -131,12 +416,165 @@ impl AnnotateType {
     }
 }
 
+/// How an inferred type relates to the type that was written in the code, as found by the
+/// assignability check of the language server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TypeRelation {
+    /// Both types are assignable to each other.
+    Same,
+    /// The inferred type is assignable to the written type, but not the other way around.
+    Narrower,
+    /// The written type is assignable to the inferred type, but not the other way around.
+    Wider,
+    /// Neither type is assignable to the other.
+    Incompatible,
+    /// The server could not compare the types, e.g. because they refer to type parameters.
+    Unknown,
+}
+
+impl std::fmt::Display for TypeRelation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            TypeRelation::Same => "same",
+            TypeRelation::Narrower => "narrower",
+            TypeRelation::Wider => "wider",
+            TypeRelation::Incompatible => "incompatible",
+            TypeRelation::Unknown => "unknown",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// The comparison of a type that was written in the code with the type that was inferred in
+/// its place, as reported by the `audit` command.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnnotationAudit {
+    /// A readable name of the annotated slot, e.g. the name of the parameter.
+    pub location: String,
+    /// The line of the slot in the original code, starting at 1.
+    pub line: usize,
+    /// The type that was written in the original code.
+    pub declared: String,
+    /// The type that was inferred.
+    pub inferred: String,
+    pub relation: TypeRelation,
+}
+
+impl std::fmt::Display for AnnotationAudit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: declared `{}`, inferred `{}` ({})",
+            self.line, self.location, self.declared, self.inferred, self.relation
+        )
+    }
+}
+
+/// The options of the type checker of the language server, e.g. the strictness of the checks.
+/// They are sent with every `typecheck` request, and override the defaults of the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CompilerOptions {
+    /// The path to a config file whose options are used, e.g. a `tsconfig.json`.
+    Config(String),
+    /// The options themselves, e.g. `{"strict": true, "target": "es2019"}`.
+    Inline(serde_json::Map<String, serde_json::Value>),
+}
+
+impl CompilerOptions {
+    /// Makes the path of a `Config` absolute, as the server may run in another directory.
+    pub fn canonicalize(self) -> Result<Self, LangServerError> {
+        match self {
+            CompilerOptions::Config(path) => std::fs::canonicalize(&path)
+                .map(|p| CompilerOptions::Config(p.to_string_lossy().to_string()))
+                .map_err(|e| LangServerError::Config(format!("could not read {path}: {e}"))),
+            inline => Ok(inline),
+        }
+    }
+}
+
+impl FromStr for CompilerOptions {
+    type Err = String;
+
+    /// Parses either a JSON object of options, or otherwise the path to a config file.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim_start().starts_with('{') {
+            serde_json::from_str(s)
+                .map(CompilerOptions::Inline)
+                .map_err(|e| format!("invalid compiler options: {e}"))
+        } else {
+            Ok(CompilerOptions::Config(s.to_string()))
+        }
+    }
+}
+
+/// The version of the socket protocol spoken by this client.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The capabilities of a language server, as reported by the `capabilities` command.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    /// The names of the socket commands the server implements, e.g. "print" or "weave".
+    pub commands: Vec<String>,
+    /// The version of the socket protocol the server speaks.
+    pub protocol_version: u32,
+    /// The spelling of the Any type in the language, e.g. `any` for TypeScript.
+    pub any_type: String,
+    /// The token the server uses for type holes, e.g. `_hole_`.
+    pub hole_token: String,
+}
+
+impl Capabilities {
+    /// The capabilities assumed for servers that predate the `capabilities` command.
+    /// These servers are assumed to implement every command.
+    pub fn legacy(any_type: String) -> Self {
+        Self {
+            commands: [
+                "print",
+                "tree",
+                "stub",
+                "check",
+                "weave",
+                "usages",
+                "objectInfo",
+                "typedefGen",
+                "typecheck",
+            ]
+            .iter()
+            .map(|cmd| cmd.to_string())
+            .collect(),
+            protocol_version: 0,
+            any_type,
+            hole_token: "_hole_".to_string(),
+        }
+    }
+
+    /// Returns true if the server implements the given command.
+    pub fn supports(&self, cmd: &str) -> bool {
+        self.commands.iter().any(|c| c == cmd)
+    }
+
+    /// Returns the commands in `cmds` that the server does not implement.
+    pub fn missing(&self, cmds: &[&str]) -> Vec<String> {
+        cmds.iter()
+            .filter(|cmd| !self.supports(cmd))
+            .map(|cmd| cmd.to_string())
+            .collect()
+    }
+}
+
 #[async_trait]
 /// The language server commands that are available to the completion engine.
 /// The `simple` strategy only requires the `pretty_print` and `check_complete` commands.
 /// The `tree` strategy additionally requires `to_tree`, `stub`, `weave`, and `usages`.
 /// For type definition generation, the `typedef_gen` and object_info` commands are required.
+/// Which of these a server implements can be queried with `capabilities`.
 pub trait LangServerCommands {
+    /// queries the commands supported by the server, the protocol version it speaks, and
+    /// the spelling of the any type and type holes.
+    async fn capabilities(&self) -> Result<Capabilities, LangServerError>;
+
     /// pretty print the given code, making all missing types the given type token.
     /// the `types` parameter specifies which types of code blocks should be annotated.
     async fn pretty_print(
@@ -159,6 +597,17 @@ pub trait LangServerCommands {
         completed: &str,
     ) -> Result<(Vec<CheckProblem>, u16), LangServerError>;
 
+    /// same as `check_complete`, but also returns the breakdown of the score into a
+    /// `TypeQuality`, if the server reports it.
+    async fn check_complete_detailed(
+        &self,
+        original: &str,
+        completed: &str,
+    ) -> Result<(Vec<CheckProblem>, u16, Option<TypeQuality>), LangServerError> {
+        let (problems, score) = self.check_complete(original, completed).await?;
+        Ok((problems, score, None))
+    }
+
     /// performs a type weaving operation on the given `original` code, such that the types of the
     /// `nettle` code are transplanted into the `original` code. The `level` parameter specifies the
     /// level of the tree where the `nettle` block is located relative to `original`.
@@ -227,6 +676,20 @@ pub trait LangServerCommands {
     /// }
     /// ```
     async fn typedef_gen(&self, code: &str) -> Result<String, LangServerError>;
+
+    /// removes the existing types of the given kinds from the code, the inverse of
+    /// `pretty_print`.
+    async fn strip(&self, code: &str, types: &[AnnotateType]) -> Result<String, LangServerError>;
+
+    /// compares the types of the given kinds that are written in `original` with the ones in
+    /// `completed`, which has to be the same code with different types. only the slots that
+    /// are annotated in both are compared, in the order they appear in `original`.
+    async fn audit(
+        &self,
+        original: &str,
+        completed: &str,
+        types: &[AnnotateType],
+    ) -> Result<Vec<AnnotationAudit>, LangServerError>;
 }
 
 /// This is the trait that defines operations on the language server.
@@ -238,9 +701,33 @@ pub trait LangServer: LangServerCommands + std::fmt::Debug {
         Self: std::marker::Sized;
 
     /// type checks the given code. returns 0 if there are no errors, returns the
-    /// number of errors otherwise.
+    /// number of errors otherwise. only diagnostics of the error category are counted, the
+    /// same ones as `Diagnostic::is_error` in `type_check_detailed`.
     async fn type_check(&self, code: &str) -> Result<usize, LangServerError>;
 
+    /// type checks the given code, returning all the diagnostics of the type checker,
+    /// with their location, error code, message and category.
+    async fn type_check_detailed(&self, code: &str) -> Result<Vec<Diagnostic>, LangServerError>;
+
+    /// type checks the given code as a replacement of `file`, a path relative to the root of
+    /// the project the server was started with. only errors in `file` are counted, such that
+    /// imports from sibling modules resolve. servers without a project check the code in
+    /// isolation, like `type_check`.
+    async fn type_check_file(&self, code: &str, file: &str) -> Result<usize, LangServerError> {
+        let _ = file;
+        self.type_check(code).await
+    }
+
+    /// same as `type_check_file`, but returns all the diagnostics like `type_check_detailed`.
+    async fn type_check_file_detailed(
+        &self,
+        code: &str,
+        file: &str,
+    ) -> Result<Vec<Diagnostic>, LangServerError> {
+        let _ = file;
+        self.type_check_detailed(code).await
+    }
+
     /// produces the Any type for the given language.
     /// for example, in TypeScript, this would be `any`.
     fn any_type(&self) -> String;
@@ -261,6 +748,20 @@ pub struct LSReq {
     pub text: String,
 }
 
+/// Request to the language server for the typecheck command.
+/// in the format of {cmd: "typecheck", text: "the-text", file: "the/file.ts",
+///                   compilerOptions: {...}}, where `file` is the file of the project that the
+/// text replaces, if any, and `compilerOptions` are either the options or a path to a config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LSTypecheckReq {
+    pub cmd: String,
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(rename = "compilerOptions", skip_serializing_if = "Option::is_none")]
+    pub compiler_options: Option<CompilerOptions>,
+}
+
 /// Request to the language server for the printer command.
 /// in the format of {cmd: "the-cmd", text: "the-text", typeName: "the-type-name"}
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -281,6 +782,26 @@ pub struct LSCheckReq {
     pub original: String,
 }
 
+/// Request to the language server for the strip command.
+/// in the format of {cmd: "strip", text: "the-text", types: ["FuncDecl", ...]}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LSStripReq {
+    pub cmd: String,
+    pub text: String,
+    pub types: Vec<AnnotateType>,
+}
+
+/// Request to the language server for the audit command.
+/// in the format of {cmd: "audit", text: "the-completed-text", original: "the-original-text",
+///                   types: ["FuncDecl", ...]}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LSAuditReq {
+    pub cmd: String,
+    pub text: String,
+    pub original: String,
+    pub types: Vec<AnnotateType>,
+}
+
 /// Request to the language server for the weave command.
 /// in the format of {cmd: "the-cmd", text: "the-original-text",
 ///                   nettle: "the-nettle-text", level: 0}
@@ -303,6 +824,199 @@ pub struct LSUsagesReq {
     pub inner_block: String,
 }
 
+/// Response of the language server for the commands that reply with base64 encoded text:
+/// print, tree, stub, weave, objectInfo, typedefGen and strip.
+/// in the format of {type: "the-type", text: "the-text"}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LSTextResp {
+    pub text: String,
+}
+
+/// Response of the language server for the check command.
+/// in the format of {type: "checkResponse", problems: ["NotComplete"], score: 0, quality: {...}},
+/// where `quality` is optional.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LSCheckResp {
+    pub problems: Vec<CheckProblem>,
+    pub score: u16,
+    #[serde(default)]
+    pub quality: Option<TypeQuality>,
+}
+
+/// Response of the language server for the audit command.
+/// in the format of {type: "auditResponse", annotations: [...]}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LSAuditResp {
+    pub annotations: Vec<AnnotationAudit>,
+}
+
+/// Response of the language server for the usages command.
+/// in the format of {type: "usagesResponse", text: "the-usages", numUsages: 0}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LSUsagesResp {
+    pub text: String,
+    #[serde(rename = "numUsages")]
+    pub num_usages: usize,
+}
+
+/// Response of the language server for the typecheck command.
+/// in the format of {type: "typeCheckResponse", errors: 0}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LSTypecheckResp {
+    pub errors: usize,
+}
+
+/// Response of the language server for the typecheck command, when the diagnostics are needed.
+/// in the format of {type: "typeCheckResponse", errors: 0, diagnostics: [...]}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LSTypecheckDetailedResp {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// The category of a diagnostic of the type checker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticCategory {
+    Error,
+    Warning,
+    Suggestion,
+    Message,
+}
+
+impl std::fmt::Display for DiagnosticCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiagnosticCategory::Error => write!(f, "error"),
+            DiagnosticCategory::Warning => write!(f, "warning"),
+            DiagnosticCategory::Suggestion => write!(f, "suggestion"),
+            DiagnosticCategory::Message => write!(f, "message"),
+        }
+    }
+}
+
+/// The span of code that a diagnostic refers to. Lines and columns are 1-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticSpan {
+    pub start_line: usize,
+    pub start_col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+
+/// A diagnostic produced by the type checker of the language server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// The span of the code in the file, None if the diagnostic is not about a specific
+    /// part of the file.
+    pub span: Option<DiagnosticSpan>,
+    /// The error code given by the type checker, e.g. `TS2322` for TypeScript.
+    pub code: String,
+    pub message: String,
+    pub category: DiagnosticCategory,
+    /// whether the uncompleted file has the same diagnostic, i.e. it wasn't caused by the
+    /// completion. set by the client, not by the server.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pre_existing: bool,
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.category == DiagnosticCategory::Error
+    }
+}
+
+/// Marks the diagnostics that are also in `baseline`, the diagnostics of the uncompleted file.
+/// They are matched by their code and message, not by their span, as the completed types move
+/// the code around. Each diagnostic of the baseline matches at most one diagnostic.
+pub fn mark_pre_existing(diagnostics: &mut [Diagnostic], baseline: &[Diagnostic]) {
+    let mut unmatched: HashMap<(&str, &str), usize> = HashMap::new();
+    for diag in baseline {
+        *unmatched
+            .entry((diag.code.as_str(), diag.message.as_str()))
+            .or_default() += 1;
+    }
+    for diag in diagnostics {
+        if let Some(count) = unmatched.get_mut(&(diag.code.as_str(), diag.message.as_str())) {
+            if *count > 0 {
+                *count -= 1;
+                diag.pre_existing = true;
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(span) = &self.span {
+            write!(f, "{}:{}: ", span.start_line, span.start_col)?;
+        }
+        write!(f, "{} {}: {}", self.category, self.code, self.message)?;
+        if self.pre_existing {
+            write!(f, " (pre-existing)")?;
+        }
+        Ok(())
+    }
+}
+
+/// A response of the language server, with the fields a reply has to include.
+pub trait LSResp: DeserializeOwned {
+    /// The keys of the required fields, as they are spelled in the reply.
+    const FIELDS: &'static [&'static str];
+}
+
+impl LSResp for Capabilities {
+    const FIELDS: &'static [&'static str] =
+        &["commands", "protocolVersion", "anyType", "holeToken"];
+}
+
+impl LSResp for LSTextResp {
+    const FIELDS: &'static [&'static str] = &["text"];
+}
+
+impl LSResp for LSCheckResp {
+    const FIELDS: &'static [&'static str] = &["problems", "score"];
+}
+
+impl LSResp for LSAuditResp {
+    const FIELDS: &'static [&'static str] = &["annotations"];
+}
+
+impl LSResp for LSUsagesResp {
+    const FIELDS: &'static [&'static str] = &["text", "numUsages"];
+}
+
+impl LSResp for LSTypecheckResp {
+    const FIELDS: &'static [&'static str] = &["errors"];
+}
+
+impl LSResp for LSTypecheckDetailedResp {
+    const FIELDS: &'static [&'static str] = &["diagnostics"];
+}
+
+/// Decodes the response of the language server into the given response type.
+/// A required field that is absent or null gives `MissingField`, anything else that doesn't
+/// fit the type gives `Decode`.
+pub fn decode_resp<T: LSResp>(resp: serde_json::Value) -> Result<T, LangServerError> {
+    let obj = resp
+        .as_object()
+        .ok_or_else(|| LangServerError::Decode(format!("expected an object, got {resp}")))?;
+    if let Some(field) = T::FIELDS
+        .iter()
+        .find(|field| obj.get(**field).is_none_or(|v| v.is_null()))
+    {
+        return Err(LangServerError::MissingField(field.to_string()));
+    }
+    Ok(serde_json::from_value(resp)?)
+}
+
+/// Decodes a base64 encoded text field of a response.
+pub fn decode_text(text: &str) -> Result<String, LangServerError> {
+    let bytes = base64::decode(text)
+        .map_err(|e| LangServerError::Encoding(format!("invalid base64: {e}")))?;
+    String::from_utf8(bytes).map_err(|e| LangServerError::Encoding(format!("invalid utf-8: {e}")))
+}
+
 #[derive(Debug, Clone, Error)]
 pub enum LangServerError {
     #[error("Language client error: {0}")]
@@ -311,32 +1025,101 @@ pub enum LangServerError {
     ProcessSpawn,
     #[error("Socket IO error")]
     SocketIO,
+    #[error("Language server timed out after {0:?}")]
+    Timeout(std::time::Duration),
+    #[error("Incompatible language server: {0}")]
+    Incompatible(String),
+    #[error("Could not decode the language server response: {0}")]
+    Decode(String),
+    #[error("Badly encoded text in the language server response: {0}")]
+    Encoding(String),
+    #[error("Language server response is missing the `{0}` field")]
+    MissingField(String),
+    #[error("Invalid language server config: {0}")]
+    Config(String),
+}
+
+impl From<serde_json::Error> for LangServerError {
+    fn from(e: serde_json::Error) -> Self {
+        LangServerError::Decode(e.to_string())
+    }
 }
 
 impl From<SocketError> for LangServerError {
     fn from(e: SocketError) -> Self {
         match e {
-            SocketError::Io(_) | SocketError::Serde(_) => LangServerError::SocketIO,
+            SocketError::Io(_) | SocketError::ShuttingDown => LangServerError::SocketIO,
+            // the reply was not valid JSON
+            SocketError::Serde(e) => LangServerError::Decode(e.to_string()),
             SocketError::Service(s) => LangServerError::LC(s),
+            SocketError::Timeout(d) => LangServerError::Timeout(d),
         }
     }
 }
 
+/// Commands that a language server can run in-process, instead of sending them to the server.
+/// Each method returns None if the command has to be sent to the server.
+pub trait NativeCommands {
+    /// Same as `LangServerCommands::pretty_print`.
+    fn native_pretty_print(
+        &self,
+        _code: &str,
+        _type_name: &str,
+        _types: &[AnnotateType],
+    ) -> Option<String> {
+        None
+    }
+
+    /// Same as `LangServerCommands::weave`.
+    fn native_weave(&self, _original: &str, _nettle: &str, _level: usize) -> Option<String> {
+        None
+    }
+}
+
 /// Implements the LangServerCommands trait for a given language server.
+/// With `native`, the language server implements `NativeCommands` itself, otherwise all the
+/// commands are sent to the server.
 ///
 /// # IMPORTANT
 /// The language server must have a `socket` field of type `SocketAbstraction`.
 #[macro_export]
 macro_rules! impl_langserver_commands {
     ($name:ident) => {
+        impl $crate::langserver::NativeCommands for $name {}
+        $crate::impl_langserver_commands!($name, native);
+    };
+    ($name:ident, native) => {
         #[async_trait::async_trait]
         impl $crate::langserver::LangServerCommands for $name {
+            async fn capabilities(
+                &self,
+            ) -> Result<$crate::langserver::Capabilities, $crate::langserver::LangServerError> {
+                let req = $crate::langserver::LSReq {
+                    cmd: "capabilities".to_string(),
+                    text: String::new(),
+                };
+
+                use $crate::socket::SendToSocket;
+                let resp = self
+                    .socket
+                    .send_req(serde_json::to_value(&req).unwrap())
+                    .await?;
+
+                $crate::langserver::decode_resp(resp)
+            }
+
             async fn pretty_print(
                 &self,
                 code: &str,
                 type_name: &str,
                 types: &[$crate::langserver::AnnotateType],
             ) -> Result<String, $crate::langserver::LangServerError> {
+                if let Some(text) = $crate::langserver::NativeCommands::native_pretty_print(
+                    self, code, type_name, types,
+                ) {
+                    return Ok(text);
+                }
+
                 let req = $crate::langserver::LSPrintReq {
                     cmd: "print".to_string(),
                     text: base64::encode(code),
@@ -350,9 +1133,8 @@ macro_rules! impl_langserver_commands {
                     .send_req(serde_json::to_value(&req).unwrap())
                     .await?;
                 // decode the response
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
-
-                Ok(String::from_utf8(resp).unwrap())
+                let resp: $crate::langserver::LSTextResp = $crate::langserver::decode_resp(resp)?;
+                $crate::langserver::decode_text(&resp.text)
             }
 
             async fn to_tree(
@@ -371,9 +1153,10 @@ macro_rules! impl_langserver_commands {
                     .await?;
 
                 // decode the response
-                let tree = base64::decode(resp["text"].as_str().unwrap()).unwrap();
+                let resp: $crate::langserver::LSTextResp = $crate::langserver::decode_resp(resp)?;
+                let tree = $crate::langserver::decode_text(&resp.text)?;
 
-                Ok(serde_json::from_slice(&tree).unwrap())
+                Ok(serde_json::from_str(&tree)?)
             }
 
             async fn stub(
@@ -391,9 +1174,8 @@ macro_rules! impl_langserver_commands {
                     .send_req(serde_json::to_value(&req).unwrap())
                     .await?;
                 // decode the response
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
-
-                Ok(String::from_utf8(resp).unwrap())
+                let resp: $crate::langserver::LSTextResp = $crate::langserver::decode_resp(resp)?;
+                $crate::langserver::decode_text(&resp.text)
             }
 
             async fn check_complete(
@@ -403,6 +1185,23 @@ macro_rules! impl_langserver_commands {
             ) -> Result<
                 (Vec<$crate::langserver::CheckProblem>, u16),
                 $crate::langserver::LangServerError,
+            > {
+                let (problems, score, _) =
+                    self.check_complete_detailed(original, completed).await?;
+                Ok((problems, score))
+            }
+
+            async fn check_complete_detailed(
+                &self,
+                original: &str,
+                completed: &str,
+            ) -> Result<
+                (
+                    Vec<$crate::langserver::CheckProblem>,
+                    u16,
+                    Option<$crate::langserver::TypeQuality>,
+                ),
+                $crate::langserver::LangServerError,
             > {
                 // encode original and completed into json: {original: "", completed: ""}
                 let req = $crate::langserver::LSCheckReq {
@@ -416,16 +1215,9 @@ macro_rules! impl_langserver_commands {
                     .send_req(serde_json::to_value(&req).unwrap())
                     .await?;
 
-                let problems_json = resp["problems"].as_array().unwrap();
-                let mut problems = Vec::new();
-                for p in problems_json {
-                    problems.push(serde_json::from_value(p.clone()).unwrap());
-                }
+                let resp: $crate::langserver::LSCheckResp = $crate::langserver::decode_resp(resp)?;
 
-                Ok((
-                    problems,
-                    resp["score"].as_u64().unwrap().try_into().unwrap(),
-                ))
+                Ok((resp.problems, resp.score, resp.quality))
             }
 
             async fn weave(
@@ -434,6 +1226,12 @@ macro_rules! impl_langserver_commands {
                 nettle: &str,
                 level: usize,
             ) -> Result<String, $crate::langserver::LangServerError> {
+                if let Some(text) =
+                    $crate::langserver::NativeCommands::native_weave(self, original, nettle, level)
+                {
+                    return Ok(text);
+                }
+
                 let req = $crate::langserver::LSWeaveReq {
                     cmd: "weave".to_string(),
                     text: base64::encode(original),
@@ -447,9 +1245,8 @@ macro_rules! impl_langserver_commands {
                     .send_req(serde_json::to_value(&req).unwrap())
                     .await?;
                 // decode the response
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
-
-                Ok(String::from_utf8(resp).unwrap())
+                let resp: $crate::langserver::LSTextResp = $crate::langserver::decode_resp(resp)?;
+                $crate::langserver::decode_text(&resp.text)
             }
 
             async fn usages(
@@ -469,13 +1266,10 @@ macro_rules! impl_langserver_commands {
                     .send_req(serde_json::to_value(&req).unwrap())
                     .await?;
                 // decode the response
-                let num_usages = resp["numUsages"].as_u64().unwrap();
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
+                let resp: $crate::langserver::LSUsagesResp = $crate::langserver::decode_resp(resp)?;
+                let usages = $crate::langserver::decode_text(&resp.text)?;
 
-                Ok((
-                    String::from_utf8(resp).unwrap(),
-                    num_usages.try_into().unwrap(),
-                ))
+                Ok((usages, resp.num_usages))
             }
 
             async fn object_info(
@@ -494,9 +1288,10 @@ macro_rules! impl_langserver_commands {
                     .send_req(serde_json::to_value(&req).unwrap())
                     .await?;
                 // decode the response
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
+                let resp: $crate::langserver::LSTextResp = $crate::langserver::decode_resp(resp)?;
+                let info = $crate::langserver::decode_text(&resp.text)?;
 
-                Ok(serde_json::from_slice(&resp).unwrap())
+                Ok(serde_json::from_str(&info)?)
             }
 
             async fn typedef_gen(
@@ -514,10 +1309,128 @@ macro_rules! impl_langserver_commands {
                     .send_req(serde_json::to_value(&req).unwrap())
                     .await?;
                 // decode the response
-                let resp = base64::decode(resp["text"].as_str().unwrap()).unwrap();
+                let resp: $crate::langserver::LSTextResp = $crate::langserver::decode_resp(resp)?;
+                $crate::langserver::decode_text(&resp.text)
+            }
+
+            async fn strip(
+                &self,
+                code: &str,
+                types: &[$crate::langserver::AnnotateType],
+            ) -> Result<String, $crate::langserver::LangServerError> {
+                let req = $crate::langserver::LSStripReq {
+                    cmd: "strip".to_string(),
+                    text: base64::encode(code),
+                    types: types.to_vec(),
+                };
+
+                use $crate::socket::SendToSocket;
+                let resp = self
+                    .socket
+                    .send_req(serde_json::to_value(&req).unwrap())
+                    .await?;
+                // decode the response
+                let resp: $crate::langserver::LSTextResp = $crate::langserver::decode_resp(resp)?;
+                $crate::langserver::decode_text(&resp.text)
+            }
+
+            async fn audit(
+                &self,
+                original: &str,
+                completed: &str,
+                types: &[$crate::langserver::AnnotateType],
+            ) -> Result<
+                Vec<$crate::langserver::AnnotationAudit>,
+                $crate::langserver::LangServerError,
+            > {
+                let req = $crate::langserver::LSAuditReq {
+                    cmd: "audit".to_string(),
+                    text: base64::encode(completed),
+                    original: base64::encode(original),
+                    types: types.to_vec(),
+                };
 
-                Ok(String::from_utf8(resp).unwrap())
+                use $crate::socket::SendToSocket;
+                let resp = self
+                    .socket
+                    .send_req(serde_json::to_value(&req).unwrap())
+                    .await?;
+                let resp: $crate::langserver::LSAuditResp = $crate::langserver::decode_resp(resp)?;
+                Ok(resp.annotations)
             }
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoding_tells_missing_fields_from_bad_ones() {
+        let resp =
+            serde_json::json!({"type": "usagesResponse", "text": "dXNhZ2Vz", "numUsages": 2});
+        let usages: LSUsagesResp = decode_resp(resp).unwrap();
+        assert_eq!(usages.num_usages, 2);
+
+        let resp = serde_json::json!({"type": "usagesResponse", "text": "dXNhZ2Vz"});
+        assert!(matches!(
+            decode_resp::<LSUsagesResp>(resp),
+            Err(LangServerError::MissingField(field)) if field == "numUsages"
+        ));
+        let resp = serde_json::json!({"type": "typeCheckResponse", "errors": null});
+        assert!(matches!(
+            decode_resp::<LSTypecheckResp>(resp),
+            Err(LangServerError::MissingField(field)) if field == "errors"
+        ));
+        let resp = serde_json::json!({"type": "typeCheckResponse", "errors": "none"});
+        assert!(matches!(
+            decode_resp::<LSTypecheckResp>(resp),
+            Err(LangServerError::Decode(_))
+        ));
+        assert!(matches!(
+            decode_resp::<LSTextResp>(serde_json::json!("text")),
+            Err(LangServerError::Decode(_))
+        ));
+    }
+
+    fn diag(code: &str, message: &str) -> Diagnostic {
+        Diagnostic {
+            span: None,
+            code: code.to_string(),
+            message: message.to_string(),
+            category: DiagnosticCategory::Error,
+            pre_existing: false,
+        }
+    }
+
+    #[test]
+    fn marks_each_baseline_diagnostic_once() {
+        let baseline = vec![diag("TS2304", "Cannot find name 'x'.")];
+        let mut diags = vec![
+            diag("TS2304", "Cannot find name 'x'."),
+            diag("TS2304", "Cannot find name 'x'."),
+            diag("TS2322", "Type 'string' is not assignable to type 'number'."),
+        ];
+        mark_pre_existing(&mut diags, &baseline);
+        let marked: Vec<bool> = diags.iter().map(|d| d.pre_existing).collect();
+        assert_eq!(marked, vec![true, false, false]);
+    }
+
+    #[test]
+    fn lists_without_positions_annotate_every_position() {
+        use AnnotateType::*;
+        assert_eq!(
+            AnnotateType::with_legacy_positions(&[FuncDecl, ClassMethod]),
+            vec![FuncDecl, ClassMethod, Param, Return, Destructuring, ObjectLiteralMember]
+        );
+        assert_eq!(
+            AnnotateType::with_legacy_positions(&[FuncDecl, Param]),
+            vec![FuncDecl, Param]
+        );
+        assert_eq!(
+            AnnotateType::with_legacy_positions(&AnnotateType::all()),
+            AnnotateType::all()
+        );
+    }
+}
@@ -0,0 +1,476 @@
+use std::borrow::Cow;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    completion::HOLE_IDENTIFIER,
+    impl_langserver_commands,
+    socket::SendToSocket,
+    socket::{SocketAbstraction, SocketTimeouts, Transport},
+    tree::CodeBlockTree,
+    typedef_gen::ObjectInfoMap,
+};
+
+use super::{
+    decode_resp, AnnotateType, AnnotationAudit, Capabilities, CheckProblem, CompilerOptions,
+    Diagnostic, LSTypecheckDetailedResp, LSTypecheckReq, LSTypecheckResp, LangServer,
+    LangServerCommands, LangServerError, TypeQuality,
+};
+
+/// The type parsers built into the client, that a configured server can opt into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BuiltinTypeParser {
+    Ts,
+    Py,
+}
+
+/// The configuration of a `GenericLangServer`, loaded from a TOML or JSON file.
+///
+/// # Example
+/// ```toml
+/// name = "flow"
+/// command = ["node", "/opt/flow-server/main.js"]
+/// any_type = "any"
+/// hole_token = "_hole_"
+/// types = ["FuncDecl", "FuncExpr", "ClassMethod"]
+/// type_parser = "ts"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LangServerConfig {
+    /// The name of the language, used for the socket file and as the default extension of
+    /// the output files.
+    pub name: String,
+    /// The command that spawns the server. The socket path (or `--stdio`) and the pid of the
+    /// client are appended to it, like for the built-in servers. With `--stdio`, responses
+    /// have to end with `??END??` like the requests.
+    pub command: Vec<String>,
+    /// The spelling of the Any type in the language.
+    pub any_type: String,
+    /// The token the server uses for type holes. It is translated to and from the token of
+    /// the client, so the server doesn't need to use `_hole_`. It must be an identifier that
+    /// is not used otherwise in the code, as every occurrence of it is translated back.
+    #[serde(default = "default_hole_token")]
+    pub hole_token: String,
+    /// The kinds of statements the server can annotate. Other kinds are never asked for.
+    #[serde(default = "AnnotateType::all_with_optional")]
+    pub types: Vec<AnnotateType>,
+    /// The built-in type parser to run on the types generated by the model, if any.
+    #[serde(default)]
+    pub type_parser: Option<BuiltinTypeParser>,
+    /// The extension of the output files, defaults to the name.
+    #[serde(default)]
+    pub extension: Option<String>,
+}
+
+fn default_hole_token() -> String {
+    HOLE_IDENTIFIER.to_string()
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// Checks that the token is a single identifier, such that it can be told apart from the rest
+/// of the code by its word boundaries.
+fn is_identifier(token: &str) -> bool {
+    let mut chars = token.chars();
+    match chars.next() {
+        Some(c) if !c.is_numeric() && is_identifier_char(c) => chars.all(is_identifier_char),
+        _ => false,
+    }
+}
+
+/// Replaces the occurrences of the identifier `from` in the code with `to`. Only whole
+/// identifiers are replaced, e.g. `_hole_` is not replaced in `my_hole_type`.
+fn replace_identifier(code: &str, from: &str, to: &str) -> String {
+    let mut out = String::with_capacity(code.len());
+    let mut last = 0;
+    for (start, _) in code.match_indices(from) {
+        let end = start + from.len();
+        let before = code[..start].chars().next_back();
+        let after = code[end..].chars().next();
+        if before.is_some_and(is_identifier_char) || after.is_some_and(is_identifier_char) {
+            continue;
+        }
+        out.push_str(&code[last..start]);
+        out.push_str(to);
+        last = end;
+    }
+    out.push_str(&code[last..]);
+    out
+}
+
+impl LangServerConfig {
+    /// Loads the config from the given file. Files ending in `.json` are parsed as JSON,
+    /// everything else as TOML.
+    pub fn load(path: &str) -> Result<Self, LangServerError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| LangServerError::Config(format!("could not read {path}: {e}")))?;
+        let config: Self = if path.ends_with(".json") {
+            serde_json::from_str(&contents).map_err(|e| LangServerError::Config(e.to_string()))?
+        } else {
+            toml::from_str(&contents).map_err(|e| LangServerError::Config(e.to_string()))?
+        };
+        if config.command.is_empty() {
+            return Err(LangServerError::Config("`command` is empty".to_string()));
+        }
+        if !is_identifier(&config.hole_token) {
+            return Err(LangServerError::Config(format!(
+                "`hole_token` {:?} is not an identifier",
+                config.hole_token
+            )));
+        }
+        if config.hole_token == config.any_type {
+            return Err(LangServerError::Config(
+                "`hole_token` can't be the same as `any_type`".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    /// The extension of the output files.
+    pub fn extension(&self) -> &str {
+        self.extension.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// The connection to the server, all the commands are sent as they are.
+#[derive(Debug)]
+struct GenericSocket {
+    socket: SocketAbstraction,
+}
+
+impl_langserver_commands!(GenericSocket);
+
+/// A language server that is described entirely by a `LangServerConfig`. Any executable that
+/// speaks the socket protocol can be used, without changes to the client.
+#[derive(Debug)]
+pub struct GenericLangServer {
+    inner: GenericSocket,
+    config: LangServerConfig,
+    /// the options that type checking is done with, sent with every `typecheck` request
+    compiler_options: Option<CompilerOptions>,
+}
+
+/// Builder for spawning a `GenericLangServer` with non-default options.
+pub struct GenericLangServerBuilder {
+    config: LangServerConfig,
+    timeouts: SocketTimeouts,
+    transport: Transport,
+    compiler_options: Option<CompilerOptions>,
+}
+
+impl GenericLangServerBuilder {
+    /// Creates a new builder, given the config of the server.
+    pub fn new(config: LangServerConfig) -> Self {
+        Self {
+            config,
+            timeouts: SocketTimeouts::default(),
+            transport: Transport::default(),
+            compiler_options: None,
+        }
+    }
+
+    pub fn timeouts(mut self, timeouts: SocketTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Sends the given compiler options with the `typecheck` requests, servers that don't
+    /// know about them may ignore them.
+    pub fn compiler_options(mut self, options: CompilerOptions) -> Self {
+        self.compiler_options = Some(options);
+        self
+    }
+
+    pub async fn build(self) -> Result<GenericLangServer, LangServerError> {
+        let args: Vec<&str> = self.config.command.iter().map(|s| s.as_str()).collect();
+        let socket = SocketAbstraction::spawn_server_with_transport(
+            &self.config.name,
+            &args,
+            true,
+            self.transport,
+        )
+        .await
+        .map_err(|_| LangServerError::ProcessSpawn)?
+        .with_timeouts(self.timeouts);
+        let compiler_options = match self.compiler_options {
+            Some(options) => Some(options.canonicalize()?),
+            None => None,
+        };
+        Ok(GenericLangServer {
+            inner: GenericSocket { socket },
+            config: self.config,
+            compiler_options,
+        })
+    }
+}
+
+impl GenericLangServer {
+    /// The config the server was spawned with.
+    pub fn config(&self) -> &LangServerConfig {
+        &self.config
+    }
+
+    /// Replaces the hole token of the client with the one of the server.
+    fn hole_to_server<'a>(&self, code: &'a str) -> Cow<'a, str> {
+        if self.config.hole_token == HOLE_IDENTIFIER {
+            Cow::Borrowed(code)
+        } else {
+            Cow::Owned(replace_identifier(
+                code,
+                HOLE_IDENTIFIER,
+                &self.config.hole_token,
+            ))
+        }
+    }
+
+    /// Replaces the hole token of the server with the one of the client.
+    fn hole_from_server(&self, code: String) -> String {
+        if self.config.hole_token == HOLE_IDENTIFIER {
+            code
+        } else {
+            replace_identifier(&code, &self.config.hole_token, HOLE_IDENTIFIER)
+        }
+    }
+
+    async fn send_typecheck(
+        &self,
+        code: &str,
+        file: Option<&str>,
+    ) -> Result<serde_json::Value, LangServerError> {
+        let req = LSTypecheckReq {
+            cmd: "typecheck".to_string(),
+            text: base64::encode(self.hole_to_server(code).as_ref()),
+            file: file.map(|f| f.to_string()),
+            compiler_options: self.compiler_options.clone(),
+        };
+        Ok(self
+            .inner
+            .socket
+            .send_req(serde_json::to_value(&req).unwrap())
+            .await?)
+    }
+}
+
+#[async_trait]
+impl LangServerCommands for GenericLangServer {
+    async fn capabilities(&self) -> Result<Capabilities, LangServerError> {
+        let mut caps = self.inner.capabilities().await?;
+        // the client only ever sees its own hole token
+        if caps.hole_token == self.config.hole_token {
+            caps.hole_token = HOLE_IDENTIFIER.to_string();
+        }
+        Ok(caps)
+    }
+
+    async fn pretty_print(
+        &self,
+        code: &str,
+        type_name: &str,
+        types: &[AnnotateType],
+    ) -> Result<String, LangServerError> {
+        let types: Vec<AnnotateType> = types
+            .iter()
+            .filter(|ty| self.config.types.contains(ty))
+            .copied()
+            .collect();
+        let printed = self
+            .inner
+            .pretty_print(
+                &self.hole_to_server(code),
+                &self.hole_to_server(type_name),
+                &types,
+            )
+            .await?;
+        Ok(self.hole_from_server(printed))
+    }
+
+    async fn to_tree(&self, code: &str) -> Result<CodeBlockTree, LangServerError> {
+        self.inner.to_tree(&self.hole_to_server(code)).await
+    }
+
+    async fn stub(&self, code: &str) -> Result<String, LangServerError> {
+        let stubbed = self.inner.stub(&self.hole_to_server(code)).await?;
+        Ok(self.hole_from_server(stubbed))
+    }
+
+    async fn check_complete(
+        &self,
+        original: &str,
+        completed: &str,
+    ) -> Result<(Vec<CheckProblem>, u16), LangServerError> {
+        self.inner
+            .check_complete(
+                &self.hole_to_server(original),
+                &self.hole_to_server(completed),
+            )
+            .await
+    }
+
+    async fn check_complete_detailed(
+        &self,
+        original: &str,
+        completed: &str,
+    ) -> Result<(Vec<CheckProblem>, u16, Option<TypeQuality>), LangServerError> {
+        self.inner
+            .check_complete_detailed(
+                &self.hole_to_server(original),
+                &self.hole_to_server(completed),
+            )
+            .await
+    }
+
+    async fn weave(
+        &self,
+        original: &str,
+        nettle: &str,
+        level: usize,
+    ) -> Result<String, LangServerError> {
+        let woven = self
+            .inner
+            .weave(
+                &self.hole_to_server(original),
+                &self.hole_to_server(nettle),
+                level,
+            )
+            .await?;
+        Ok(self.hole_from_server(woven))
+    }
+
+    async fn usages(
+        &self,
+        outer_block: &str,
+        inner_block: &str,
+    ) -> Result<(String, usize), LangServerError> {
+        let (usages, num) = self
+            .inner
+            .usages(
+                &self.hole_to_server(outer_block),
+                &self.hole_to_server(inner_block),
+            )
+            .await?;
+        Ok((self.hole_from_server(usages), num))
+    }
+
+    async fn object_info(&self, code: &str) -> Result<ObjectInfoMap, LangServerError> {
+        self.inner.object_info(&self.hole_to_server(code)).await
+    }
+
+    async fn typedef_gen(&self, code: &str) -> Result<String, LangServerError> {
+        let generated = self.inner.typedef_gen(&self.hole_to_server(code)).await?;
+        Ok(self.hole_from_server(generated))
+    }
+
+    async fn strip(&self, code: &str, types: &[AnnotateType]) -> Result<String, LangServerError> {
+        let types: Vec<AnnotateType> = types
+            .iter()
+            .filter(|ty| self.config.types.contains(ty))
+            .copied()
+            .collect();
+        let stripped = self.inner.strip(&self.hole_to_server(code), &types).await?;
+        Ok(self.hole_from_server(stripped))
+    }
+
+    async fn audit(
+        &self,
+        original: &str,
+        completed: &str,
+        types: &[AnnotateType],
+    ) -> Result<Vec<AnnotationAudit>, LangServerError> {
+        let types: Vec<AnnotateType> = types
+            .iter()
+            .filter(|ty| self.config.types.contains(ty))
+            .copied()
+            .collect();
+        self.inner
+            .audit(
+                &self.hole_to_server(original),
+                &self.hole_to_server(completed),
+                &types,
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl LangServer for GenericLangServer {
+    /// Makes the server from the config file at the given path.
+    async fn make(config_path: &str) -> Result<Self, LangServerError> {
+        GenericLangServerBuilder::new(LangServerConfig::load(config_path)?)
+            .build()
+            .await
+    }
+
+    async fn type_check(&self, code: &str) -> Result<usize, LangServerError> {
+        let resp: LSTypecheckResp = decode_resp(self.send_typecheck(code, None).await?)?;
+        Ok(resp.errors)
+    }
+
+    async fn type_check_detailed(&self, code: &str) -> Result<Vec<Diagnostic>, LangServerError> {
+        let resp: LSTypecheckDetailedResp = decode_resp(self.send_typecheck(code, None).await?)?;
+        Ok(resp.diagnostics)
+    }
+
+    async fn type_check_file(&self, code: &str, file: &str) -> Result<usize, LangServerError> {
+        let resp: LSTypecheckResp = decode_resp(self.send_typecheck(code, Some(file)).await?)?;
+        Ok(resp.errors)
+    }
+
+    async fn type_check_file_detailed(
+        &self,
+        code: &str,
+        file: &str,
+    ) -> Result<Vec<Diagnostic>, LangServerError> {
+        let resp: LSTypecheckDetailedResp =
+            decode_resp(self.send_typecheck(code, Some(file)).await?)?;
+        Ok(resp.diagnostics)
+    }
+
+    fn any_type(&self) -> String {
+        self.config.any_type.clone()
+    }
+
+    fn get_type_parser(&self) -> Option<Box<dyn Fn(&str) -> Option<String> + Sync + Send>> {
+        match self.config.type_parser? {
+            #[cfg(feature = "tsparser")]
+            BuiltinTypeParser::Ts => Some(Box::new(super::ts::ts_parse_type)),
+            #[cfg(feature = "pyparser")]
+            BuiltinTypeParser::Py => Some(Box::new(super::py::py_parse_type)),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_only_whole_identifiers() {
+        let code = "let a: _hole_ = f<_hole_>(my_hole_type, _hole_s);";
+        assert_eq!(
+            replace_identifier(code, "_hole_", "HOLE"),
+            "let a: HOLE = f<HOLE>(my_hole_type, _hole_s);"
+        );
+        assert_eq!(replace_identifier("$hole", "hole", "x"), "$hole");
+    }
+
+    #[test]
+    fn hole_token_must_be_an_identifier() {
+        assert!(is_identifier("_hole_"));
+        assert!(is_identifier("$Hole1"));
+        assert!(!is_identifier(""));
+        assert!(!is_identifier("1hole"));
+        assert!(!is_identifier("?"));
+        assert!(!is_identifier("hole token"));
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    future::Future,
+    ops::Deref,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use async_trait::async_trait;
+
+use crate::{tree::CodeBlockTree, typedef_gen::ObjectInfoMap};
+
+use super::{
+    AnnotateType, AnnotationAudit, Capabilities, CheckProblem, Diagnostic, LangServer,
+    LangServerCommands, LangServerError, TypeQuality,
+};
+
+/// A pool of language servers of the same kind, that distributes the commands across them.
+/// Each command goes to the server with the fewest commands in flight.
+#[derive(Debug)]
+pub struct LangServerPool<S> {
+    servers: Vec<S>,
+    /// the number of commands in flight, for each server
+    in_flight: Vec<AtomicUsize>,
+}
+
+/// A server of the pool that was picked for a command. The command is counted as
+/// in flight for as long as the guard is alive.
+struct PooledServer<'a, S> {
+    pool: &'a LangServerPool<S>,
+    idx: usize,
+}
+
+impl<S> Deref for PooledServer<'_, S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.pool.servers[self.idx]
+    }
+}
+
+impl<S> Drop for PooledServer<'_, S> {
+    fn drop(&mut self) {
+        self.pool.in_flight[self.idx].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<S: LangServer + Send + 'static> LangServerPool<S> {
+    /// Makes a pool out of the given servers. Panics if there are no servers.
+    pub fn new(servers: Vec<S>) -> Self {
+        assert!(!servers.is_empty(), "a language server pool needs a server");
+        let in_flight = servers.iter().map(|_| AtomicUsize::new(0)).collect();
+        Self { servers, in_flight }
+    }
+
+    /// Spawns a pool of `size` servers (at least one) concurrently, each made by `make`.
+    ///
+    /// # Example
+    /// ```ignore
+    /// let pool = LangServerPool::spawn(4, || TsServerBuilder::new(&path).build()).await?;
+    /// ```
+    pub async fn spawn<F, Fut>(size: usize, make: F) -> Result<Self, LangServerError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<S, LangServerError>> + Send + 'static,
+    {
+        let handles: Vec<_> = (0..size.max(1)).map(|_| tokio::spawn(make())).collect();
+        let mut servers = Vec::with_capacity(handles.len());
+        for handle in handles {
+            servers.push(handle.await.map_err(|_| LangServerError::ProcessSpawn)??);
+        }
+        Ok(Self::new(servers))
+    }
+
+    /// The number of servers in the pool.
+    pub fn size(&self) -> usize {
+        self.servers.len()
+    }
+
+    /// Picks the server with the fewest commands in flight.
+    fn pick(&self) -> PooledServer<'_, S> {
+        let (idx, count) = self
+            .in_flight
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, count)| count.load(Ordering::SeqCst))
+            .unwrap();
+        count.fetch_add(1, Ordering::SeqCst);
+        PooledServer { pool: self, idx }
+    }
+}
+
+#[async_trait]
+impl<S: LangServer + Send + Sync + 'static> LangServerCommands for LangServerPool<S> {
+    async fn capabilities(&self) -> Result<Capabilities, LangServerError> {
+        // all the servers are of the same kind
+        self.pick().capabilities().await
+    }
+
+    async fn pretty_print(
+        &self,
+        code: &str,
+        type_name: &str,
+        types: &[AnnotateType],
+    ) -> Result<String, LangServerError> {
+        self.pick().pretty_print(code, type_name, types).await
+    }
+
+    async fn to_tree(&self, code: &str) -> Result<CodeBlockTree, LangServerError> {
+        self.pick().to_tree(code).await
+    }
+
+    async fn stub(&self, code: &str) -> Result<String, LangServerError> {
+        self.pick().stub(code).await
+    }
+
+    async fn check_complete(
+        &self,
+        original: &str,
+        completed: &str,
+    ) -> Result<(Vec<CheckProblem>, u16), LangServerError> {
+        self.pick().check_complete(original, completed).await
+    }
+
+    async fn check_complete_detailed(
+        &self,
+        original: &str,
+        completed: &str,
+    ) -> Result<(Vec<CheckProblem>, u16, Option<TypeQuality>), LangServerError> {
+        self.pick()
+            .check_complete_detailed(original, completed)
+            .await
+    }
+
+    async fn weave(
+        &self,
+        original: &str,
+        nettle: &str,
+        level: usize,
+    ) -> Result<String, LangServerError> {
+        self.pick().weave(original, nettle, level).await
+    }
+
+    async fn usages(
+        &self,
+        outer_block: &str,
+        inner_block: &str,
+    ) -> Result<(String, usize), LangServerError> {
+        self.pick().usages(outer_block, inner_block).await
+    }
+
+    async fn object_info(&self, code: &str) -> Result<ObjectInfoMap, LangServerError> {
+        self.pick().object_info(code).await
+    }
+
+    async fn typedef_gen(&self, code: &str) -> Result<String, LangServerError> {
+        self.pick().typedef_gen(code).await
+    }
+
+    async fn strip(&self, code: &str, types: &[AnnotateType]) -> Result<String, LangServerError> {
+        self.pick().strip(code, types).await
+    }
+
+    async fn audit(
+        &self,
+        original: &str,
+        completed: &str,
+        types: &[AnnotateType],
+    ) -> Result<Vec<AnnotationAudit>, LangServerError> {
+        self.pick().audit(original, completed, types).await
+    }
+}
+
+#[async_trait]
+impl<S: LangServer + Send + Sync + 'static> LangServer for LangServerPool<S> {
+    /// Makes a pool with a single server, use `LangServerPool::spawn` for bigger pools.
+    async fn make(path: &str) -> Result<Self, LangServerError> {
+        Ok(Self::new(vec![S::make(path).await?]))
+    }
+
+    async fn type_check(&self, code: &str) -> Result<usize, LangServerError> {
+        self.pick().type_check(code).await
+    }
+
+    async fn type_check_detailed(&self, code: &str) -> Result<Vec<Diagnostic>, LangServerError> {
+        self.pick().type_check_detailed(code).await
+    }
+
+    async fn type_check_file(&self, code: &str, file: &str) -> Result<usize, LangServerError> {
+        self.pick().type_check_file(code, file).await
+    }
+
+    async fn type_check_file_detailed(
+        &self,
+        code: &str,
+        file: &str,
+    ) -> Result<Vec<Diagnostic>, LangServerError> {
+        self.pick().type_check_file_detailed(code, file).await
+    }
+
+    fn any_type(&self) -> String {
+        self.servers[0].any_type()
+    }
+
+    fn get_type_parser(&self) -> Option<Box<dyn Fn(&str) -> Option<String> + Sync + Send>> {
+        self.servers[0].get_type_parser()
+    }
+}
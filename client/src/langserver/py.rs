@@ -1,60 +1,180 @@
+use std::str::FromStr;
+
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 
-use crate::{impl_langserver_commands, socket::SocketAbstraction};
+use crate::{
+    impl_langserver_commands,
+    socket::SendToSocket,
+    socket::{SocketAbstraction, SocketTimeouts, Transport},
+};
 
-use super::{LangServer, LangServerError};
+use super::{
+    decode_resp, Diagnostic, LSTypecheckDetailedResp, LSTypecheckResp, LangServer, LangServerError,
+};
 
 #[derive(Debug)]
 pub struct PyServer {
     socket: SocketAbstraction,
+    checker: PyTypeChecker,
+}
+
+/// The type checker that is used by the python server for type checking.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PyTypeChecker {
+    #[default]
+    Mypy,
+    Pyright,
+}
+
+impl FromStr for PyTypeChecker {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mypy" => Ok(PyTypeChecker::Mypy),
+            "pyright" => Ok(PyTypeChecker::Pyright),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Request to the python server for the typecheck command.
+/// in the format of {cmd: "typecheck", text: "the-text", checker: "mypy", file: "pkg/mod.py"},
+/// where `file` is the module of the project that the text replaces, if any.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PyTypecheckReq {
+    cmd: String,
+    text: String,
+    checker: PyTypeChecker,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+}
+
+/// Builder for spawning a `PyServer` with non-default options.
+pub struct PyServerBuilder {
+    server_path: String,
+    timeouts: SocketTimeouts,
+    transport: Transport,
+    checker: PyTypeChecker,
+    project: Option<String>,
 }
 
-// NOTE: this is commented out because it's WIP. sorry for the mess!
+impl PyServerBuilder {
+    /// Creates a new builder, given the path to the `py-ast` directory.
+    pub fn new(server_path: &str) -> Self {
+        Self {
+            server_path: server_path.to_string(),
+            timeouts: SocketTimeouts::default(),
+            transport: Transport::default(),
+            checker: PyTypeChecker::default(),
+            project: None,
+        }
+    }
+
+    pub fn timeouts(mut self, timeouts: SocketTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn checker(mut self, checker: PyTypeChecker) -> Self {
+        self.checker = checker;
+        self
+    }
+
+    /// Starts the server in project mode, given the root directory of the package. The type
+    /// checker then runs from the root, such that imports of sibling modules resolve.
+    pub fn project(mut self, project: &str) -> Self {
+        self.project = Some(project.to_string());
+        self
+    }
+
+    pub async fn build(self) -> Result<PyServer, LangServerError> {
+        let main_path = format!("{}/main.py", self.server_path);
+        let args = ["python3", "-u", &main_path];
+        let project = match &self.project {
+            Some(p) => Some(
+                std::fs::canonicalize(p)
+                    .map_err(|e| LangServerError::Config(format!("invalid project path {p}: {e}")))?
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let extra_args: Vec<&str> = project.iter().map(|p| p.as_str()).collect();
+        let socket = SocketAbstraction::spawn_server_with_args(
+            "python",
+            &args,
+            true,
+            self.transport,
+            &extra_args,
+        )
+        .await
+        .map_err(|_| LangServerError::ProcessSpawn)?
+        .with_timeouts(self.timeouts);
+        Ok(PyServer {
+            socket,
+            checker: self.checker,
+        })
+    }
+}
+
+impl PyServer {
+    /// Sends the typecheck command, optionally as a replacement of a module of the project.
+    async fn send_typecheck(
+        &self,
+        code: &str,
+        file: Option<&str>,
+    ) -> Result<serde_json::Value, LangServerError> {
+        let req = PyTypecheckReq {
+            cmd: "typecheck".to_string(),
+            text: base64::encode(code),
+            checker: self.checker,
+            file: file.map(|f| f.to_string()),
+        };
+        Ok(self
+            .socket
+            .send_req(serde_json::to_value(&req).unwrap())
+            .await?)
+    }
+}
 
 #[async_trait]
 impl LangServer for PyServer {
-    async fn make(_path: &str) -> Result<Self, LangServerError> {
-        // let pid = std::process::id();
-        // let tmp_dir = std::env::temp_dir();
-        // let tmp_socket_file = tmp_dir.join(format!("codex-{}.sock", pid));
-        // println!("tmp_socket_file: {:?}", tmp_socket_file);
-
-        // todo!("python server call");
-        // let mut process = match tokio::process::Command::new("python")
-        // .args([])
-        // .stdout(Stdio::piped())
-        // // stderr is open by default, we want to see the output
-        // .spawn()
-        // {
-        // Ok(p) => p,
-        // Err(_) => return Err(LangServerError::ProcessSpawn),
-        // };
-
-        // // before allowing to connect, wait for the process to output "Listening"
-        // {
-        // let stdout = process.stdout.as_mut().unwrap();
-        // let reader = tokio::io::BufReader::new(stdout);
-        // let mut lines = reader.lines();
-        // println!("client output:");
-        // while let Some(line) = lines.next_line().await.unwrap() {
-        // println!("{}", line);
-        // if line.contains("Listening") {
-        // break;
-        // }
-        // }
-        // }
-        // println!("client ready to connect to socket!");
-        // let socket_path = tmp_socket_file.to_str().unwrap().to_string();
-        // let socket = SocketAbstraction {
-        // socket_path,
-        // process,
-        // };
-        // Ok(Self { socket })
-        todo!()
-    }
-
-    async fn type_check(&self, _code: &str) -> Result<usize, LangServerError> {
-        todo!()
+    async fn make(server_path: &str) -> Result<Self, LangServerError> {
+        PyServerBuilder::new(server_path).build().await
+    }
+
+    async fn type_check(&self, code: &str) -> Result<usize, LangServerError> {
+        // the server runs the selected type checker on the code
+        let resp: LSTypecheckResp = decode_resp(self.send_typecheck(code, None).await?)?;
+        Ok(resp.errors)
+    }
+
+    async fn type_check_detailed(&self, code: &str) -> Result<Vec<Diagnostic>, LangServerError> {
+        let resp: LSTypecheckDetailedResp = decode_resp(self.send_typecheck(code, None).await?)?;
+        Ok(resp.diagnostics)
+    }
+
+    async fn type_check_file(&self, code: &str, file: &str) -> Result<usize, LangServerError> {
+        let resp: LSTypecheckResp = decode_resp(self.send_typecheck(code, Some(file)).await?)?;
+        Ok(resp.errors)
+    }
+
+    async fn type_check_file_detailed(
+        &self,
+        code: &str,
+        file: &str,
+    ) -> Result<Vec<Diagnostic>, LangServerError> {
+        let resp: LSTypecheckDetailedResp =
+            decode_resp(self.send_typecheck(code, Some(file)).await?)?;
+        Ok(resp.diagnostics)
     }
 
     fn any_type(&self) -> String {
@@ -62,8 +182,326 @@ impl LangServer for PyServer {
     }
 
     fn get_type_parser(&self) -> Option<Box<dyn Fn(&str) -> Option<String> + Sync + Send>> {
-        None
+        #[cfg(feature = "pyparser")]
+        {
+            Some(Box::new(py_parse_type))
+        }
+        #[cfg(not(feature = "pyparser"))]
+        {
+            None
+        }
     }
 }
 
 impl_langserver_commands!(PyServer);
+
+#[cfg(feature = "pyparser")]
+/// Parses the given input and extracts the type annotation generated by a model.
+/// Accepts PEP 484 and PEP 604 annotations, e.g. `list[int]`, `int | None`,
+/// `Callable[[str], int]` and string forward references like `"Node"`. Parsing stops at the
+/// end of the type expression, and the rest of the input is discarded if it could follow an
+/// annotation, e.g. ` = 1` or `) -> int:`. Any other rest is rejected.
+pub fn py_parse_type(input: &str) -> Option<String> {
+    let input = input.trim();
+    let mut parser = PyTypeParser::new(input);
+    parser.union()?;
+    let end = parser.pos;
+    parser.skip_ws();
+    let rest = parser.rest();
+    if !rest.is_empty() && !rest.starts_with(['=', ',', ')', ':', '\n', '\r', '#']) {
+        return None;
+    }
+    Some(input[..end].trim().to_string())
+}
+
+/// The keywords that can't be used as names in a type.
+#[cfg(feature = "pyparser")]
+const PY_KEYWORDS: &[&str] = &[
+    "False", "True", "and", "as", "assert", "async", "await", "break", "class", "continue", "def",
+    "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in",
+    "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with",
+    "yield",
+];
+
+/// A recursive descent parser for Python type expressions.
+#[cfg(feature = "pyparser")]
+struct PyTypeParser<'a> {
+    src: &'a str,
+    /// the position right after the last consumed token
+    pos: usize,
+    /// how many brackets deep we are, newlines are only whitespace inside brackets
+    depth: usize,
+}
+
+#[cfg(feature = "pyparser")]
+impl<'a> PyTypeParser<'a> {
+    fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn skip_ws(&mut self) {
+        let nested = self.depth > 0;
+        let rest = self.rest();
+        let trimmed = rest
+            .trim_start_matches(|c| c == ' ' || c == '\t' || (nested && (c == '\n' || c == '\r')));
+        self.pos += rest.len() - trimmed.len();
+    }
+
+    /// Consumes the given token if it's next, otherwise nothing is consumed.
+    fn eat(&mut self, tok: &str) -> bool {
+        let start = self.pos;
+        self.skip_ws();
+        if self.rest().starts_with(tok) {
+            self.pos += tok.len();
+            true
+        } else {
+            self.pos = start;
+            false
+        }
+    }
+
+    /// Consumes the given closing bracket, failing if it's not next.
+    fn close(&mut self, tok: &str) -> Option<()> {
+        // newlines before the bracket still belong to the nested expression
+        let closed = self.eat(tok);
+        self.depth -= 1;
+        closed.then_some(())
+    }
+
+    /// Consumes an identifier, which may be a keyword.
+    fn ident(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        self.skip_ws();
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let ident = &rest[..len];
+        if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+            self.pos = start;
+            return None;
+        }
+        self.pos += len;
+        Some(ident)
+    }
+
+    /// Consumes a possibly dotted name, e.g. `typing.List`, returning its last part.
+    fn dotted_name(&mut self) -> Option<&'a str> {
+        let mut name = self.ident()?;
+        if PY_KEYWORDS.contains(&name) {
+            return None;
+        }
+        loop {
+            let before_dot = self.pos;
+            if !self.eat(".") {
+                break;
+            }
+            match self.ident() {
+                Some(part) if !PY_KEYWORDS.contains(&part) && name != "None" => name = part,
+                // a trailing dot is not part of the type
+                _ => {
+                    self.pos = before_dot;
+                    break;
+                }
+            }
+        }
+        Some(name)
+    }
+
+    /// Consumes a single-line string literal, returning its contents.
+    fn string(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        self.skip_ws();
+        let rest = self.rest();
+        let quote = match rest.chars().next() {
+            Some(c @ ('"' | '\'')) => c,
+            _ => {
+                self.pos = start;
+                return None;
+            }
+        };
+        let mut escaped = false;
+        for (i, c) in rest.char_indices().skip(1) {
+            match c {
+                '\n' => break,
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                _ if c == quote => {
+                    self.pos += i + 1;
+                    return Some(&rest[1..i]);
+                }
+                _ => {}
+            }
+        }
+        self.pos = start;
+        None
+    }
+
+    /// Consumes a number, for `Literal` types.
+    fn number(&mut self) -> Option<()> {
+        let start = self.pos;
+        self.eat("-");
+        self.skip_ws();
+        let rest = self.rest();
+        if !rest.starts_with(|c: char| c.is_ascii_digit()) {
+            self.pos = start;
+            return None;
+        }
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(rest.len());
+        self.pos += len;
+        Some(())
+    }
+
+    /// type := primary ('|' primary)*
+    fn union(&mut self) -> Option<()> {
+        self.primary()?;
+        while self.eat("|") {
+            self.primary()?;
+        }
+        Some(())
+    }
+
+    /// primary := string | 'None' | dotted_name ('[' subscripts ']')?
+    fn primary(&mut self) -> Option<()> {
+        if let Some(forward_ref) = self.string() {
+            // a forward reference has to be a complete type by itself
+            let forward_ref = forward_ref.trim();
+            return (py_parse_type(forward_ref)? == forward_ref).then_some(());
+        }
+        let name = self.dotted_name()?;
+        if name != "None" && self.eat("[") {
+            self.depth += 1;
+            self.subscripts(name == "Literal")?;
+            self.close("]")?;
+        }
+        Some(())
+    }
+
+    /// subscripts := subscript (',' subscript)* ','?
+    fn subscripts(&mut self, literal: bool) -> Option<()> {
+        loop {
+            self.subscript(literal)?;
+            if !self.eat(",") {
+                return Some(());
+            }
+            let before = self.pos;
+            if self.eat("]") {
+                // trailing comma
+                self.pos = before;
+                return Some(());
+            }
+        }
+    }
+
+    /// subscript := '...' | '[' (type (',' type)*)? ']' | type,
+    /// or the values of a `Literal`
+    fn subscript(&mut self, literal: bool) -> Option<()> {
+        if literal {
+            if self.string().is_some() || self.number().is_some() {
+                return Some(());
+            }
+            let start = self.pos;
+            if let Some("True" | "False") = self.ident() {
+                return Some(());
+            }
+            self.pos = start;
+            // e.g. enum members
+            return self.dotted_name().map(|_| ());
+        }
+        if self.eat("...") {
+            return Some(());
+        }
+        if self.eat("[") {
+            // the parameters of a `Callable`
+            self.depth += 1;
+            let before = self.pos;
+            if !self.eat("]") {
+                self.pos = before;
+                self.union()?;
+                while self.eat(",") {
+                    self.union()?;
+                }
+                self.close("]")?;
+            } else {
+                self.depth -= 1;
+            }
+            return Some(());
+        }
+        self.union()
+    }
+}
+
+#[cfg(all(test, feature = "pyparser"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_type_expressions() {
+        let types = [
+            "int",
+            "None",
+            "typing.List[int]",
+            "Optional[str]",
+            "Optional[Dict[str, int]]",
+            "int | None",
+            "list[int] | dict[str, set[int]] | None",
+            "Dict[str, List[Tuple[int, ...]]]",
+            "Callable[[int, str], bool]",
+            "Callable[[], None]",
+            "Callable[..., Awaitable[int]]",
+            "\"Node\"",
+            "Optional['Node']",
+            "Literal['a', \"b\", 1, -2, True]",
+            "Tuple[int,]",
+            "Dict[\n    str,\n    int,\n]",
+        ];
+        for ty in types {
+            assert_eq!(py_parse_type(ty).as_deref(), Some(ty), "{ty}");
+        }
+    }
+
+    #[test]
+    fn drops_what_follows_an_annotation() {
+        assert_eq!(py_parse_type("int = 5").as_deref(), Some("int"));
+        assert_eq!(
+            py_parse_type("List[int]) -> None:").as_deref(),
+            Some("List[int]")
+        );
+        assert_eq!(py_parse_type("str, b: int").as_deref(), Some("str"));
+        assert_eq!(py_parse_type("  int  # a comment").as_deref(), Some("int"));
+        assert_eq!(py_parse_type("int\nx = 1").as_deref(), Some("int"));
+    }
+
+    #[test]
+    fn rejects_invalid_types() {
+        let inputs = [
+            "",
+            "garbage!!",
+            "int str",
+            "List[int",
+            "List[int]]",
+            "int |",
+            "| int",
+            "def",
+            "typing.",
+            "1int",
+            "Callable[[int] int]",
+            "\"unterminated",
+            "\"not a type\"",
+            "Optional[\n",
+        ];
+        for input in inputs {
+            assert_eq!(py_parse_type(input), None, "{input:?}");
+        }
+    }
+}
@@ -1,36 +1,197 @@
 use async_trait::async_trait;
 
-use crate::{impl_langserver_commands, socket::SendToSocket, socket::SocketAbstraction};
+use crate::{
+    impl_langserver_commands,
+    socket::SendToSocket,
+    socket::{SocketAbstraction, SocketTimeouts, Transport},
+};
 
-use super::{LSReq, LangServer, LangServerError};
+use super::{
+    decode_resp, AnnotateType, CompilerOptions, Diagnostic, LSTypecheckDetailedResp,
+    LSTypecheckReq, LSTypecheckResp, LangServer, LangServerError, NativeCommands,
+};
+
+#[cfg(feature = "tsprinter")]
+mod layout; // where swc's output differs from the typescript printer
+#[cfg(feature = "tsprinter")]
+pub mod printer; // in-process printing with swc
+#[cfg(feature = "tsweaver")]
+pub mod weaver; // in-process weaving with swc
 
 #[derive(Debug)]
 pub struct TsServer {
     socket: SocketAbstraction,
+    /// whether types are written as JSDoc comments in javascript code
+    jsdoc: bool,
+    /// the options that type checking is done with, instead of the defaults of the server
+    compiler_options: Option<CompilerOptions>,
+    /// whether `print` and `weave` are tried in-process with swc first
+    #[cfg_attr(not(feature = "tsprinter"), allow(dead_code))]
+    native: bool,
 }
 
-#[async_trait]
-impl LangServer for TsServer {
-    async fn make(server_path: &str) -> Result<Self, LangServerError> {
-        let args = ["npm", "--prefix", server_path, "start"];
-        let socket = SocketAbstraction::spawn_server("typescript", &args, true)
-            .await
-            .map_err(|_| LangServerError::ProcessSpawn)?;
-        Ok(Self { socket })
+/// Builder for spawning a `TsServer` with non-default options.
+pub struct TsServerBuilder {
+    server_path: String,
+    timeouts: SocketTimeouts,
+    transport: Transport,
+    project: Option<String>,
+    jsdoc: bool,
+    compiler_options: Option<CompilerOptions>,
+    native: bool,
+}
+
+impl TsServerBuilder {
+    /// Creates a new builder, given the path to the `ts-compiler` directory.
+    pub fn new(server_path: &str) -> Self {
+        Self {
+            server_path: server_path.to_string(),
+            timeouts: SocketTimeouts::default(),
+            transport: Transport::default(),
+            project: None,
+            jsdoc: false,
+            compiler_options: None,
+            native: false,
+        }
     }
 
-    async fn type_check(&self, code: &str) -> Result<usize, LangServerError> {
-        // for typescript, we use the language server for typechecking
-        let req = LSReq {
+    pub fn timeouts(mut self, timeouts: SocketTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Starts the server in project mode, given either the root directory of the project
+    /// or the path to its `tsconfig.json`. Type checking with `type_check_file` will then
+    /// resolve imports from the other files of the project.
+    pub fn project(mut self, project: &str) -> Self {
+        self.project = Some(project.to_string());
+        self
+    }
+
+    /// Starts the server in JSDoc mode, for plain javascript code. Holes are printed as
+    /// JSDoc comments (`/** @param {_hole_} x */`, `@returns`, `@type`) instead of inline
+    /// types, and type checking is done with `checkJs`.
+    pub fn jsdoc(mut self, jsdoc: bool) -> Self {
+        self.jsdoc = jsdoc;
+        self
+    }
+
+    /// Type checks with the given compiler options, either inline or from a `tsconfig.json`.
+    /// They are put on top of the default options of the server, or of the options of the
+    /// project in project mode.
+    pub fn compiler_options(mut self, options: CompilerOptions) -> Self {
+        self.compiler_options = Some(options);
+        self
+    }
+
+    /// Whether to run `print` and `weave` in-process with swc when it can. It is off by
+    /// default, as the swc printer and weaver are only checked against the server by the
+    /// ignored corpus tests. Without it, every command is sent to the server.
+    pub fn native(mut self, native: bool) -> Self {
+        self.native = native;
+        self
+    }
+
+    pub async fn build(self) -> Result<TsServer, LangServerError> {
+        let args = ["npm", "--prefix", &self.server_path, "start"];
+        // the server is started in the `ts-compiler` directory, so the path must be absolute
+        let project = match &self.project {
+            Some(p) => Some(
+                std::fs::canonicalize(p)
+                    .map_err(|e| LangServerError::Config(format!("invalid project path {p}: {e}")))?
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            None => None,
+        };
+        let mut extra_args: Vec<&str> = vec![];
+        if self.jsdoc {
+            extra_args.push("--jsdoc");
+        }
+        extra_args.extend(project.iter().map(|p| p.as_str()));
+        let socket = SocketAbstraction::spawn_server_with_args(
+            "typescript",
+            &args,
+            true,
+            self.transport,
+            &extra_args,
+        )
+        .await
+        .map_err(|_| LangServerError::ProcessSpawn)?
+        .with_timeouts(self.timeouts);
+        let compiler_options = match self.compiler_options {
+            Some(options) => Some(options.canonicalize()?),
+            None => None,
+        };
+        Ok(TsServer {
+            socket,
+            jsdoc: self.jsdoc,
+            compiler_options,
+            native: self.native,
+        })
+    }
+}
+
+impl TsServer {
+    /// Whether the server was started in JSDoc mode.
+    pub fn is_jsdoc(&self) -> bool {
+        self.jsdoc
+    }
+
+    /// Sends the typecheck command, optionally as a replacement of a file of the project.
+    async fn send_typecheck(
+        &self,
+        code: &str,
+        file: Option<&str>,
+    ) -> Result<serde_json::Value, LangServerError> {
+        let req = LSTypecheckReq {
             cmd: "typecheck".to_string(),
             text: base64::encode(code),
+            file: file.map(|f| f.to_string()),
+            compiler_options: self.compiler_options.clone(),
         };
-        let resp = self
+        Ok(self
             .socket
             .send_req(serde_json::to_value(&req).unwrap())
-            .await?;
+            .await?)
+    }
+}
 
-        Ok(resp["errors"].as_u64().unwrap() as usize)
+#[async_trait]
+impl LangServer for TsServer {
+    async fn make(server_path: &str) -> Result<Self, LangServerError> {
+        TsServerBuilder::new(server_path).build().await
+    }
+
+    async fn type_check(&self, code: &str) -> Result<usize, LangServerError> {
+        // for typescript, we use the language server for typechecking
+        let resp: LSTypecheckResp = decode_resp(self.send_typecheck(code, None).await?)?;
+        Ok(resp.errors)
+    }
+
+    async fn type_check_detailed(&self, code: &str) -> Result<Vec<Diagnostic>, LangServerError> {
+        let resp: LSTypecheckDetailedResp = decode_resp(self.send_typecheck(code, None).await?)?;
+        Ok(resp.diagnostics)
+    }
+
+    async fn type_check_file(&self, code: &str, file: &str) -> Result<usize, LangServerError> {
+        let resp: LSTypecheckResp = decode_resp(self.send_typecheck(code, Some(file)).await?)?;
+        Ok(resp.errors)
+    }
+
+    async fn type_check_file_detailed(
+        &self,
+        code: &str,
+        file: &str,
+    ) -> Result<Vec<Diagnostic>, LangServerError> {
+        let resp: LSTypecheckDetailedResp =
+            decode_resp(self.send_typecheck(code, Some(file)).await?)?;
+        Ok(resp.diagnostics)
     }
 
     fn any_type(&self) -> String {
@@ -49,8 +210,48 @@ impl LangServer for TsServer {
     }
 }
 
+impl NativeCommands for TsServer {
+    fn native_pretty_print(
+        &self,
+        code: &str,
+        type_name: &str,
+        types: &[AnnotateType],
+    ) -> Option<String> {
+        #[cfg(feature = "tsprinter")]
+        {
+            // falls back to the server if swc can't parse the code. the printer only
+            // knows about inline types, so JSDoc mode always goes to the server
+            if self.is_jsdoc() || !self.native {
+                return None;
+            }
+            printer::print_source(code, type_name, types)
+        }
+        #[cfg(not(feature = "tsprinter"))]
+        {
+            let _ = (code, type_name, types);
+            None
+        }
+    }
+
+    fn native_weave(&self, original: &str, nettle: &str, level: usize) -> Option<String> {
+        #[cfg(feature = "tsweaver")]
+        {
+            // falls back to the server if swc can't parse the programs
+            if self.is_jsdoc() || !self.native {
+                return None;
+            }
+            weaver::weave_programs(original, nettle, level)
+        }
+        #[cfg(not(feature = "tsweaver"))]
+        {
+            let _ = (original, nettle, level);
+            None
+        }
+    }
+}
+
 // implement the LangServerCommands trait
-impl_langserver_commands!(TsServer);
+impl_langserver_commands!(TsServer, native);
 
 #[cfg(feature = "tsparser")]
 /// Parses the given input and extracts the type generated by a model.
@@ -0,0 +1,436 @@
+//! Makes the output of swc's code generator match the printer of the typescript server.
+//! swc formats everything from the AST, while the typescript printer keeps parts of the
+//! original layout, e.g. single-line object literals and function bodies, or the line breaks
+//! inside of expressions. Modules that have any of those are left to the server, and the
+//! spacing that the two printers disagree on is fixed up after printing.
+
+use swc_common::{
+    comments::{CommentKind, SingleThreadedComments},
+    BytePos, SourceMap, Spanned,
+};
+use swc_ecma_ast::{
+    ArrayLit, ArrayPat, ArrowExpr, AssignExpr, BinExpr, BlockStmt, BlockStmtOrExpr, Class,
+    ClassMember, CondExpr, Decorator, DoWhileStmt, ForInStmt, ForOfStmt, ForStmt, IfStmt,
+    MemberExpr, Module, ObjectLit, ObjectPat, Prop, PropOrSpread, Regex, SeqExpr, Stmt, Str,
+    SwitchCase, SwitchStmt, Tpl, TsEnumDecl, TsExportAssignment, TsInterfaceBody, TsModuleDecl,
+    TsTypeLit, WhileStmt, WithStmt,
+};
+use swc_ecma_visit::{Visit, VisitWith};
+
+/// Keywords that the typescript printer always follows with a space before `(`.
+const PAREN_KEYWORDS: [&str; 7] = [
+    "function*",
+    "function",
+    "if",
+    "for",
+    "while",
+    "switch",
+    "catch",
+];
+
+/// Keywords that the typescript printer always follows with a space before `{`.
+const BRACE_KEYWORDS: [&str; 4] = ["else", "try", "finally", "do"];
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// Adds the spaces that the typescript printer puts where swc doesn't: after keywords like
+/// `function` and `for`, before the `{` of a body (`) {`), and around `=>`. Also removes the
+/// extra spaces that swc puts in object patterns.
+pub(crate) fn fix_spacing(printed: &str) -> String {
+    let mut out = String::with_capacity(printed.len());
+    let mut prev: Option<char> = None;
+    let mut rest = printed;
+    'outer: while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("=>") {
+            out.push_str("=>");
+            if after.starts_with(|c: char| !c.is_whitespace()) {
+                out.push(' ');
+            }
+            prev = Some('>');
+            rest = after;
+            continue;
+        }
+        // swc puts a space before the commas of object patterns, `{ a , b }`, or two before
+        // the closing brace if the pattern has a type, `{ a, b  }: T`
+        if let Some(after) = rest.strip_prefix(" ,") {
+            out.push(',');
+            prev = Some(',');
+            rest = after;
+            continue;
+        }
+        if prev.is_some_and(|p| !p.is_whitespace()) && rest.starts_with("  }") {
+            out.push(' ');
+            prev = Some(' ');
+            rest = &rest[2..];
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix("){") {
+            out.push_str(") {");
+            prev = Some('{');
+            rest = after;
+            continue;
+        }
+        // keywords have to start a word, and can't be a property name like `a.for(`
+        if !prev.is_some_and(|p| is_identifier_char(p) || p == '.') {
+            let keywords = PAREN_KEYWORDS
+                .iter()
+                .map(|kw| (kw, '('))
+                .chain(BRACE_KEYWORDS.iter().map(|kw| (kw, '{')));
+            for (kw, next) in keywords {
+                if let Some(after) = rest.strip_prefix(kw) {
+                    if after.starts_with(next) {
+                        out.push_str(kw);
+                        out.push(' ');
+                        prev = Some(' ');
+                        rest = after;
+                        continue 'outer;
+                    }
+                }
+            }
+        }
+        out.push(c);
+        prev = Some(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// Checks that swc prints the given module like the typescript printer, up to the spacing
+/// that `fix_spacing` fixes. `code` is the source the module was parsed from.
+pub(crate) fn matches_ts_printer(
+    cm: &SourceMap,
+    code: &str,
+    module: &Module,
+    comments: &SingleThreadedComments,
+) -> bool {
+    let mut checker = LayoutChecker {
+        cm,
+        code,
+        templates: vec![],
+        ok: true,
+    };
+    module.visit_with(&mut checker);
+    checker.ok && checker.comments_match(comments) && !checker.has_blank_lines()
+}
+
+struct LayoutChecker<'a> {
+    cm: &'a SourceMap,
+    code: &'a str,
+    /// the ranges of the template literals, their text is printed as it is
+    templates: Vec<(usize, usize)>,
+    ok: bool,
+}
+
+impl LayoutChecker<'_> {
+    fn offset(&self, pos: BytePos) -> usize {
+        self.cm.lookup_byte_offset(pos).pos.0 as usize
+    }
+
+    fn text(&self, lo: BytePos, hi: BytePos) -> &str {
+        self.code
+            .get(self.offset(lo)..self.offset(hi))
+            .unwrap_or_default()
+    }
+
+    /// Whether there is a line break between the two positions.
+    fn breaks(&self, lo: BytePos, hi: BytePos) -> bool {
+        self.text(lo, hi).contains('\n')
+    }
+
+    /// Rejects text that is printed as it is, if the spacing fixes would change it.
+    fn check_verbatim(&mut self, lo: BytePos, hi: BytePos) {
+        let text = self.text(lo, hi);
+        if fix_spacing(text) != text {
+            self.ok = false;
+        }
+    }
+
+    /// The typescript printer keeps a list on a single line if it was written that way, and
+    /// keeps its trailing comma. swc always breaks the lines, and drops the comma. `items` is
+    /// the start of the first item and the end of the last one, None if the list is empty.
+    fn check_list(&mut self, lo: BytePos, hi: BytePos, items: Option<(BytePos, BytePos)>) {
+        match items {
+            Some((first, last)) => {
+                self.ok &= self.breaks(lo, first) && !self.text(last, hi).contains(',');
+            }
+            None => self.ok &= !self.breaks(lo, hi),
+        }
+    }
+
+    /// The typescript printer keeps the trailing comma of a pattern, which swc drops. The
+    /// pattern ends at `close`, the rest of its span is its type.
+    fn check_no_trailing_comma(&mut self, last: BytePos, hi: BytePos, close: char) {
+        let rest = self.text(last, hi);
+        self.ok &= !rest.split(close).next().unwrap_or_default().contains(',');
+    }
+
+    /// Only line comments on their own line, before some code of the same block, are
+    /// printed in the same place by both printers.
+    fn comments_match(&self, comments: &SingleThreadedComments) -> bool {
+        let (leading, trailing) = comments.borrow_all();
+        if trailing.values().any(|c| !c.is_empty()) {
+            return false;
+        }
+        leading.values().flatten().all(|comment| {
+            let start = self.offset(comment.span.lo);
+            let end = self.offset(comment.span.hi);
+            let line_start = self.code[..start].rfind('\n').map_or(0, |i| i + 1);
+            let next = self.code[end..]
+                .lines()
+                .map(str::trim)
+                .find(|l| !l.is_empty() && !l.starts_with("//"));
+            comment.kind == CommentKind::Line
+                && self.code[line_start..start].trim().is_empty()
+                && fix_spacing(&comment.text) == *comment.text
+                && next.is_some_and(|l| !l.starts_with(['}', ')', ']']))
+        })
+    }
+
+    /// swc drops the blank lines between statements and members, which the typescript printer
+    /// keeps. Blank lines in template literals are printed as they are.
+    fn has_blank_lines(&self) -> bool {
+        let mut start = 0;
+        for line in self.code.trim_end().split_inclusive('\n') {
+            let in_template = self
+                .templates
+                .iter()
+                .any(|&(lo, hi)| lo < start && start < hi);
+            if line.trim().is_empty() && !in_template {
+                return true;
+            }
+            start += line.len();
+        }
+        false
+    }
+
+    /// Statements of `if`, `else` and loops that aren't blocks are put on their own line by
+    /// the typescript printer.
+    fn check_body(&mut self, body: &Stmt) {
+        self.ok &= matches!(body, Stmt::Block(_));
+    }
+}
+
+impl Visit for LayoutChecker<'_> {
+    fn visit_str(&mut self, n: &Str) {
+        self.check_verbatim(n.span.lo, n.span.hi);
+    }
+
+    fn visit_regex(&mut self, n: &Regex) {
+        self.check_verbatim(n.span.lo, n.span.hi);
+    }
+
+    fn visit_tpl(&mut self, n: &Tpl) {
+        for quasi in n.quasis.iter() {
+            self.check_verbatim(quasi.span.lo, quasi.span.hi);
+        }
+        self.templates
+            .push((self.offset(n.span.lo), self.offset(n.span.hi)));
+        n.visit_children_with(self);
+    }
+
+    fn visit_object_lit(&mut self, n: &ObjectLit) {
+        // swc prints a space between the name and the parameters of methods
+        self.ok &= !n.props.iter().any(|p| match p {
+            PropOrSpread::Prop(p) => {
+                matches!(**p, Prop::Method(_) | Prop::Getter(_) | Prop::Setter(_))
+            }
+            PropOrSpread::Spread(_) => false,
+        });
+        let items = n.props.first().zip(n.props.last());
+        self.check_list(
+            n.span.lo,
+            n.span.hi,
+            items.map(|(first, last)| (first.span().lo, last.span().hi)),
+        );
+        n.visit_children_with(self);
+    }
+
+    fn visit_array_lit(&mut self, n: &ArrayLit) {
+        // elisions, e.g. `[, a]`
+        if n.elems.iter().any(Option::is_none) {
+            self.ok = false;
+            return;
+        }
+        let spans: Vec<_> = n.elems.iter().flatten().map(|e| e.span()).collect();
+        let items = spans.first().zip(spans.last());
+        self.check_list(
+            n.span.lo,
+            n.span.hi,
+            items.map(|(first, last)| (first.lo, last.hi)),
+        );
+        n.visit_children_with(self);
+    }
+
+    fn visit_object_pat(&mut self, n: &ObjectPat) {
+        if let Some(last) = n.props.last() {
+            self.check_no_trailing_comma(last.span().hi, n.span.hi, '}');
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_array_pat(&mut self, n: &ArrayPat) {
+        match n.elems.last() {
+            Some(Some(last)) => self.check_no_trailing_comma(last.span().hi, n.span.hi, ']'),
+            Some(None) => self.ok = false,
+            None => {}
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_block_stmt(&mut self, n: &BlockStmt) {
+        // empty blocks are printed as `{ }`, and single-line function bodies are kept
+        match n.stmts.first() {
+            Some(first) => self.ok &= self.breaks(n.span.lo, first.span().lo),
+            None => self.ok = false,
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_class(&mut self, n: &Class) {
+        // swc drops `abstract`, and the commas between the implemented interfaces
+        self.ok &= !n.body.is_empty() && !n.is_abstract && n.implements.len() <= 1;
+        n.visit_children_with(self);
+    }
+
+    fn visit_class_member(&mut self, n: &ClassMember) {
+        self.ok &= match n {
+            ClassMember::Method(m) => !m.is_abstract,
+            ClassMember::ClassProp(p) => !p.is_abstract,
+            ClassMember::Constructor(_)
+            | ClassMember::PrivateMethod(_)
+            | ClassMember::PrivateProp(_) => true,
+            // swc doesn't end index signatures with `;`
+            ClassMember::TsIndexSignature(_)
+            | ClassMember::Empty(_)
+            | ClassMember::StaticBlock(_) => false,
+        };
+        n.visit_children_with(self);
+    }
+
+    fn visit_decorator(&mut self, _: &Decorator) {
+        self.ok = false;
+    }
+
+    fn visit_ts_interface_body(&mut self, n: &TsInterfaceBody) {
+        self.ok &= !n.body.is_empty();
+        n.visit_children_with(self);
+    }
+
+    fn visit_ts_enum_decl(&mut self, n: &TsEnumDecl) {
+        self.ok &= !n.members.is_empty();
+        n.visit_children_with(self);
+    }
+
+    fn visit_ts_module_decl(&mut self, _: &TsModuleDecl) {
+        // swc prints namespaces as `module`
+        self.ok = false;
+    }
+
+    fn visit_ts_export_assignment(&mut self, _: &TsExportAssignment) {
+        // swc drops the semicolon of `export =`
+        self.ok = false;
+    }
+
+    fn visit_do_while_stmt(&mut self, _: &DoWhileStmt) {
+        // swc drops the semicolon after `while (...)`
+        self.ok = false;
+    }
+
+    fn visit_if_stmt(&mut self, n: &IfStmt) {
+        self.check_body(&n.cons);
+        match n.alt.as_deref() {
+            None | Some(Stmt::If(_)) => {}
+            Some(alt) => self.check_body(alt),
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_for_stmt(&mut self, n: &ForStmt) {
+        self.check_body(&n.body);
+        n.visit_children_with(self);
+    }
+
+    fn visit_for_in_stmt(&mut self, n: &ForInStmt) {
+        self.check_body(&n.body);
+        n.visit_children_with(self);
+    }
+
+    fn visit_for_of_stmt(&mut self, n: &ForOfStmt) {
+        self.check_body(&n.body);
+        n.visit_children_with(self);
+    }
+
+    fn visit_while_stmt(&mut self, n: &WhileStmt) {
+        self.check_body(&n.body);
+        n.visit_children_with(self);
+    }
+
+    fn visit_with_stmt(&mut self, n: &WithStmt) {
+        self.check_body(&n.body);
+        n.visit_children_with(self);
+    }
+
+    fn visit_switch_stmt(&mut self, n: &SwitchStmt) {
+        self.ok &= !n.cases.is_empty();
+        n.visit_children_with(self);
+    }
+
+    fn visit_ts_type_lit(&mut self, n: &TsTypeLit) {
+        self.ok &= !n.members.is_empty();
+        n.visit_children_with(self);
+    }
+
+    fn visit_switch_case(&mut self, n: &SwitchCase) {
+        // a single statement on the line of the `case` stays there
+        if let Some(first) = n.cons.first() {
+            self.ok &= self.breaks(n.span.lo, first.span().lo);
+        }
+        n.visit_children_with(self);
+    }
+
+    // the typescript printer keeps the line breaks between the operands and the operators
+
+    fn visit_bin_expr(&mut self, n: &BinExpr) {
+        self.ok &= !self.breaks(n.left.span().hi, n.right.span().lo);
+        n.visit_children_with(self);
+    }
+
+    fn visit_assign_expr(&mut self, n: &AssignExpr) {
+        self.ok &= !self.breaks(n.left.span().hi, n.right.span().lo);
+        n.visit_children_with(self);
+    }
+
+    fn visit_cond_expr(&mut self, n: &CondExpr) {
+        self.ok &= !self.breaks(n.test.span().hi, n.cons.span().lo)
+            && !self.breaks(n.cons.span().hi, n.alt.span().lo);
+        n.visit_children_with(self);
+    }
+
+    fn visit_member_expr(&mut self, n: &MemberExpr) {
+        self.ok &= !self.breaks(n.obj.span().hi, n.prop.span().lo);
+        n.visit_children_with(self);
+    }
+
+    fn visit_seq_expr(&mut self, n: &SeqExpr) {
+        self.ok &= !n
+            .exprs
+            .windows(2)
+            .any(|w| self.breaks(w[0].span().hi, w[1].span().lo));
+        n.visit_children_with(self);
+    }
+
+    fn visit_arrow_expr(&mut self, n: &ArrowExpr) {
+        // swc adds the parentheses around a single parameter, e.g. `x => x`
+        if let Some(first) = n.params.first() {
+            self.ok &= self.text(n.span.lo, first.span().lo).contains('(');
+        }
+        if let BlockStmtOrExpr::Expr(body) = &n.body {
+            let head = self.text(n.span.lo, body.span().lo);
+            let after_arrow = head.rsplit("=>").next().unwrap_or_default();
+            self.ok &= !after_arrow.contains('\n');
+        }
+        n.visit_children_with(self);
+    }
+}
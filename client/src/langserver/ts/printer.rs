@@ -0,0 +1,471 @@
+//! An in-process implementation of the `print` command of the typescript server, using swc.
+//! Mirrors `printer.ts` in the `ts-compiler` directory, such that the output can be used
+//! interchangeably with the one of the server.
+
+use swc_common::{comments::SingleThreadedComments, sync::Lrc, FileName, SourceMap, DUMMY_SP};
+use swc_ecma_ast::{
+    ArrowExpr, BindingIdent, CatchClause, ClassMethod, ClassProp, Constructor, Decl, DefaultDecl,
+    EsVersion, Expr, FnDecl, FnExpr, Function, Ident, MethodKind, MethodProp, Module,
+    ParamOrTsParamProp, Pat, PrivateMethod, PrivateProp, Prop, TsEntityName, TsFnParam, TsFnType,
+    TsParamPropParam, TsPropertySignature, TsType, TsTypeAnn, TsTypeParam, TsTypeRef,
+};
+use swc_ecma_codegen::{text_writer::JsWriter, Emitter};
+use swc_ecma_parser::{lexer::Lexer, Parser, StringInput, Syntax, TsConfig};
+use swc_ecma_visit::{VisitMut, VisitMutWith};
+
+use super::layout;
+use crate::langserver::AnnotateType;
+
+/// Prints the given code, annotating every missing type of the given kinds with `type_name`.
+/// Returns None if the code could not be parsed by swc, or if swc would lay it out differently
+/// than the typescript printer; in that case the typescript server should be used instead.
+pub fn print_source(code: &str, type_name: &str, types: &[AnnotateType]) -> Option<String> {
+    let cm: Lrc<SourceMap> = Default::default();
+    let comments = SingleThreadedComments::default();
+    let mut module = parse_module(&cm, code, &comments)?;
+    if !layout::matches_ts_printer(&cm, code, &module, &comments) {
+        return None;
+    }
+
+    let types = &AnnotateType::with_legacy_positions(types);
+    module.visit_mut_with(&mut HoleInserter { type_name, types });
+    emit_module(&module, cm, &comments).map(|printed| layout::fix_spacing(&printed))
+}
+
+/// Parses the given code as a typescript module, collecting the comments into `comments`.
+/// Returns None if there were any errors.
+pub(crate) fn parse_module(
+    cm: &Lrc<SourceMap>,
+    code: &str,
+    comments: &SingleThreadedComments,
+) -> Option<Module> {
+    let fm = cm.new_source_file(FileName::Anon, code.to_string());
+    let lexer = Lexer::new(
+        Syntax::Typescript(TsConfig {
+            decorators: true,
+            ..Default::default()
+        }),
+        EsVersion::latest(),
+        StringInput::from(&*fm),
+        Some(comments),
+    );
+    let mut parser = Parser::new_from(lexer);
+    let module = parser.parse_module().ok()?;
+    if !parser.take_errors().is_empty() {
+        return None;
+    }
+    Some(module)
+}
+
+/// Prints the given module with swc's code generator, keeping the comments.
+pub(crate) fn emit_module(
+    module: &Module,
+    cm: Lrc<SourceMap>,
+    comments: &SingleThreadedComments,
+) -> Option<String> {
+    let mut buf = vec![];
+    {
+        let mut emitter = Emitter {
+            cfg: Default::default(),
+            cm: cm.clone(),
+            comments: Some(comments),
+            wr: JsWriter::new(cm, "\n", &mut buf, None),
+        };
+        emitter.emit_module(module).ok()?;
+    }
+    String::from_utf8(buf).ok()
+}
+
+/// Creates the type that is inserted into the holes.
+fn fake_ts_type(type_name: &str) -> Box<TsType> {
+    Box::new(TsType::TsTypeRef(TsTypeRef {
+        span: DUMMY_SP,
+        type_name: TsEntityName::Ident(Ident::new(type_name.into(), DUMMY_SP)),
+        type_params: None,
+    }))
+}
+
+/// Creates the type annotation that is inserted into the holes.
+fn fake_type(type_name: &str) -> Box<TsTypeAnn> {
+    Box::new(TsTypeAnn {
+        span: DUMMY_SP,
+        type_ann: fake_ts_type(type_name),
+    })
+}
+
+/// Returns the type annotation slot of the given pattern, if it can have one.
+pub(crate) fn pat_type_ann(pat: &mut Pat) -> Option<&mut Option<Box<TsTypeAnn>>> {
+    match pat {
+        Pat::Ident(BindingIdent { type_ann, .. }) => Some(type_ann),
+        Pat::Array(p) => Some(&mut p.type_ann),
+        Pat::Object(p) => Some(&mut p.type_ann),
+        Pat::Rest(p) => Some(&mut p.type_ann),
+        // the type goes on the left of a default value, e.g. `x: number = 1`
+        Pat::Assign(p) => pat_type_ann(&mut p.left),
+        Pat::Invalid(_) | Pat::Expr(_) => None,
+    }
+}
+
+/// Whether the given pattern destructures its value, e.g. `{ a, b }` or `[a, ...rest] = []`.
+fn is_destructuring(pat: &Pat) -> bool {
+    match pat {
+        Pat::Array(_) | Pat::Object(_) => true,
+        Pat::Assign(p) => is_destructuring(&p.left),
+        Pat::Rest(p) => is_destructuring(&p.arg),
+        Pat::Ident(_) | Pat::Invalid(_) | Pat::Expr(_) => false,
+    }
+}
+
+/// Inserts the holes into the AST, equivalent to `typeTraversal` of the typescript server.
+struct HoleInserter<'a> {
+    type_name: &'a str,
+    types: &'a [AnnotateType],
+}
+
+impl HoleInserter<'_> {
+    fn enabled(&self, ty: AnnotateType) -> bool {
+        self.types.contains(&ty)
+    }
+
+    /// Puts a hole in the given slot, if it's empty. Existing types are never changed.
+    fn fill(&self, slot: &mut Option<Box<TsTypeAnn>>) {
+        if slot.is_none() {
+            *slot = Some(fake_type(self.type_name));
+        }
+    }
+
+    /// Puts a hole in the slot of the given binding, unless it's a destructuring pattern
+    /// and those are not selected.
+    fn fill_pat(&self, pat: &mut Pat) {
+        if is_destructuring(pat) && !self.enabled(AnnotateType::Destructuring) {
+            return;
+        }
+        if let Some(slot) = pat_type_ann(pat) {
+            self.fill(slot);
+        }
+    }
+
+    fn fill_param(&self, pat: &mut Pat) {
+        if self.enabled(AnnotateType::Param) {
+            self.fill_pat(pat);
+        }
+    }
+
+    fn fill_return(&self, slot: &mut Option<Box<TsTypeAnn>>) {
+        if self.enabled(AnnotateType::Return) {
+            self.fill(slot);
+        }
+    }
+
+    /// Fills the parameters and the return type of the given function.
+    fn fill_function(&self, func: &mut Function) {
+        func.params
+            .iter_mut()
+            .for_each(|p| self.fill_param(&mut p.pat));
+        self.fill_return(&mut func.return_type);
+    }
+
+    fn fill_arrow(&self, arrow: &mut ArrowExpr) {
+        arrow.params.iter_mut().for_each(|p| self.fill_param(p));
+        self.fill_return(&mut arrow.return_type);
+    }
+}
+
+impl VisitMut for HoleInserter<'_> {
+    fn visit_mut_fn_expr(&mut self, n: &mut FnExpr) {
+        if self.enabled(AnnotateType::FuncExpr) {
+            self.fill_function(&mut n.function);
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_arrow_expr(&mut self, n: &mut ArrowExpr) {
+        if self.enabled(AnnotateType::FuncExpr) {
+            self.fill_arrow(n);
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_fn_decl(&mut self, n: &mut FnDecl) {
+        if self.enabled(AnnotateType::FuncDecl) {
+            self.fill_function(&mut n.function);
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_default_decl(&mut self, n: &mut DefaultDecl) {
+        // `export default function` is a declaration for typescript, not an expression
+        if let DefaultDecl::Fn(f) = n {
+            if self.enabled(AnnotateType::FuncDecl) {
+                self.fill_function(&mut f.function);
+            }
+            f.function.visit_mut_children_with(self);
+        } else {
+            n.visit_mut_children_with(self);
+        }
+    }
+
+    fn visit_mut_constructor(&mut self, n: &mut Constructor) {
+        // no return type for constructors
+        if self.enabled(AnnotateType::FuncDecl) && self.enabled(AnnotateType::Param) {
+            for param in n.params.iter_mut() {
+                match param {
+                    ParamOrTsParamProp::Param(p) => self.fill_pat(&mut p.pat),
+                    ParamOrTsParamProp::TsParamProp(p) => match &mut p.param {
+                        TsParamPropParam::Ident(i) => self.fill(&mut i.type_ann),
+                        TsParamPropParam::Assign(a) => self.fill_pat(&mut a.left),
+                    },
+                }
+            }
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_class_method(&mut self, n: &mut ClassMethod) {
+        if n.kind == MethodKind::Method && self.enabled(AnnotateType::ClassMethod) {
+            self.fill_function(&mut n.function);
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_private_method(&mut self, n: &mut PrivateMethod) {
+        if n.kind == MethodKind::Method && self.enabled(AnnotateType::ClassMethod) {
+            self.fill_function(&mut n.function);
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_prop(&mut self, n: &mut Prop) {
+        if let Prop::Method(MethodProp { function, .. }) = n {
+            if self.enabled(AnnotateType::ObjectLiteralMember) {
+                self.fill_function(function);
+            }
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_class_prop(&mut self, n: &mut ClassProp) {
+        if self.enabled(AnnotateType::ClassProp) {
+            self.fill(&mut n.type_ann);
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_private_prop(&mut self, n: &mut PrivateProp) {
+        if self.enabled(AnnotateType::ClassProp) {
+            self.fill(&mut n.type_ann);
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_decl(&mut self, n: &mut Decl) {
+        // only variable statements, the declarations of `for` loops are not annotated
+        if let Decl::Var(var) = n {
+            if self.enabled(AnnotateType::VarDecl) {
+                for decl in var.decls.iter_mut() {
+                    if is_destructuring(&decl.name) && !self.enabled(AnnotateType::Destructuring) {
+                        continue;
+                    }
+                    let slot = match pat_type_ann(&mut decl.name) {
+                        Some(slot) if slot.is_none() => slot,
+                        _ => continue,
+                    };
+                    // functions bound to a variable get their signature annotated instead
+                    match decl.init.as_deref_mut() {
+                        Some(Expr::Arrow(arrow)) if self.enabled(AnnotateType::FuncExpr) => {
+                            self.fill_arrow(arrow)
+                        }
+                        Some(Expr::Fn(f)) if self.enabled(AnnotateType::FuncExpr) => {
+                            self.fill_function(&mut f.function)
+                        }
+                        _ => *slot = Some(fake_type(self.type_name)),
+                    }
+                }
+            }
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_ts_property_signature(&mut self, n: &mut TsPropertySignature) {
+        if self.enabled(AnnotateType::TypeDecl) {
+            self.fill(&mut n.type_ann);
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_ts_fn_type(&mut self, n: &mut TsFnType) {
+        // function types fall under function expressions
+        if self.enabled(AnnotateType::FuncExpr) && self.enabled(AnnotateType::Param) {
+            let destructuring = self.enabled(AnnotateType::Destructuring);
+            for param in n.params.iter_mut() {
+                let slot = match param {
+                    TsFnParam::Ident(p) => &mut p.type_ann,
+                    TsFnParam::Rest(p) if destructuring || !is_destructuring(&p.arg) => {
+                        &mut p.type_ann
+                    }
+                    TsFnParam::Array(p) if destructuring => &mut p.type_ann,
+                    TsFnParam::Object(p) if destructuring => &mut p.type_ann,
+                    _ => continue,
+                };
+                self.fill(slot);
+            }
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_ts_type_param(&mut self, n: &mut TsTypeParam) {
+        if self.enabled(AnnotateType::TypeParam) && n.constraint.is_none() {
+            n.constraint = Some(fake_ts_type(self.type_name));
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_catch_clause(&mut self, n: &mut CatchClause) {
+        if self.enabled(AnnotateType::CatchClause) {
+            if let Some(param) = &mut n.param {
+                self.fill_pat(param);
+            }
+        }
+        n.visit_mut_children_with(self);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::{
+        get_path_from_rootdir,
+        langserver::{ts::TsServerBuilder, LangServerCommands},
+    };
+
+    /// The typescript files in the given directory of `utils/testfiles`, recursively.
+    pub(crate) fn testfiles(dir: &str) -> Vec<PathBuf> {
+        fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    walk(&path, files);
+                } else if path.extension().is_some_and(|e| e == "ts") {
+                    files.push(path);
+                }
+            }
+        }
+        let mut files = vec![];
+        walk(
+            Path::new(&get_path_from_rootdir(format!("utils/testfiles/{dir}"))),
+            &mut files,
+        );
+        files.sort();
+        files
+    }
+
+    fn print(code: &str) -> Option<String> {
+        print_source(code, "_hole_", &AnnotateType::all())
+    }
+
+    #[test]
+    fn prints_like_the_ts_printer() {
+        let code = r#"// a class
+class Fraction {
+    private numerator;
+    constructor(numerator) {
+        this.numerator = numerator;
+    }
+    public add(fraction: Fraction) {
+        const f = function(x) {
+            return x;
+        };
+        for (let i = 0; i < 3; i++){
+            f(i);
+        }
+        try {
+            f("a");
+        } finally {
+            f(1);
+        }
+        const { a, b } = this;
+        return [].map((x) => x + 1);
+    }
+}
+"#;
+        let expected = r#"// a class
+class Fraction {
+    private numerator: _hole_;
+    constructor(numerator: _hole_) {
+        this.numerator = numerator;
+    }
+    public add(fraction: Fraction): _hole_ {
+        const f = function (x: _hole_): _hole_ {
+            return x;
+        };
+        for (let i = 0; i < 3; i++) {
+            f(i);
+        }
+        try {
+            f("a");
+        } finally {
+            f(1);
+        }
+        const { a, b }: _hole_ = this;
+        return [].map((x: _hole_): _hole_ => x + 1);
+    }
+}
+"#;
+        assert_eq!(print(code).unwrap(), expected);
+    }
+
+    #[test]
+    fn leaves_other_layouts_to_the_server() {
+        let codes = [
+            "function f() {\n    return 1;\n}\n\nfunction g() {\n    return 2;\n}\n",
+            "const o = { a: 1 };\n",
+            "const a = [\n    1,\n];\n",
+            "function f() { return 1; }\n",
+            "function f() {\n}\n",
+            "/** doc */\nfunction f() {\n    return 1;\n}\n",
+            "let a = 1; // one\n",
+            "if (a) b();\n",
+            "namespace N {\n    const a = 1;\n}\n",
+            "let a = b +\n    c;\n",
+            "let f = x => x;\n",
+            "let s = \"=>{\";\n",
+            "const { a, } = o;\n",
+        ];
+        for code in codes {
+            assert_eq!(print(code), None, "{code}");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the dependencies of ts-compiler"]
+    async fn matches_the_ts_printer_on_the_testfiles() {
+        let ls = TsServerBuilder::new(&get_path_from_rootdir("ts-compiler".to_string()))
+            .native(false)
+            .build()
+            .await
+            .unwrap();
+        let types = AnnotateType::all();
+        for path in testfiles("") {
+            let code = std::fs::read_to_string(&path).unwrap();
+            // most of the files have blank lines, which swc leaves to the server
+            let compact: String = code
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(|l| format!("{l}\n"))
+                .collect();
+            for code in [code, compact] {
+                if let Some(printed) = print_source(&code, "_hole_", &types) {
+                    let expected = ls.pretty_print(&code, "_hole_", &types).await.unwrap();
+                    assert_eq!(
+                        printed.trim_end(),
+                        expected.trim_end(),
+                        "{}",
+                        path.display()
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,635 @@
+//! An in-process implementation of the `weave` command of the typescript server, using swc.
+//! Mirrors `weave.ts` in the `ts-compiler` directory: definitions are matched by their name
+//! and the names of the functions and classes they are nested in.
+//!
+//! Unlike the server, types are not resolved with the type checker, they are taken as written
+//! in the nettle. This only gives the same result when every declaration the server would weave
+//! is annotated in the nettle with a type that the server prints as it's written, so the
+//! weaver gives up in any other case and the server is used instead.
+
+use std::collections::HashMap;
+
+use swc_common::{comments::SingleThreadedComments, sync::Lrc, SourceMap, DUMMY_SP};
+use swc_ecma_ast::{
+    ArrowExpr, ClassDecl, ClassMethod, ClassProp, Constructor, DefaultDecl, Expr, FnDecl,
+    ForInStmt, ForOfStmt, Function, MethodKind, MethodProp, Param, ParamOrTsParamProp, Pat,
+    PrivateMethod, Prop, PropName, Stmt, TsEntityName, TsLit, TsParamPropParam, TsType, TsTypeAnn,
+    TsTypeOperatorOp, TsTypeParamDecl, TsUnionOrIntersectionType, VarDeclarator,
+};
+use swc_ecma_visit::{Visit, VisitMut, VisitMutWith, VisitWith};
+
+use super::layout;
+use super::printer::{emit_module, parse_module, pat_type_ann};
+
+/// The name used for constructors in the type map, same as the server.
+const CONSTRUCTOR_NAME: &str = "__constructor__";
+
+/// Transplants the type annotations of the `nettle` code into the `original` code.
+/// The `level` is the level of the nettle in the tree relative to the original, 0 being
+/// the original itself. Returns None if either of the programs could not be parsed by swc,
+/// if the server would resolve types of the nettle that aren't written, or if the original
+/// is not laid out like the typescript printer would; in that case the typescript server
+/// should be used instead.
+pub fn weave_programs(original: &str, nettle: &str, level: usize) -> Option<String> {
+    // both programs share the source map, such that the spans of transplanted types are valid
+    let cm: Lrc<SourceMap> = Default::default();
+    let comments = SingleThreadedComments::default();
+    let mut target = parse_module(&cm, original, &comments)?;
+    if !layout::matches_ts_printer(&cm, original, &target, &comments) {
+        return None;
+    }
+    let nettle = parse_module(&cm, nettle, &SingleThreadedComments::default())?;
+
+    let mut builder = TypeMapBuilder::default();
+    nettle.visit_with(&mut builder);
+    if builder.needs_checker {
+        return None;
+    }
+
+    target.visit_mut_with(&mut Weaver {
+        type_map: &builder.type_map,
+        nettle_level: level,
+        scope: String::new(),
+        level: 0,
+    });
+    emit_module(&target, cm, &comments).map(|woven| layout::fix_spacing(&woven))
+}
+
+/// The signature of a function in the nettle. Parameters are matched by their position.
+#[derive(Debug, Clone)]
+struct FnSig {
+    type_params: Option<Box<TsTypeParamDecl>>,
+    params: Vec<Option<Box<TsTypeAnn>>>,
+    return_type: Option<Box<TsTypeAnn>>,
+}
+
+impl FnSig {
+    fn of_function(func: &Function) -> Self {
+        Self {
+            type_params: func.type_params.clone(),
+            params: func.params.iter().map(|p| annotation(&p.pat)).collect(),
+            return_type: func.return_type.clone(),
+        }
+    }
+
+    fn of_arrow(arrow: &ArrowExpr) -> Self {
+        Self {
+            type_params: arrow.type_params.clone(),
+            params: arrow.params.iter().map(annotation).collect(),
+            return_type: arrow.return_type.clone(),
+        }
+    }
+
+    fn of_constructor(cons: &Constructor) -> Self {
+        let params = cons
+            .params
+            .iter()
+            .map(|p| match p {
+                ParamOrTsParamProp::Param(p) => annotation(&p.pat),
+                ParamOrTsParamProp::TsParamProp(p) => match &p.param {
+                    TsParamPropParam::Ident(i) => i.type_ann.clone(),
+                    TsParamPropParam::Assign(a) => annotation(&a.left),
+                },
+            })
+            .collect();
+        Self {
+            type_params: None,
+            params,
+            return_type: None,
+        }
+    }
+
+    /// Annotates the given parameters with the types of the signature.
+    fn weave_params<'a>(&self, params: impl Iterator<Item = &'a mut Pat>) {
+        for (pat, ann) in params.zip(self.params.iter()) {
+            if let (Some(slot), Some(ann)) = (pat_type_ann(pat), ann) {
+                *slot = Some(ann.clone());
+            }
+        }
+    }
+
+    fn weave_function(&self, func: &mut Function) {
+        self.weave_params(func.params.iter_mut().map(|p: &mut Param| &mut p.pat));
+        // like the server, the type parameters are replaced even if the nettle has none
+        func.type_params = self.type_params.clone();
+        func.return_type = self.return_type.clone();
+    }
+
+    fn weave_arrow(&self, arrow: &mut ArrowExpr) {
+        self.weave_params(arrow.params.iter_mut());
+        // like the server, the type parameters are replaced even if the nettle has none
+        arrow.type_params = self.type_params.clone();
+        arrow.return_type = self.return_type.clone();
+    }
+
+    fn weave_constructor(&self, cons: &mut Constructor) {
+        for (param, ann) in cons.params.iter_mut().zip(self.params.iter()) {
+            let ann = match ann {
+                Some(ann) => ann,
+                None => continue,
+            };
+            let slot = match param {
+                ParamOrTsParamProp::Param(p) => pat_type_ann(&mut p.pat),
+                ParamOrTsParamProp::TsParamProp(p) => match &mut p.param {
+                    TsParamPropParam::Ident(i) => Some(&mut i.type_ann),
+                    TsParamPropParam::Assign(a) => pat_type_ann(&mut a.left),
+                },
+            };
+            if let Some(slot) = slot {
+                *slot = Some(ann.clone());
+            }
+        }
+    }
+}
+
+/// A type in the type map of the nettle.
+#[derive(Debug, Clone)]
+enum WeaveType {
+    /// The type of a variable or a property.
+    Type(Box<TsType>),
+    /// The signature of a function, method or constructor.
+    Func(FnSig),
+}
+
+/// Returns the type annotation of the given pattern, if any.
+fn annotation(pat: &Pat) -> Option<Box<TsTypeAnn>> {
+    match pat {
+        Pat::Ident(i) => i.type_ann.clone(),
+        Pat::Array(p) => p.type_ann.clone(),
+        Pat::Object(p) => p.type_ann.clone(),
+        Pat::Rest(p) => p.type_ann.clone(),
+        Pat::Assign(p) => annotation(&p.left),
+        Pat::Invalid(_) | Pat::Expr(_) => None,
+    }
+}
+
+/// Whether the server prints a type as it's written. The types the server weaves are resolved
+/// with the type checker, which e.g. reorders unions, resolves queries and prints array
+/// references and type literals differently.
+struct AsWritten(bool);
+
+impl Visit for AsWritten {
+    fn visit_ts_type(&mut self, n: &TsType) {
+        match n {
+            TsType::TsThisType(_)
+            | TsType::TsTypeQuery(_)
+            | TsType::TsTypeLit(_)
+            | TsType::TsUnionOrIntersectionType(TsUnionOrIntersectionType::TsUnionType(_))
+            | TsType::TsConditionalType(_)
+            | TsType::TsInferType(_)
+            | TsType::TsIndexedAccessType(_)
+            | TsType::TsMappedType(_)
+            | TsType::TsTypePredicate(_)
+            | TsType::TsImportType(_) => self.0 = false,
+            TsType::TsTypeOperator(op) if op.op != TsTypeOperatorOp::ReadOnly => self.0 = false,
+            TsType::TsLitType(lit) if matches!(lit.lit, TsLit::Str(_) | TsLit::Tpl(_)) => {
+                self.0 = false
+            }
+            TsType::TsTypeRef(r) => {
+                if let TsEntityName::Ident(i) = &r.type_name {
+                    if &*i.sym == "Array" || &*i.sym == "ReadonlyArray" {
+                        self.0 = false;
+                    }
+                }
+            }
+            _ => {}
+        }
+        n.visit_children_with(self);
+    }
+}
+
+/// Returns the name of a bound function, e.g. the `f` of `const f = () => 1`.
+fn bound_function_name(decl: &VarDeclarator) -> Option<String> {
+    match (&decl.name, decl.init.as_deref()) {
+        (Pat::Ident(i), Some(Expr::Arrow(_) | Expr::Fn(_))) => Some(i.id.sym.to_string()),
+        _ => None,
+    }
+}
+
+/// Returns the name of a method or a property, only identifiers are supported.
+fn prop_name(name: &PropName) -> Option<String> {
+    match name {
+        PropName::Ident(i) => Some(i.sym.to_string()),
+        _ => None,
+    }
+}
+
+/// Builds the map from scoped names (e.g. `Foo$bar$x`) to the types of the nettle.
+#[derive(Default)]
+struct TypeMapBuilder {
+    type_map: HashMap<String, WeaveType>,
+    scope: String,
+    /// whether the server would weave a type that is not written in the nettle
+    needs_checker: bool,
+}
+
+impl TypeMapBuilder {
+    /// Checks that the server would weave the given annotation as it's written.
+    fn check_type(&mut self, ann: Option<&TsTypeAnn>) {
+        let mut as_written = AsWritten(ann.is_some());
+        if let Some(ann) = ann {
+            ann.type_ann.visit_with(&mut as_written);
+        }
+        self.needs_checker |= !as_written.0;
+    }
+
+    /// Checks a parameter of a function. The server builds the parameters from the signature
+    /// of the function, which drops default values and destructuring.
+    fn check_param(&mut self, pat: &Pat) {
+        match pat {
+            Pat::Ident(i) => self.check_type(i.type_ann.as_deref()),
+            Pat::Rest(r) if matches!(*r.arg, Pat::Ident(_)) => {
+                self.check_type(r.type_ann.as_deref())
+            }
+            _ => self.needs_checker = true,
+        }
+    }
+
+    fn function_sig(&mut self, func: &Function) -> WeaveType {
+        // overloads don't have a single signature
+        self.needs_checker |= func.body.is_none();
+        func.params.iter().for_each(|p| self.check_param(&p.pat));
+        self.check_type(func.return_type.as_deref());
+        WeaveType::Func(FnSig::of_function(func))
+    }
+
+    fn arrow_sig(&mut self, arrow: &ArrowExpr) -> WeaveType {
+        arrow.params.iter().for_each(|p| self.check_param(p));
+        self.check_type(arrow.return_type.as_deref());
+        WeaveType::Func(FnSig::of_arrow(arrow))
+    }
+
+    fn constructor_sig(&mut self, cons: &Constructor) -> WeaveType {
+        // the server copies the parameters of the constructor as they are written
+        let sig = FnSig::of_constructor(cons);
+        self.needs_checker |= cons.body.is_none() || sig.params.iter().any(Option::is_none);
+        WeaveType::Func(sig)
+    }
+
+    fn method_sig(&mut self, name: &PropName, func: &Function) {
+        match prop_name(name) {
+            Some(name) => {
+                let sig = self.function_sig(func);
+                self.insert(&name, sig);
+            }
+            None => self.needs_checker = true,
+        }
+    }
+
+    fn insert(&mut self, name: &str, ty: WeaveType) {
+        self.type_map.insert(format!("{}{}", self.scope, name), ty);
+    }
+
+    /// Visits the children of the given node in the scope of `name`.
+    fn visit_in_scope<N: VisitWith<Self>>(&mut self, name: &str, node: &N) {
+        let outer = self.scope.clone();
+        self.scope = format!("{outer}{name}$");
+        node.visit_children_with(self);
+        self.scope = outer;
+    }
+}
+
+impl Visit for TypeMapBuilder {
+    fn visit_var_declarator(&mut self, n: &VarDeclarator) {
+        if let Some(name) = bound_function_name(n) {
+            match n.init.as_deref() {
+                Some(Expr::Arrow(arrow)) => {
+                    let sig = self.arrow_sig(arrow);
+                    self.insert(&name, sig);
+                    self.visit_in_scope(&name, arrow);
+                }
+                Some(Expr::Fn(f)) => {
+                    let sig = self.function_sig(&f.function);
+                    self.insert(&name, sig);
+                    self.visit_in_scope(&name, &*f.function);
+                }
+                _ => unreachable!(),
+            }
+            return;
+        }
+        if let Pat::Ident(i) = &n.name {
+            self.check_type(i.type_ann.as_deref());
+            if let Some(ann) = &i.type_ann {
+                self.insert(&i.id.sym, WeaveType::Type(ann.type_ann.clone()));
+            }
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_for_of_stmt(&mut self, n: &ForOfStmt) {
+        // the variables of `for (let x of y)` can't be annotated
+        n.right.visit_with(self);
+        n.body.visit_with(self);
+    }
+
+    fn visit_class_prop(&mut self, n: &ClassProp) {
+        if let Some(name) = prop_name(&n.key) {
+            self.check_type(n.type_ann.as_deref());
+            if let Some(ann) = &n.type_ann {
+                self.insert(&name, WeaveType::Type(ann.type_ann.clone()));
+            }
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_constructor(&mut self, n: &Constructor) {
+        let sig = self.constructor_sig(n);
+        self.insert(CONSTRUCTOR_NAME, sig);
+        self.visit_in_scope(CONSTRUCTOR_NAME, n);
+    }
+
+    fn visit_fn_decl(&mut self, n: &FnDecl) {
+        let name = n.ident.sym.to_string();
+        let sig = self.function_sig(&n.function);
+        self.insert(&name, sig);
+        self.visit_in_scope(&name, &*n.function);
+    }
+
+    fn visit_default_decl(&mut self, n: &DefaultDecl) {
+        match n {
+            DefaultDecl::Fn(f) if f.ident.is_some() => {
+                let name = f.ident.as_ref().unwrap().sym.to_string();
+                let sig = self.function_sig(&f.function);
+                self.insert(&name, sig);
+                self.visit_in_scope(&name, &*f.function);
+            }
+            DefaultDecl::Class(c) if c.ident.is_some() => {
+                let name = c.ident.as_ref().unwrap().sym.to_string();
+                self.visit_in_scope(&name, &*c.class);
+            }
+            _ => n.visit_children_with(self),
+        }
+    }
+
+    fn visit_class_method(&mut self, n: &ClassMethod) {
+        if n.kind == MethodKind::Method {
+            self.method_sig(&n.key, &n.function);
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_private_method(&mut self, n: &PrivateMethod) {
+        if n.kind == MethodKind::Method {
+            let name = format!("#{}", n.key.id.sym);
+            let sig = self.function_sig(&n.function);
+            self.insert(&name, sig);
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_prop(&mut self, n: &Prop) {
+        // methods of object literals are method declarations for typescript
+        if let Prop::Method(MethodProp { key, function }) = n {
+            self.method_sig(key, function);
+        }
+        n.visit_children_with(self);
+    }
+
+    fn visit_class_decl(&mut self, n: &ClassDecl) {
+        let name = n.ident.sym.to_string();
+        self.visit_in_scope(&name, &*n.class);
+    }
+}
+
+/// Weaves the types of the type map into the target.
+struct Weaver<'a> {
+    type_map: &'a HashMap<String, WeaveType>,
+    nettle_level: usize,
+    scope: String,
+    level: usize,
+}
+
+impl Weaver<'_> {
+    fn get(&self, name: &str) -> Option<&WeaveType> {
+        self.type_map.get(&format!("{}{}", self.scope, name))
+    }
+
+    /// Visits the children of a function or class named `name`, one level deeper. The scope
+    /// only changes once we are at the level of the nettle.
+    fn visit_nested<N: VisitMutWith<Self>>(&mut self, name: &str, node: &mut N) {
+        let outer = self.scope.clone();
+        if self.level >= self.nettle_level {
+            self.scope = format!("{outer}{name}$");
+        }
+        self.level += 1;
+        node.visit_mut_children_with(self);
+        self.level -= 1;
+        self.scope = outer;
+    }
+
+    /// Visits the declarations of a `for..in` or `for..of` loop, which can't be annotated.
+    fn visit_loop(&mut self, right: &mut Expr, body: &mut Stmt) {
+        right.visit_mut_with(self);
+        body.visit_mut_with(self);
+    }
+}
+
+impl VisitMut for Weaver<'_> {
+    fn visit_mut_var_declarator(&mut self, n: &mut VarDeclarator) {
+        if let Some(name) = bound_function_name(n) {
+            let ty = self.get(&name).cloned();
+            match (ty, n.init.as_deref_mut()) {
+                (Some(WeaveType::Func(sig)), Some(Expr::Arrow(arrow))) => sig.weave_arrow(arrow),
+                (Some(WeaveType::Func(sig)), Some(Expr::Fn(f))) => {
+                    sig.weave_function(&mut f.function)
+                }
+                (Some(WeaveType::Type(ty)), _) => {
+                    if let Pat::Ident(i) = &mut n.name {
+                        i.type_ann = Some(type_ann(ty));
+                    }
+                }
+                _ => {}
+            }
+            match n.init.as_deref_mut() {
+                Some(Expr::Arrow(arrow)) => self.visit_nested(&name, arrow),
+                Some(Expr::Fn(f)) => self.visit_nested(&name, &mut *f.function),
+                _ => unreachable!(),
+            }
+            return;
+        }
+        if let Pat::Ident(i) = &mut n.name {
+            if let Some(WeaveType::Type(ty)) = self.get(&i.id.sym) {
+                i.type_ann = Some(type_ann(ty.clone()));
+            }
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_for_of_stmt(&mut self, n: &mut ForOfStmt) {
+        self.visit_loop(&mut n.right, &mut n.body);
+    }
+
+    fn visit_mut_for_in_stmt(&mut self, n: &mut ForInStmt) {
+        self.visit_loop(&mut n.right, &mut n.body);
+    }
+
+    fn visit_mut_class_prop(&mut self, n: &mut ClassProp) {
+        if let Some(WeaveType::Type(ty)) = prop_name(&n.key).and_then(|name| self.get(&name)) {
+            n.type_ann = Some(type_ann(ty.clone()));
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_constructor(&mut self, n: &mut Constructor) {
+        if let Some(WeaveType::Func(sig)) = self.get(CONSTRUCTOR_NAME) {
+            sig.clone().weave_constructor(n);
+        }
+        // NOTE: the server does not change the scope here
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_fn_decl(&mut self, n: &mut FnDecl) {
+        let name = n.ident.sym.to_string();
+        if let Some(WeaveType::Func(sig)) = self.get(&name) {
+            sig.clone().weave_function(&mut n.function);
+        }
+        self.visit_nested(&name, &mut *n.function);
+    }
+
+    fn visit_mut_default_decl(&mut self, n: &mut DefaultDecl) {
+        match n {
+            DefaultDecl::Fn(f) if f.ident.is_some() => {
+                let name = f.ident.as_ref().unwrap().sym.to_string();
+                if let Some(WeaveType::Func(sig)) = self.get(&name) {
+                    sig.clone().weave_function(&mut f.function);
+                }
+                self.visit_nested(&name, &mut *f.function);
+            }
+            DefaultDecl::Class(c) if c.ident.is_some() => {
+                let name = c.ident.as_ref().unwrap().sym.to_string();
+                self.visit_nested(&name, &mut *c.class);
+            }
+            _ => n.visit_mut_children_with(self),
+        }
+    }
+
+    fn visit_mut_class_method(&mut self, n: &mut ClassMethod) {
+        if n.kind == MethodKind::Method {
+            if let Some(WeaveType::Func(sig)) = prop_name(&n.key).and_then(|k| self.get(&k)) {
+                sig.clone().weave_function(&mut n.function);
+            }
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_private_method(&mut self, n: &mut PrivateMethod) {
+        if n.kind == MethodKind::Method {
+            if let Some(WeaveType::Func(sig)) = self.get(&format!("#{}", n.key.id.sym)) {
+                sig.clone().weave_function(&mut n.function);
+            }
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_prop(&mut self, n: &mut Prop) {
+        if let Prop::Method(MethodProp { key, function }) = n {
+            if let Some(WeaveType::Func(sig)) = prop_name(key).and_then(|k| self.get(&k)) {
+                sig.clone().weave_function(function);
+            }
+        }
+        n.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_class_decl(&mut self, n: &mut ClassDecl) {
+        let name = n.ident.sym.to_string();
+        self.visit_nested(&name, &mut *n.class);
+    }
+}
+
+/// Wraps the given type into an annotation.
+fn type_ann(ty: Box<TsType>) -> Box<TsTypeAnn> {
+    Box::new(TsTypeAnn {
+        span: DUMMY_SP,
+        type_ann: ty,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        get_path_from_rootdir,
+        langserver::{
+            ts::{printer::tests::testfiles, TsServerBuilder},
+            AnnotateType, LangServerCommands,
+        },
+    };
+
+    const ORIGINAL: &str = r#"class Point {
+    x: _hole_;
+    constructor(x: _hole_) {
+        this.x = x;
+    }
+    scale(by: _hole_): _hole_ {
+        const f = (k: _hole_): _hole_ => k * by;
+        return f(this.x);
+    }
+}
+"#;
+
+    #[test]
+    fn weaves_annotated_nettles() {
+        let nettle = r#"class Point {
+    x: number;
+    constructor(x: number) {
+        this.x = x;
+    }
+    scale(by: number): number {
+        const f = (k: number): number => k * by;
+        return f(this.x);
+    }
+}
+"#;
+        assert_eq!(weave_programs(ORIGINAL, nettle, 0).unwrap(), nettle);
+    }
+
+    #[test]
+    fn leaves_types_the_server_would_resolve_to_it() {
+        let nettles = [
+            // the server infers the types of unannotated declarations
+            "class Point {\n    x;\n}\n",
+            "class Point {\n    scale(by: number) {\n        return by;\n    }\n}\n",
+            "function f(x: number) {\n    const y = x;\n    return y;\n}\n",
+            "class Point {\n    constructor(x) {\n    }\n}\n",
+            // and prints the types it resolves differently than they are written
+            "let a: Array<number> = [];\n",
+            "let a: number | string = 1;\n",
+            "let a: { b: number } = o;\n",
+            "let a: 'b' = 'b';\n",
+            // and drops default values of parameters
+            "function f(x: number = 1): number {\n    return x;\n}\n",
+        ];
+        for nettle in nettles {
+            assert_eq!(weave_programs(ORIGINAL, nettle, 0), None, "{nettle}");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the dependencies of ts-compiler"]
+    async fn matches_weave_ts_on_the_tree_testfiles() {
+        let ls = TsServerBuilder::new(&get_path_from_rootdir("ts-compiler".to_string()))
+            .native(false)
+            .build()
+            .await
+            .unwrap();
+        let types = AnnotateType::all();
+        let mut woven = 0;
+        for path in testfiles("tree") {
+            let code = std::fs::read_to_string(&path).unwrap();
+            // the blank lines of the files are left to the server
+            let code: String = code
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(|l| format!("{l}\n"))
+                .collect();
+            let original = ls.pretty_print(&code, "_hole_", &types).await.unwrap();
+            let nettle = ls.pretty_print(&code, "number", &types).await.unwrap();
+            if let Some(ours) = weave_programs(&original, &nettle, 0) {
+                let expected = ls.weave(&original, &nettle, 0).await.unwrap();
+                assert_eq!(ours.trim_end(), expected.trim_end(), "{}", path.display());
+                woven += 1;
+            }
+        }
+        assert!(woven > 0, "none of the testfiles were woven with swc");
+    }
+}
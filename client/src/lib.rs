@@ -1,8 +1,10 @@
+pub mod audit;
 pub mod cache;
 pub mod completion;
 pub mod langserver;
 pub mod main_strategies;
 pub mod socket;
+pub mod testing;
 pub mod tree;
 pub mod typedef_gen;
 pub mod args;
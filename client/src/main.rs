@@ -1,45 +1,69 @@
-use std::{str::FromStr, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
 use clap::Parser;
 use opentau::{
+    audit::{run_audit, AuditReport},
     cache::Cache,
     completion::{sort_completions, Completion, TypecheckedCompletion},
-    langserver::AnnotateType,
+    langserver::TypeRelation,
     main_strategies::{MainCtx, MainStrategy},
+    socket::exit,
 };
 use tokio::sync::Mutex;
 
+/// How long in-flight requests get to finish when interrupted with Ctrl-C.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Prints the disagreements of the audit, and writes the full report to `audit.json` in the
+/// output dir.
+async fn write_audit(output: &str, report: &AuditReport) {
+    for audit in report.disagreements() {
+        println!("{audit}");
+    }
+    println!(
+        "Audited {} annotations: {} narrower, {} wider, {} incompatible, {} unknown",
+        report.annotations.len(),
+        report.count(TypeRelation::Narrower),
+        report.count(TypeRelation::Wider),
+        report.count(TypeRelation::Incompatible),
+        report.count(TypeRelation::Unknown),
+    );
+
+    let output_dir = std::path::Path::new(output);
+    if !output_dir.exists() {
+        tokio::fs::create_dir_all(output_dir).await.unwrap();
+    }
+    let json = serde_json::to_string_pretty(report).unwrap();
+    tokio::fs::write(output_dir.join("audit.json"), json)
+        .await
+        .unwrap();
+}
+
 #[tokio::main]
 async fn main() {
     let args = opentau::args::Args::parse();
 
-    let lang_client = args.lang_client_factory().await;
+    // everything that can fail on bad arguments is done before spawning any server
     let strategy = args.stategy_factory();
-
-    let file_contents = tokio::fs::read_to_string(&args.file).await.unwrap();
+    let file_contents = tokio::fs::read_to_string(&args.file)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to read {}: {e}", args.file);
+            exit(1);
+        });
+    let types_to_annot = args.annotate_types();
+    let project_file = args.project_file();
 
     let cache: Option<Arc<Mutex<Cache>>> = args.cache.as_ref().map(|u| {
         Arc::new(Mutex::new(Cache::new(u, args.stop_at).unwrap_or_else(
             |e| {
                 eprintln!("Failed to connect to redis: {e}");
-                std::process::exit(1);
+                exit(1);
             },
         )))
     });
 
-    let types_to_annot = match args.exclude {
-        None => AnnotateType::all(),
-        Some(ref exclude) => {
-            let commasplit = exclude.split(',');
-            let mut exclude_types = Vec::new();
-            for s in commasplit {
-                let ex_type: AnnotateType = AnnotateType::from_str(s)
-                    .unwrap_or_else(|_| panic!("Unknown type to exclude: {s}"));
-                exclude_types.push(ex_type);
-            }
-            AnnotateType::all_except(&exclude_types)
-        }
-    };
+    let lang_client = args.lang_client_factory().await;
 
     let ctx = MainCtx {
         file_contents,
@@ -51,19 +75,53 @@ async fn main() {
         enable_type_check: !args.disable_type_check,
         enable_defgen: args.enable_defgen,
         depth_limit: args.depth_limit,
+        enable_pruning: args.enable_pruning,
+        merge: args.merge.strategy(),
+        seed: args.seed,
         enable_usages: !args.disable_usages,
         enable_stubbing: !args.disable_stubbing,
         enable_parser: true,
         enable_checkproblems: true,
+        enable_diagnostics: args.diagnostics,
         types: types_to_annot,
+        project_file,
+        checkpoint: args.checkpoint.clone(),
+        resume: args.resume,
     };
 
+    if args.audit {
+        let report: AuditReport = tokio::select! {
+            res = run_audit(strategy.as_ref(), ctx) => match res {
+                Ok(report) => report,
+                Err(e) => {
+                    eprintln!("Fatal error while auditing: {e}");
+                    exit(1);
+                }
+            },
+            _ = tokio::signal::ctrl_c() => {
+                eprintln!("Interrupted, shutting down servers...");
+                opentau::socket::shutdown(SHUTDOWN_GRACE).await;
+                std::process::exit(130);
+            }
+        };
+        write_audit(&args.output, &report).await;
+        opentau::socket::cleanup_spawned();
+        return;
+    }
+
     // the typechecked and completed code(s). here if we get errors we exit with 1
-    let mut good_ones: Vec<TypecheckedCompletion> = match strategy.run(ctx).await {
-        Ok(good_ones) => good_ones,
-        Err(e) => {
-            eprintln!("Fatal error while running strategy: {e}");
-            std::process::exit(1);
+    let mut good_ones: Vec<TypecheckedCompletion> = tokio::select! {
+        res = strategy.run(ctx) => match res {
+            Ok(good_ones) => good_ones,
+            Err(e) => {
+                eprintln!("Fatal error while running strategy: {e}");
+                exit(1);
+            }
+        },
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!("Interrupted, shutting down servers...");
+            opentau::socket::shutdown(SHUTDOWN_GRACE).await;
+            std::process::exit(130);
         }
     };
 
@@ -72,7 +130,7 @@ async fn main() {
 
     if good_ones.is_empty() {
         eprintln!("No completions type checked");
-        std::process::exit(1);
+        exit(1);
     }
 
     println!("Number of good completions: {}", good_ones.len());
@@ -84,12 +142,26 @@ async fn main() {
     }
 
     // write to the output dir
+    let extension = args.output_extension();
     for (i, comp) in good_ones.into_iter().enumerate() {
         let fallback = if comp.fallbacked { "_fallback" } else { "" };
+        // the breakdown of the score, such that it's clear why a completion ranks higher
+        let quality = comp
+            .quality
+            .map(|q| format!("_{}", q.label()))
+            .unwrap_or_default();
         let output_path = format!(
-            "{}/{}_errors_{}_score_{}{}.{}",
-            args.output, i, comp.num_type_errors, comp.score, fallback, args.lang
+            "{}/{}_errors_{}_score_{}{}{}.{}",
+            args.output, i, comp.num_type_errors, comp.score, quality, fallback, extension
         );
         tokio::fs::write(&output_path, comp.code).await.unwrap();
+        if let Some(diagnostics) = comp.diagnostics {
+            println!("Diagnostics of {output_path}:");
+            for diagnostic in diagnostics {
+                println!("  {diagnostic}");
+            }
+        }
     }
+
+    opentau::socket::cleanup_spawned();
 }
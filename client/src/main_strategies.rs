@@ -1,9 +1,16 @@
+use std::sync::Arc;
+
 use crate::{
     completion::ArcCompletionEngine,
-    completion::{Completion, CompletionError, CompletionQueryBuilder, TypecheckedCompletion},
+    completion::{
+        Completion, CompletionError, CompletionQueryBuilder, TypecheckedCompletion, HOLE_IDENTIFIER,
+    },
     debug,
-    langserver::{AnnotateType, CheckProblem, LangServerError},
-    tree::{stats::ArcTreeAlgoStats, CompletionLevels, HyperParams},
+    langserver::{
+        mark_pre_existing, AnnotateType, Capabilities, CheckProblem, Diagnostic, LangServerError,
+        PROTOCOL_VERSION,
+    },
+    tree::{merge::Merger, stats::ArcTreeAlgoStats, Checkpoint, CompletionLevels, HyperParams},
 };
 use tokio::{sync::Semaphore, task::JoinHandle};
 
@@ -22,34 +29,145 @@ pub struct MainCtx {
     pub enable_stubbing: bool,
     pub enable_parser: bool,
     pub enable_checkproblems: bool,
+    /// keep the diagnostics of the type checker in the completions
+    pub enable_diagnostics: bool,
     pub depth_limit: Option<usize>,
+    /// for the tree strategy, drop the completions of a node that add type errors to its parent
+    pub enable_pruning: bool,
+    /// for the tree strategy, how to merge the completions of the children into their parent
+    pub merge: Merger,
+    /// the seed of the random choices of the tree strategy, random if None
+    pub seed: Option<u64>,
     pub types: Vec<AnnotateType>,
+    /// in project mode, the path of the target file relative to the root of the project.
+    /// candidates are then type checked as a replacement of this file.
+    pub project_file: Option<String>,
+    /// for the tree strategy, the file where a checkpoint is written after every completed level
+    pub checkpoint: Option<String>,
+    /// for the tree strategy, resume from the checkpoint instead of starting over
+    pub resume: bool,
 }
 
 impl MainCtx {
+    /// Queries the capabilities of the language server, and errors if the server is
+    /// incompatible with this client or any of the `required` commands is missing.
+    /// Servers that predate the `capabilities` command are assumed to implement every command.
+    pub async fn check_capabilities(
+        &self,
+        required: &[&str],
+    ) -> Result<Capabilities, CompletionError> {
+        let ls = self.engine.get_ls();
+        let caps = match ls.capabilities().await {
+            Ok(caps) => caps,
+            Err(LangServerError::LC(msg)) if msg.contains("unknown command") => {
+                Capabilities::legacy(ls.any_type())
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if caps.protocol_version > PROTOCOL_VERSION {
+            return Err(LangServerError::Incompatible(format!(
+                "server speaks protocol version {}, client supports up to {PROTOCOL_VERSION}",
+                caps.protocol_version
+            ))
+            .into());
+        }
+        if caps.hole_token != HOLE_IDENTIFIER {
+            return Err(LangServerError::Incompatible(format!(
+                "server uses hole token {}, client expects {HOLE_IDENTIFIER}",
+                caps.hole_token
+            ))
+            .into());
+        }
+
+        let missing = caps.missing(required);
+        if !missing.is_empty() {
+            return Err(LangServerError::Incompatible(format!(
+                "missing required commands: {}",
+                missing.join(", ")
+            ))
+            .into());
+        }
+
+        Ok(caps)
+    }
+
+    /// Type checks the uncompleted file, to tell which diagnostics of the completions were
+    /// already there. Returns no diagnostics if the file can't be type checked.
+    async fn baseline_diagnostics(&self) -> Vec<Diagnostic> {
+        let ls = self.engine.get_ls();
+        let res = match &self.project_file {
+            Some(file) => ls.type_check_file_detailed(&self.file_contents, file).await,
+            None => ls.type_check_detailed(&self.file_contents).await,
+        };
+        res.unwrap_or_else(|e| {
+            println!("Could not type check the uncompleted file: {e}");
+            vec![]
+        })
+    }
+
     /// Returns the subset of completions that type check from the given set of completions
     pub async fn type_check_candidates(
         &self,
         candidates: Vec<Completion>,
     ) -> Vec<TypecheckedCompletion> {
         println!(" --- Type Checking {} Candidates ---", candidates.len());
+        let baseline = Arc::new(if self.enable_diagnostics {
+            self.baseline_diagnostics().await
+        } else {
+            vec![]
+        });
         let mut comps: Vec<TypecheckedCompletion> = vec![];
-        let mut handles: Vec<JoinHandle<TypecheckedCompletion>> = vec![];
+        let mut handles: Vec<JoinHandle<Option<TypecheckedCompletion>>> = vec![];
         for (i, candidate) in candidates.into_iter().enumerate() {
             debug!("candidate {}:\n{}", i, candidate.code);
             let lang_client = self.engine.get_ls();
+            let detailed = self.enable_diagnostics;
+            let project_file = self.project_file.clone();
+            let baseline = baseline.clone();
             // don't overload it, max 5 at a time
             let sem = Semaphore::new(5);
             handles.push(tokio::task::spawn(async move {
                 let _permit = sem.acquire().await.unwrap();
-                let type_checks = lang_client.type_check(&candidate.code).await.unwrap();
-                drop(_permit);
-                TypecheckedCompletion::new(candidate, type_checks)
+                let checked = match (detailed, &project_file) {
+                    (true, Some(file)) => lang_client
+                        .type_check_file_detailed(&candidate.code, file)
+                        .await
+                        .map(|diags| TypecheckedCompletion::with_diagnostics(candidate, diags)),
+                    (true, None) => lang_client
+                        .type_check_detailed(&candidate.code)
+                        .await
+                        .map(|diags| TypecheckedCompletion::with_diagnostics(candidate, diags)),
+                    (false, Some(file)) => lang_client
+                        .type_check_file(&candidate.code, file)
+                        .await
+                        .map(|errors| TypecheckedCompletion::new(candidate, errors)),
+                    (false, None) => lang_client
+                        .type_check(&candidate.code)
+                        .await
+                        .map(|errors| TypecheckedCompletion::new(candidate, errors)),
+                };
+                match checked {
+                    Ok(mut checked) => {
+                        if let Some(diagnostics) = &mut checked.diagnostics {
+                            mark_pre_existing(diagnostics, &baseline);
+                        }
+                        Some(checked)
+                    }
+                    // we can't rank a candidate that we couldn't type check, drop it
+                    Err(LangServerError::Timeout(d)) => {
+                        println!("Type checking candidate {i} timed out after {d:?}");
+                        None
+                    }
+                    Err(e) => panic!("type checking failed: {e:?}"),
+                }
             }));
         }
 
         for handle in handles {
-            comps.push(handle.await.unwrap());
+            if let Some(comp) = handle.await.unwrap() {
+                comps.push(comp);
+            }
             if comps.len() >= self.stop_at {
                 break;
             }
@@ -77,15 +195,24 @@ impl MainStrategy for TreeStrategy {
     /// TODO: somehow add caching to this strategy, maybe go up the tree?
     ///
     /// TODO: implement enable_type_parser and enable_checkproblems options
-    async fn run(&self, context: MainCtx) -> Result<Vec<TypecheckedCompletion>, CompletionError> {
-        let mut tree = context
-            .engine
-            .get_ls()
-            .to_tree(&context.file_contents)
-            .await?;
+    async fn run(
+        &self,
+        mut context: MainCtx,
+    ) -> Result<Vec<TypecheckedCompletion>, CompletionError> {
+        let mut required = vec!["tree", "print", "check", "weave"];
+        if context.enable_type_check || context.enable_pruning {
+            required.push("typecheck");
+        }
+        let caps = context.check_capabilities(&required).await?;
 
-        if let Some(limit) = context.depth_limit {
-            tree.depth_limit(limit);
+        // usages and stubbing only improve the prompts, so we can run without them
+        if context.enable_usages && !caps.supports("usages") {
+            eprintln!("Language server does not support usages, disabling them");
+            context.enable_usages = false;
+        }
+        if context.enable_stubbing && !caps.supports("stub") {
+            eprintln!("Language server does not support stubbing, disabling it");
+            context.enable_stubbing = false;
         }
 
         let hyper_params = HyperParams {
@@ -96,11 +223,56 @@ impl MainStrategy for TreeStrategy {
             stub: context.enable_stubbing,
             stop_at: context.stop_at,
             types: context.types.clone(),
+            prune: context.enable_pruning,
+            merge: context.merge.clone(),
+            seed: context.seed,
         };
 
-        let levels = CompletionLevels::new(hyper_params, self.stats.clone());
+        // a checkpoint that can't be resumed is an error, as starting over could silently
+        // redo hours of completions
+        let resumed = match &context.checkpoint {
+            Some(path) if context.resume => match Checkpoint::load(path) {
+                Ok(cp) if cp.input == context.file_contents && cp.params == hyper_params => {
+                    Some(cp)
+                }
+                Ok(_) => {
+                    return Err(CompletionError::Checkpoint(format!(
+                        "{path} is for a different input or parameters"
+                    )))
+                }
+                Err(e) => {
+                    return Err(CompletionError::Checkpoint(format!(
+                        "failed to load {path}: {e}"
+                    )))
+                }
+            },
+            _ => None,
+        };
 
-        let prepared = levels.prepare(tree, context.engine.get_ls()).await?;
+        let prepared = match resumed {
+            Some(cp) => {
+                eprintln!("Resuming from checkpoint, {} levels left", cp.remaining);
+                CompletionLevels::resume(cp, self.stats.clone())
+            }
+            None => {
+                let mut tree = context
+                    .engine
+                    .get_ls()
+                    .to_tree(&context.file_contents)
+                    .await?;
+
+                if let Some(limit) = context.depth_limit {
+                    tree.depth_limit(limit);
+                }
+
+                let levels = CompletionLevels::new(hyper_params, self.stats.clone());
+                levels.prepare(tree, context.engine.get_ls()).await?
+            }
+        };
+        let prepared = match &context.checkpoint {
+            Some(path) => prepared.with_checkpoint(path, context.file_contents.clone()),
+            None => prepared,
+        };
         let completed = prepared.tree_complete(context.engine.clone()).await;
         let disassembled = completed.disassemble();
 
@@ -109,14 +281,15 @@ impl MainStrategy for TreeStrategy {
         for code in disassembled {
             let ls = context.engine.get_ls();
             handles.push(tokio::task::spawn(async move {
-                let (_, score) = ls
-                    .check_complete(&code, &code)
+                let (_, score, quality) = ls
+                    .check_complete_detailed(&code, &code)
                     .await
-                    .unwrap_or((vec![], 1000));
+                    .unwrap_or((vec![], 1000, None));
                 Completion {
                     code,
                     score,
                     fallbacked: false,
+                    quality,
                 }
             }));
         }
@@ -145,6 +318,15 @@ impl MainStrategy for SimpleStrategy {
     /// Runs the simple completion strategy, which just runs the completion on the given file
     /// without any transformation, other than adding "_hole_" to each unknwon type
     async fn run(&self, context: MainCtx) -> Result<Vec<TypecheckedCompletion>, CompletionError> {
+        let mut required = vec!["print", "check"];
+        if context.enable_defgen {
+            required.push("typedefGen");
+        }
+        if context.enable_type_check {
+            required.push("typecheck");
+        }
+        context.check_capabilities(&required).await?;
+
         let initial_input = if context.enable_defgen {
             context
                 .engine
@@ -225,3 +407,218 @@ impl MainStrategy for SimpleStrategy {
         Ok(comps)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, FakeLangServer, ScriptedModel, ScriptedResponse},
+        tree::merge::MergeMode,
+    };
+
+    const CODE: &str = "fn dist(a, b) {
+    let d = 1;
+    fn square(x) {
+        return x;
+    }
+    return square(d);
+}
+let p = dist(1, 2);";
+
+    const COMPLETED: &str = "fn dist(a: number, b: number): number {
+    let d: number = 1;
+    fn square(x: number): number {
+        return x;
+    }
+    return square(d);
+}
+let p: number = dist(1, 2);";
+
+    /// The tree only completes the functions, the top level code is left as it is.
+    const TREE_COMPLETED: &str = "fn dist(a: number, b: number): number {
+    let d: number = 1;
+    fn square(x: number): number {
+        return x;
+    }
+    return square(d);
+}
+let p = dist(1, 2);";
+
+    /// Runs the tree strategy on `CODE`, after configuring the context with `configure`.
+    async fn run_tree(
+        ls: Arc<FakeLangServer>,
+        model: ScriptedModel,
+        configure: impl FnOnce(&mut MainCtx),
+    ) -> Vec<TypecheckedCompletion> {
+        let mut ctx = testing::main_ctx(testing::engine(ls, model), CODE.to_string());
+        configure(&mut ctx);
+        TreeStrategy { stats: None }.run(ctx).await.unwrap()
+    }
+
+    fn codes(comps: &[TypecheckedCompletion]) -> Vec<String> {
+        comps.iter().map(|c| c.code.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn tree_strategy_completes_the_file() {
+        for mode in [
+            MergeMode::Poisson,
+            MergeMode::Beam,
+            MergeMode::AllCombinations,
+        ] {
+            let ls = Arc::new(FakeLangServer::new());
+            let model = ScriptedModel::new(vec![ScriptedResponse::types(["number"])]);
+            let comps = run_tree(ls.clone(), model.clone(), |ctx| {
+                ctx.merge = mode.strategy();
+            })
+            .await;
+            assert_eq!(codes(&comps), [TREE_COMPLETED], "{mode:?}");
+            assert_eq!(comps[0].num_type_errors, 0);
+            // one prompt for each function
+            assert_eq!(model.prompts().len(), 2);
+            assert!(ls.calls("weave") > 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn simple_strategy_completes_the_file() {
+        let ls = Arc::new(FakeLangServer::new());
+        let model = ScriptedModel::new(vec![
+            ScriptedResponse::types(["string"]),
+            ScriptedResponse::types(["number"]),
+        ]);
+        let mut ctx = testing::main_ctx(testing::engine(ls.clone(), model), CODE.to_string());
+        ctx.retries = 2;
+        ctx.stop_at = 2;
+        let comps = SimpleStrategy.run(ctx).await.unwrap();
+
+        // the one with the mismatching literals is ranked after the one without type errors
+        assert_eq!(comps.len(), 2);
+        let correct = comps.iter().find(|c| c.code == COMPLETED).unwrap();
+        assert_eq!(correct.num_type_errors, 0);
+        assert!(comps.iter().any(|c| c.num_type_errors > 0));
+        assert_eq!(ls.calls("tree"), 0);
+    }
+
+    #[tokio::test]
+    async fn simple_strategy_orders_candidates_reproducibly() {
+        // the order that the model answers in stands for the order the threads finish in
+        let run = |script: [&'static str; 2]| async move {
+            let model = ScriptedModel::new(
+                script
+                    .iter()
+                    .map(|ty| ScriptedResponse::types([*ty]))
+                    .collect(),
+            );
+            let ls = Arc::new(FakeLangServer::new());
+            let mut ctx = testing::main_ctx(testing::engine(ls, model), CODE.to_string());
+            ctx.seed = Some(42);
+            ctx.retries = 2;
+            ctx.stop_at = 2;
+            ctx.enable_type_check = false;
+            codes(&SimpleStrategy.run(ctx).await.unwrap())
+        };
+        let first = run(["string", "number"]).await;
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0], COMPLETED);
+        assert_eq!(run(["number", "string"]).await, first);
+    }
+
+    #[tokio::test]
+    async fn tree_strategy_resumes_from_a_checkpoint() {
+        let path = std::env::temp_dir().join(format!("opentau-resume-{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let model = ScriptedModel::new(vec![ScriptedResponse::types(["number"])]);
+        let first = run_tree(Arc::new(FakeLangServer::new()), model, |ctx| {
+            ctx.checkpoint = Some(path.clone());
+        })
+        .await;
+
+        // pretend that the run was interrupted before the root was merged
+        let mut checkpoint = Checkpoint::load(&path).unwrap();
+        assert_eq!(checkpoint.remaining, 0);
+        checkpoint.remaining = 1;
+        checkpoint.levels[0].nodes[0].completed.clear();
+        checkpoint.save(&path).unwrap();
+
+        let ls = Arc::new(FakeLangServer::new());
+        let model = ScriptedModel::new(vec![]);
+        let resumed = run_tree(ls.clone(), model.clone(), |ctx| {
+            ctx.checkpoint = Some(path.clone());
+            ctx.resume = true;
+        })
+        .await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(codes(&resumed), codes(&first));
+        // only the root was merged again, the tree and the completions came from the checkpoint
+        assert_eq!(ls.calls("tree"), 0);
+        assert!(model.prompts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tree_strategy_does_not_start_over_when_it_cannot_resume() {
+        let path =
+            std::env::temp_dir().join(format!("opentau-no-resume-{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let model = ScriptedModel::new(vec![ScriptedResponse::types(["number"])]);
+        run_tree(Arc::new(FakeLangServer::new()), model, |ctx| {
+            ctx.checkpoint = Some(path.clone());
+        })
+        .await;
+
+        let resume = |configure: fn(&mut MainCtx)| {
+            let path = path.clone();
+            async move {
+                let ls = Arc::new(FakeLangServer::new());
+                let mut ctx = testing::main_ctx(
+                    testing::engine(ls.clone(), ScriptedModel::new(vec![])),
+                    CODE.to_string(),
+                );
+                ctx.checkpoint = Some(path);
+                ctx.resume = true;
+                configure(&mut ctx);
+                let res = TreeStrategy { stats: None }.run(ctx).await;
+                assert_eq!(ls.calls("tree"), 0);
+                match res {
+                    Err(CompletionError::Checkpoint(e)) => e,
+                    res => panic!("expected a checkpoint error, got {res:?}"),
+                }
+            }
+        };
+        let err = resume(|ctx| ctx.retries += 1).await;
+        assert!(err.contains("different input or parameters"), "{err}");
+
+        // a checkpoint written with a custom merge strategy
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let contents = contents.replace("\"merge\":\"Poisson\"", "\"merge\":\"BestOnly\"");
+        std::fs::write(&path, contents).unwrap();
+        let err = resume(|_| {}).await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.contains("unknown merge strategy BestOnly"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn tree_strategy_is_reproducible_with_a_seed() {
+        let run = || async {
+            let model = ScriptedModel::new(vec![
+                ScriptedResponse::types(["number"]),
+                ScriptedResponse::types(["string"]),
+                ScriptedResponse::types(["bool"]),
+            ]);
+            run_tree(Arc::new(FakeLangServer::new()), model, |ctx| {
+                ctx.seed = Some(42);
+                // three completions for each function, more than the merges can use
+                ctx.retries = 3;
+                ctx.stop_at = 2;
+                ctx.enable_type_check = false;
+            })
+            .await
+        };
+        let first = run().await;
+        assert!(!first.is_empty());
+        for _ in 0..3 {
+            assert_eq!(codes(&run().await), codes(&first));
+        }
+    }
+}
@@ -1,11 +1,21 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use lazy_static::lazy_static;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
     net::UnixStream,
+    process::{ChildStdin, ChildStdout},
     sync::{
         mpsc::{Receiver, Sender},
         Mutex,
@@ -18,6 +28,51 @@ use crate::debug;
 pub struct SocketAbstraction {
     pub socket_path: String,
     pub process: Option<tokio::process::Child>,
+    pub timeouts: SocketTimeouts,
+    /// The stdin/stdout of the child, if it was spawned with the stdio transport.
+    stdio: Option<Mutex<StdioPipes>>,
+}
+
+/// How the client talks to a server that it spawns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Through a unix socket file in the temp dir. The server is given the path to the
+    /// socket and, optionally, the pid of the client.
+    #[default]
+    Socket,
+    /// Through the stdin and stdout of the server, no socket file is created.
+    /// The server is given `--stdio` in place of the socket path.
+    Stdio,
+}
+
+impl FromStr for Transport {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "socket" => Ok(Transport::Socket),
+            "stdio" => Ok(Transport::Stdio),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The pipes to a server spawned with the stdio transport. Requests are framed with
+/// `END_TOKEN`, and responses are JSON objects on a line of their own that ends with it.
+#[derive(Debug)]
+struct StdioPipes {
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    /// Set while a request is waiting for its response. If a request is cancelled (e.g.
+    /// it timed out), its response still arrives and has to be skipped by the next one.
+    in_flight: bool,
+    /// The number of responses of cancelled requests that still have to be skipped.
+    stale: usize,
+    /// Set while a request is being written. If a request is cancelled while it is still
+    /// set, a part of it may be left on the stdin of the server, which would be glued to the
+    /// next request, so the pipes can't be used anymore.
+    writing: bool,
 }
 
 #[derive(Debug)]
@@ -37,6 +92,55 @@ struct Worker {
 pub struct SocketPool {
     workers: Arc<Mutex<HashMap<String, Worker>>>,
     avail_rx: Mutex<Receiver<String>>,
+    timeouts: SocketTimeouts,
+}
+
+/// Timeouts for socket requests. Requests are matched on their `cmd` field, requests
+/// without a specific timeout (e.g. model requests, which have no `cmd`) use the default one.
+#[derive(Debug, Clone, Default)]
+pub struct SocketTimeouts {
+    /// The timeout for requests that don't have a specific one. None means no timeout.
+    pub default: Option<Duration>,
+    /// Timeouts for specific commands, e.g. "typecheck".
+    pub per_cmd: HashMap<String, Duration>,
+}
+
+impl SocketTimeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn default_timeout(mut self, timeout: Duration) -> Self {
+        self.default = Some(timeout);
+        self
+    }
+
+    pub fn cmd_timeout(mut self, cmd: &str, timeout: Duration) -> Self {
+        self.per_cmd.insert(cmd.to_string(), timeout);
+        self
+    }
+
+    /// Returns the timeout that applies to the given request, if any.
+    pub fn for_req(&self, req: &serde_json::Value) -> Option<Duration> {
+        req.get("cmd")
+            .and_then(|cmd| cmd.as_str())
+            .and_then(|cmd| self.per_cmd.get(cmd).copied())
+            .or(self.default)
+    }
+}
+
+/// Runs the given socket future, erroring with `SocketError::Timeout` if it
+/// does not complete in time. The future is dropped (cancelled) on timeout.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    fut: impl Future<Output = Result<T, SocketError>>,
+) -> Result<T, SocketError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| SocketError::Timeout(timeout))?,
+        None => fut.await,
+    }
 }
 
 // end token to indicate the end of a response
@@ -66,8 +170,15 @@ impl SocketPool {
         Self {
             workers: Arc::new(Mutex::new(workers)),
             avail_rx: Mutex::new(avail_rx),
+            timeouts: SocketTimeouts::default(),
         }
     }
+
+    /// Sets the timeouts for the requests sent through this pool.
+    pub fn with_timeouts(mut self, timeouts: SocketTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
 }
 
 #[async_trait::async_trait]
@@ -87,11 +198,13 @@ impl SendToSocket for SocketPool {
             (worker.socket.clone(), worker.avail_tx.clone())
         };
 
-        let resp = socket.send_req(req).await?;
+        let timeout = self.timeouts.for_req(&req);
+        let resp = with_timeout(timeout, socket.send_req(req)).await;
 
+        // the worker is available again, even if the request failed or timed out
         avail.send(socket_name).await.unwrap();
 
-        Ok(resp)
+        resp
     }
 }
 
@@ -103,13 +216,85 @@ pub enum SocketError {
     Serde(#[from] serde_json::Error),
     #[error("Service error: {0}")]
     Service(String),
+    #[error("Request timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("Shutting down, not sending any more requests")]
+    ShuttingDown,
 }
 
 lazy_static!(
     // atomic socket counter, for generating unique socket names.
     static ref SOCKET_COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    // the servers spawned by this process, by pid, with the socket file they listen on (if any).
+    // used for cleaning them up on shutdown.
+    static ref SPAWNED: std::sync::Mutex<HashMap<u32, Option<String>>> = std::sync::Mutex::new(HashMap::new());
 );
 
+// the number of requests that are currently waiting for a response
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+// set on shutdown, after which no new requests are sent
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Counts a request as in-flight for as long as the guard is alive.
+struct InFlightGuard;
+
+impl InFlightGuard {
+    fn new() -> Result<Self, SocketError> {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        if SHUTTING_DOWN.load(Ordering::SeqCst) {
+            IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+            return Err(SocketError::ShuttingDown);
+        }
+        Ok(Self)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Terminates the given spawned server, and removes its socket file, if it has one.
+fn terminate_server(pid: u32, socket_file: Option<&str>) {
+    debug!("terminating server with pid {pid}");
+    // we use SIGTERM instead of SIGKILL, such that the server can forward it to its own
+    // children (e.g. `npm start` forwards it to ts-node).
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+    if let Some(socket_file) = socket_file {
+        let _ = std::fs::remove_file(socket_file);
+    }
+}
+
+/// Kills all the servers spawned by this process, and removes their socket files.
+/// Does not wait for in-flight requests, use `shutdown` for that.
+pub fn cleanup_spawned() {
+    let spawned = std::mem::take(&mut *SPAWNED.lock().unwrap());
+    for (pid, socket_file) in spawned {
+        terminate_server(pid, socket_file.as_deref());
+    }
+}
+
+/// Exits with the given code, killing the servers spawned by this process first.
+pub fn exit(code: i32) -> ! {
+    cleanup_spawned();
+    std::process::exit(code);
+}
+
+/// Gracefully shuts down the servers spawned by this process. New requests are refused with
+/// `SocketError::ShuttingDown`, the in-flight ones get up to `grace` to finish, and then the
+/// servers are killed and their socket files removed.
+pub async fn shutdown(grace: Duration) {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    let start = Instant::now();
+    while IN_FLIGHT.load(Ordering::SeqCst) > 0 && start.elapsed() < grace {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    cleanup_spawned();
+}
+
 impl SocketAbstraction {
     /// Creates a new socket abstraction, does not spawn a process for it.
     /// The socket path is the path to the socket file, so we assume a
@@ -118,9 +303,17 @@ impl SocketAbstraction {
         Self {
             socket_path,
             process: None,
+            timeouts: SocketTimeouts::default(),
+            stdio: None,
         }
     }
 
+    /// Sets the timeouts for the requests sent through this socket.
+    pub fn with_timeouts(mut self, timeouts: SocketTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
     /// Spawns a new server process and returns a socket abstraction for it.
     /// The server command prefix is the prefix of the command to spawn the server.
     /// Does not include the last two args, which are the socket path and pid (optional).
@@ -130,49 +323,116 @@ impl SocketAbstraction {
         // Does not include the last two args, which are the socket path and pid (optional).
         server_command_prefix: &[&str],
         pid_coordination: bool,
+    ) -> Result<SocketAbstraction, SocketError> {
+        Self::spawn_server_with_transport(
+            name,
+            server_command_prefix,
+            pid_coordination,
+            Transport::Socket,
+        )
+        .await
+    }
+
+    /// Same as `spawn_server`, but with the given transport. With `Transport::Stdio`,
+    /// `--stdio` is passed in place of the socket path, and the requests are sent
+    /// through the stdin and stdout of the server.
+    pub async fn spawn_server_with_transport(
+        name: &str,
+        server_command_prefix: &[&str],
+        pid_coordination: bool,
+        transport: Transport,
+    ) -> Result<SocketAbstraction, SocketError> {
+        Self::spawn_server_with_args(
+            name,
+            server_command_prefix,
+            pid_coordination,
+            transport,
+            &[],
+        )
+        .await
+    }
+
+    /// Same as `spawn_server_with_transport`, but `extra_args` are passed after the socket
+    /// path and pid, e.g. options of the server.
+    pub async fn spawn_server_with_args(
+        name: &str,
+        server_command_prefix: &[&str],
+        pid_coordination: bool,
+        transport: Transport,
+        extra_args: &[&str],
     ) -> Result<SocketAbstraction, SocketError> {
         let pid = std::process::id();
-        let s_i = SOCKET_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-        let tmp_dir = std::env::temp_dir();
-        let tmp_socket_file = tmp_dir.join(format!("{name}-{pid}-{s_i}.sock"));
-        debug!("tmp_socket_file: {:?}", tmp_socket_file);
+        let socket_path = match transport {
+            Transport::Socket => {
+                let s_i = SOCKET_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let tmp_dir = std::env::temp_dir();
+                let tmp_socket_file = tmp_dir.join(format!("{name}-{pid}-{s_i}.sock"));
+                debug!("tmp_socket_file: {:?}", tmp_socket_file);
+                tmp_socket_file.to_str().unwrap().to_string()
+            }
+            Transport::Stdio => "--stdio".to_string(),
+        };
 
         let argv0 = server_command_prefix[0];
         let mut rest = server_command_prefix[1..]
             .iter()
             .map(|s| s.to_string())
             .collect::<Vec<_>>();
-        rest.push(tmp_socket_file.to_str().unwrap().to_string());
+        rest.push(socket_path.clone());
         if pid_coordination {
             // add pid to the rest of the arguments
             rest.push(pid.to_string());
         }
+        rest.extend(extra_args.iter().map(|s| s.to_string()));
 
-        let mut process = tokio::process::Command::new(argv0)
-            .args(rest)
-            .stdout(std::process::Stdio::piped())
-            // stderr is open by default, we want to see the output
-            .spawn()?;
-
-        // before allowing to connect, wait for the process to output "Listening"
-        {
-            let stdout = process.stdout.as_mut().unwrap();
-            let reader = tokio::io::BufReader::new(stdout);
-            let mut lines = reader.lines();
-            debug!("{name} client output:");
-            while let Some(line) = lines.next_line().await.unwrap() {
-                debug!("{}", line);
-                if line.contains("Listening") {
-                    break;
-                }
+        let mut command = tokio::process::Command::new(argv0);
+        // stderr is open by default, we want to see the output
+        command.args(rest).stdout(std::process::Stdio::piped());
+        if transport == Transport::Stdio {
+            command.stdin(std::process::Stdio::piped());
+        }
+        let mut process = command.spawn()?;
+        if let Some(child_pid) = process.id() {
+            let socket_file = match transport {
+                Transport::Socket => Some(socket_path.clone()),
+                Transport::Stdio => None,
+            };
+            SPAWNED.lock().unwrap().insert(child_pid, socket_file);
+        }
+
+        // before allowing to connect, wait for the process to output "Listening".
+        // with stdio, we keep the reader around, as the responses come after it
+        let stdout = process.stdout.take().unwrap();
+        let mut lines = BufReader::new(stdout).lines();
+        debug!("{name} client output:");
+        while let Some(line) = lines.next_line().await? {
+            debug!("{}", line);
+            if line.contains("Listening") {
+                break;
             }
         }
 
+        let stdio = match transport {
+            Transport::Socket => {
+                // give the pipe back to the process, so that it stays open
+                process.stdout = Some(lines.into_inner().into_inner());
+                None
+            }
+            Transport::Stdio => Some(Mutex::new(StdioPipes {
+                stdin: process.stdin.take().unwrap(),
+                stdout: lines,
+                in_flight: false,
+                stale: 0,
+                writing: false,
+            })),
+        };
+
         debug!("client ready to connect to {name} socket!");
-        let socket_path = tmp_socket_file.to_str().unwrap().to_string();
         let socket = SocketAbstraction {
             socket_path,
             process: Some(process),
+            timeouts: SocketTimeouts::default(),
+            stdio,
         };
         Ok(socket)
     }
@@ -181,6 +441,10 @@ impl SocketAbstraction {
     where
         T: ?Sized + Serialize,
     {
+        if let Some(stdio) = &self.stdio {
+            return Self::stdio_transaction(stdio, req).await;
+        }
+
         let mut stream = UnixStream::connect(self.socket_path.to_string()).await?;
         let req = format!("{}{}", serde_json::to_string(req).unwrap(), END_TOKEN);
 
@@ -192,6 +456,65 @@ impl SocketAbstraction {
         reader.read_line(&mut buf).await?;
         Ok(buf)
     }
+
+    async fn stdio_transaction<T>(stdio: &Mutex<StdioPipes>, req: &T) -> Result<String, SocketError>
+    where
+        T: ?Sized + Serialize,
+    {
+        let mut pipes = stdio.lock().await;
+        if pipes.writing {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "a request was cancelled while it was written to the server",
+            )
+            .into());
+        }
+        if pipes.in_flight {
+            // the previous request was cancelled before its response arrived
+            pipes.stale += 1;
+        }
+        pipes.in_flight = true;
+
+        let req = format!("{}{}", serde_json::to_string(req).unwrap(), END_TOKEN);
+        pipes.writing = true;
+        pipes.stdin.write_all(req.as_bytes()).await?;
+        pipes.stdin.flush().await?;
+        pipes.writing = false;
+
+        loop {
+            let line = pipes.stdout.next_line().await?.ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "server closed stdout")
+            })?;
+            // anything that is not framed is output of the server, not a response
+            let resp = match line.strip_suffix(END_TOKEN) {
+                Some(resp) => resp,
+                None => {
+                    debug!("{}", line);
+                    continue;
+                }
+            };
+            if pipes.stale > 0 {
+                pipes.stale -= 1;
+                continue;
+            }
+            pipes.in_flight = false;
+            return Ok(resp.to_string());
+        }
+    }
+}
+
+impl Drop for SocketAbstraction {
+    /// Backstop for `shutdown`: kills the server if we spawned it, and removes its socket file.
+    fn drop(&mut self) {
+        let pid = self.process.as_ref().and_then(|p| p.id());
+        if let Some(pid) = pid {
+            // if it's not registered anymore, it was already cleaned up
+            let registered = SPAWNED.lock().unwrap().remove(&pid);
+            if let Some(socket_file) = registered {
+                terminate_server(pid, socket_file.as_deref());
+            }
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -199,7 +522,9 @@ impl SendToSocket for SocketAbstraction {
     /// Sends the given request to the server and returns the response as a JSON object.
     /// Expects the response to have a `type` field, and if it is `error`, returns an error.
     async fn send_req(&self, req: serde_json::Value) -> Result<serde_json::Value, SocketError> {
-        let buf = self.socket_transaction(&req).await?;
+        let _in_flight = InFlightGuard::new()?;
+        let timeout = self.timeouts.for_req(&req);
+        let buf = with_timeout(timeout, self.socket_transaction(&req)).await?;
 
         // into json object
         let resp: serde_json::Value = serde_json::from_str(&buf)?;
@@ -230,3 +555,70 @@ impl SendToSocket for SingleThreadedSocket {
         socket.send_req(req).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server for the stdio transport that echoes the requests, after printing a line of
+    /// JSON that is not a response, like the logs of a library.
+    const ECHO_SERVER: &str = r#"
+import os, sys
+sys.stdout.write('Listening on stdio\n')
+sys.stdout.flush()
+pending = b''
+while True:
+    chunk = os.read(0, 4096)
+    if not chunk:
+        break
+    pending += chunk
+    while b'??END??' in pending:
+        req, pending = pending.split(b'??END??', 1)
+        sys.stdout.write('{"type": "log", "message": "not a response"}\n')
+        sys.stdout.write('{"type": "ok", "echo": ' + req.decode() + '}??END??\n')
+        sys.stdout.flush()
+"#;
+
+    async fn spawn_python(script: &str) -> SocketAbstraction {
+        SocketAbstraction::spawn_server_with_transport(
+            "test",
+            &["python3", "-c", script],
+            false,
+            Transport::Stdio,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn stdio_skips_output_that_is_not_a_response() {
+        let socket = spawn_python(ECHO_SERVER).await;
+        for i in 0..3 {
+            let resp = socket
+                .send_req(serde_json::json!({ "cmd": "echo", "i": i }))
+                .await
+                .unwrap();
+            assert_eq!(resp["type"], "ok");
+            assert_eq!(resp["echo"]["i"], i);
+        }
+    }
+
+    #[tokio::test]
+    async fn stdio_fails_fast_after_a_partially_written_request() {
+        // the server never reads its stdin, so a request larger than the pipe buffer
+        // times out while it's being written
+        let script = "import sys, time\nprint('Listening on stdio', flush=True)\ntime.sleep(60)";
+        let socket = spawn_python(script)
+            .await
+            .with_timeouts(SocketTimeouts::new().default_timeout(Duration::from_millis(200)));
+        let big = "x".repeat(1 << 20);
+        let res = socket.send_req(serde_json::json!({ "code": big })).await;
+        assert!(matches!(res, Err(SocketError::Timeout(_))), "{res:?}");
+
+        let res = socket.send_req(serde_json::json!({ "cmd": "echo" })).await;
+        match res {
+            Err(SocketError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe),
+            res => panic!("expected a broken pipe, got {res:?}"),
+        }
+    }
+}
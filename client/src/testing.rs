@@ -0,0 +1,1184 @@
+//! In-memory stand-ins for the language server and the model, such that strategies can be
+//! exercised without spawning ts-node, python or a model server.
+//!
+//! The `FakeLangServer` implements every command over a small line-based toy language:
+//! ```text
+//! // comments are kept as they are
+//! type Point = {x, y};
+//! fn dist(a: Point, b): number {
+//!     let d = 1;
+//!     fn square(x) {
+//!         return x;
+//!     }
+//!     return square(d);
+//! }
+//! let p: Point = make();
+//! ```
+//! - `fn name(param: type, ...): type {` declares a function, whose body ends at the `}`
+//!   line that closes it. Every other line ending in `{` opens an anonymous block.
+//! - `let name: type = expr;` declares a variable.
+//! - `type Name = ...;` declares a type, that can be used in annotations.
+//! - all other lines are opaque statements. Types can't contain `,`, `(`, `)`, `=` or `{`.
+//!
+//! The `ScriptedModel` answers the completion queries from a fixed script.
+//!
+//! # Example
+//! ```ignore
+//! let ls: ArcLangServer = Arc::new(FakeLangServer::new());
+//! let model = ScriptedModel::new(vec![ScriptedResponse::types(["number"])]);
+//! let engine = testing::engine(ls, model.clone());
+//! let comps = TreeStrategy { stats: None }
+//!     .run(testing::main_ctx(engine, code.to_string()))
+//!     .await?;
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use crate::{
+    completion::{
+        filter_comps, ArcCompletionEngine, ArcCompletionModel, Completion, CompletionClientBuilder,
+        CompletionEngine, CompletionModel, CompletionQuery, ModelResponseError, HOLE_IDENTIFIER,
+    },
+    langserver::{
+        AnnotateType, AnnotationAudit, Capabilities, CheckProblem, Diagnostic, DiagnosticCategory,
+        DiagnosticSpan, LangServer, LangServerCommands, LangServerError, TypeQuality, TypeRelation,
+        PROTOCOL_VERSION,
+    },
+    main_strategies::MainCtx,
+    tree::{merge::Merger, CodeBlockTree},
+    typedef_gen::ObjectInfoMap,
+};
+
+/// The types that the toy language knows without a `type` declaration.
+const BUILTIN_TYPES: [&str; 3] = ["number", "string", "bool"];
+
+/// A line of the toy language.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    Fn {
+        indent: String,
+        name: String,
+        params: Vec<(String, Option<String>)>,
+        ret: Option<String>,
+    },
+    Let {
+        indent: String,
+        name: String,
+        ty: Option<String>,
+        init: String,
+    },
+    Comment(String),
+    Other(String),
+}
+
+/// Splits an optional `: type` off of the given declaration.
+fn split_annot(decl: &str) -> (String, Option<String>) {
+    match decl.split_once(':') {
+        Some((name, ty)) => (name.trim().to_string(), Some(ty.trim().to_string())),
+        None => (decl.trim().to_string(), None),
+    }
+}
+
+fn parse_fn(indent: &str, rest: &str) -> Option<Line> {
+    let rest = rest.strip_suffix('{')?.trim_end();
+    let (name, rest) = rest.split_once('(')?;
+    let (params, ret) = rest.split_once(')')?;
+    let params = params
+        .split(',')
+        .filter(|p| !p.trim().is_empty())
+        .map(split_annot)
+        .collect();
+    let ret = ret
+        .trim()
+        .strip_prefix(':')
+        .map(|ty| ty.trim().to_string())
+        .filter(|ty| !ty.is_empty());
+    Some(Line::Fn {
+        indent: indent.to_string(),
+        name: name.trim().to_string(),
+        params,
+        ret,
+    })
+}
+
+fn parse_let(indent: &str, rest: &str) -> Option<Line> {
+    let (decl, init) = rest.split_once('=')?;
+    let (name, ty) = split_annot(decl);
+    Some(Line::Let {
+        indent: indent.to_string(),
+        name,
+        ty,
+        init: init.trim().to_string(),
+    })
+}
+
+impl Line {
+    fn parse(line: &str) -> Self {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        let parsed = if let Some(rest) = trimmed.strip_prefix("fn ") {
+            parse_fn(indent, rest)
+        } else if let Some(rest) = trimmed.strip_prefix("let ") {
+            parse_let(indent, rest)
+        } else if trimmed.starts_with("//") {
+            Some(Line::Comment(line.to_string()))
+        } else {
+            None
+        };
+        parsed.unwrap_or_else(|| Line::Other(line.to_string()))
+    }
+
+    /// Prints the line back, with the types given by `annot`, which is called on each
+    /// annotation slot of the line.
+    fn print(&self, annot: &mut impl FnMut(Option<&String>) -> Option<String>) -> String {
+        let with_annot = |name: &str, ty: Option<String>| match ty {
+            Some(ty) => format!("{name}: {ty}"),
+            None => name.to_string(),
+        };
+        match self {
+            Line::Fn {
+                indent,
+                name,
+                params,
+                ret,
+            } => {
+                let params: Vec<String> = params
+                    .iter()
+                    .map(|(name, ty)| with_annot(name, annot(ty.as_ref())))
+                    .collect();
+                let head = with_annot(
+                    &format!("fn {name}({})", params.join(", ")),
+                    annot(ret.as_ref()),
+                );
+                format!("{indent}{head} {{")
+            }
+            Line::Let {
+                indent,
+                name,
+                ty,
+                init,
+            } => format!(
+                "{indent}let {} = {init}",
+                with_annot(name, annot(ty.as_ref()))
+            ),
+            Line::Comment(line) | Line::Other(line) => line.clone(),
+        }
+    }
+
+    /// Prints the line back as it was parsed.
+    fn print_as_is(&self) -> String {
+        self.print(&mut |ty| ty.cloned())
+    }
+
+    /// Prints the line without any annotation.
+    fn print_erased(&self) -> String {
+        self.print(&mut |_| None)
+    }
+
+    /// The names of the annotation slots of the line in the order of `print`, and whether
+    /// each of them is selected by `types`.
+    fn slots(&self, types: &[AnnotateType]) -> Vec<(String, bool)> {
+        let types = &AnnotateType::with_legacy_positions(types);
+        match self {
+            Line::Fn { name, params, .. } => {
+                let func = types.contains(&AnnotateType::FuncDecl);
+                let param = func && types.contains(&AnnotateType::Param);
+                params
+                    .iter()
+                    .map(|(name, _)| (name.clone(), param))
+                    .chain(std::iter::once((
+                        format!("{name} (return)"),
+                        func && types.contains(&AnnotateType::Return),
+                    )))
+                    .collect()
+            }
+            Line::Let { name, .. } => {
+                vec![(name.clone(), types.contains(&AnnotateType::VarDecl))]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Prints the line, with `annot` called on the selected slots only.
+    fn print_selected(
+        &self,
+        types: &[AnnotateType],
+        annot: &mut impl FnMut(Option<&String>) -> Option<String>,
+    ) -> String {
+        let mut slots = self.slots(types).into_iter();
+        self.print(&mut |ty| match slots.next() {
+            Some((_, true)) => annot(ty),
+            _ => ty.cloned(),
+        })
+    }
+
+    /// The annotation of every slot of the line, in the order of `print`.
+    fn slot_annots(&self) -> Vec<Option<String>> {
+        let mut annots = vec![];
+        self.print(&mut |ty| {
+            annots.push(ty.cloned());
+            None
+        });
+        annots
+    }
+
+    /// The annotations of the line, in order.
+    fn annots(&self) -> Vec<&String> {
+        match self {
+            Line::Fn { params, ret, .. } => params
+                .iter()
+                .filter_map(|(_, ty)| ty.as_ref())
+                .chain(ret.as_ref())
+                .collect(),
+            Line::Let { ty, .. } => ty.iter().collect(),
+            _ => vec![],
+        }
+    }
+
+    fn opens_block(&self) -> bool {
+        match self {
+            Line::Fn { .. } => true,
+            Line::Other(line) => line.trim_end().ends_with('{'),
+            _ => false,
+        }
+    }
+
+    fn closes_block(&self) -> bool {
+        matches!(self, Line::Other(line) if line.trim_start().starts_with('}'))
+    }
+}
+
+/// A parsed line, with the names of the functions that enclose it.
+#[derive(Debug, Clone)]
+struct ScopedLine {
+    line: Line,
+    scope: Vec<String>,
+}
+
+impl ScopedLine {
+    /// The path of the declaration of this line, if it declares something.
+    /// Functions and variables can't share a path, as variables are prefixed with `$`.
+    fn decl_path(&self) -> Option<Vec<String>> {
+        let name = match &self.line {
+            Line::Fn { name, .. } => name.clone(),
+            Line::Let { name, .. } => format!("${name}"),
+            _ => return None,
+        };
+        let mut path = self.scope.clone();
+        path.push(name);
+        Some(path)
+    }
+}
+
+/// Parses the given code into lines, keeping track of the enclosing functions.
+fn parse(code: &str) -> Vec<ScopedLine> {
+    // the enclosing blocks, with the name of the function for function blocks
+    let mut blocks: Vec<Option<String>> = vec![];
+    let mut res = vec![];
+    for line in code.lines() {
+        let line = Line::parse(line);
+        if line.closes_block() {
+            blocks.pop();
+        }
+        let scope = blocks.iter().flatten().cloned().collect();
+        if line.opens_block() {
+            match &line {
+                Line::Fn { name, .. } => blocks.push(Some(name.clone())),
+                _ => blocks.push(None),
+            }
+        }
+        res.push(ScopedLine { line, scope });
+    }
+    res
+}
+
+/// The index of the line that closes the block opened at `start`.
+fn block_end(lines: &[ScopedLine], start: usize) -> usize {
+    let mut depth = 0;
+    for (i, l) in lines.iter().enumerate().skip(start) {
+        if l.line.closes_block() {
+            depth -= 1;
+        }
+        if l.line.opens_block() {
+            depth += 1;
+        }
+        if depth == 0 {
+            return i;
+        }
+    }
+    lines.len() - 1
+}
+
+/// Prints the lines in the given range, removing the indentation of the first one.
+fn print_dedented(lines: &[ScopedLine]) -> String {
+    let printed: Vec<String> = lines.iter().map(|l| l.line.print_as_is()).collect();
+    let indent = printed
+        .first()
+        .map(|l| l.len() - l.trim_start().len())
+        .unwrap_or(0);
+    printed
+        .iter()
+        .map(|l| {
+            let ws = l.len() - l.trim_start().len();
+            l[std::cmp::min(ws, indent)..].to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Makes the code block trees of the functions in the given lines, that are not nested in
+/// other functions of the lines.
+fn fn_trees(lines: &[ScopedLine]) -> Vec<CodeBlockTree> {
+    let mut trees = vec![];
+    let mut i = 0;
+    while i < lines.len() {
+        if let Line::Fn { name, .. } = &lines[i].line {
+            let end = block_end(lines, i);
+            trees.push(CodeBlockTree {
+                name: name.clone(),
+                code: print_dedented(&lines[i..=end]),
+                children: fn_trees(&lines[i + 1..end]),
+            });
+            i = end + 1;
+        } else {
+            i += 1;
+        }
+    }
+    trees
+}
+
+/// Whether the given annotation is a type of the toy language, i.e. names, optionally with
+/// type arguments, arrays or unions.
+fn is_valid_type(ty: &str) -> bool {
+    !ty.trim().is_empty()
+        && ty
+            .chars()
+            .all(|c| c.is_alphanumeric() || "_<>[]| ".contains(c))
+}
+
+/// Splits the given lines into the comments, the imports, and the code without types.
+fn split_lines(lines: &[ScopedLine]) -> (Vec<String>, Vec<String>, String) {
+    let mut comments = vec![];
+    let mut imports = vec![];
+    let mut code = vec![];
+    for l in lines {
+        match &l.line {
+            Line::Comment(line) => comments.push(line.trim().to_string()),
+            Line::Other(line) if line.trim_start().starts_with("import ") => {
+                imports.push(line.trim().to_string())
+            }
+            line => code.push(line.print_erased()),
+        }
+    }
+    (comments, imports, code.join("\n"))
+}
+
+/// Splits the given code into words and symbols.
+fn tokens(code: &str) -> Vec<&str> {
+    let mut res = vec![];
+    let mut start = None;
+    for (i, c) in code.char_indices() {
+        let is_word = c.is_alphanumeric() || c == '_';
+        match start {
+            Some(s) if !is_word => {
+                res.push(&code[s..i]);
+                start = None;
+            }
+            None if is_word => start = Some(i),
+            _ => {}
+        }
+        if !is_word && !c.is_whitespace() {
+            res.push(&code[i..i + c.len_utf8()]);
+        }
+    }
+    if let Some(s) = start {
+        res.push(&code[s..]);
+    }
+    res
+}
+
+/// Whether `completed` is `original` with some identifiers renamed.
+fn is_renaming(original: &str, completed: &str) -> bool {
+    const KEYWORDS: [&str; 4] = ["fn", "let", "return", "type"];
+    let is_ident =
+        |t: &str| t.starts_with(|c: char| c.is_alphabetic() || c == '_') && !KEYWORDS.contains(&t);
+    let original = tokens(original);
+    let completed = tokens(completed);
+    original.len() == completed.len()
+        && original
+            .iter()
+            .zip(completed.iter())
+            .all(|(o, c)| o == c || (is_ident(o) && is_ident(c)))
+}
+
+/// Infers the type of a literal of the toy language.
+fn literal_type(expr: &str) -> Option<&'static str> {
+    let expr = expr.trim().trim_end_matches(';').trim();
+    if expr.parse::<f64>().is_ok() {
+        Some("number")
+    } else if expr.len() >= 2 && expr.starts_with('"') && expr.ends_with('"') {
+        Some("string")
+    } else if expr == "true" || expr == "false" {
+        Some("bool")
+    } else {
+        None
+    }
+}
+
+fn error_at(line: usize, code: &str, message: String) -> Diagnostic {
+    Diagnostic {
+        span: Some(DiagnosticSpan {
+            start_line: line,
+            start_col: 0,
+            end_line: line,
+            end_col: 0,
+        }),
+        code: code.to_string(),
+        message,
+        category: DiagnosticCategory::Error,
+        pre_existing: false,
+    }
+}
+
+/// The type checker of the toy language. It reports annotations that use unknown types
+/// (`F001`), and literals that are assigned or returned to a different type than annotated
+/// (`F002`). The any type is assignable to and from everything.
+pub fn toy_type_check(code: &str, any_type: &str) -> Vec<Diagnostic> {
+    let lines = parse(code);
+    let mut known: HashSet<&str> = BUILTIN_TYPES.into_iter().collect();
+    known.insert(any_type);
+    for l in lines.iter() {
+        if let Line::Other(line) = &l.line {
+            if let Some(rest) = line.trim_start().strip_prefix("type ") {
+                if let Some((name, _)) = rest.split_once('=') {
+                    known.insert(name.trim());
+                }
+            }
+        }
+    }
+
+    let mismatch = |expected: &str, found: &str| expected != any_type && expected != found;
+    // the return types of the enclosing blocks, None for anonymous blocks
+    let mut rets: Vec<Option<Option<String>>> = vec![];
+    let mut diags = vec![];
+    for (i, l) in lines.iter().enumerate() {
+        for ty in l.line.annots() {
+            if !known.contains(ty.as_str()) {
+                diags.push(error_at(i, "F001", format!("Cannot find name '{ty}'.")));
+            }
+        }
+        match &l.line {
+            Line::Let {
+                name,
+                ty: Some(ty),
+                init,
+                ..
+            } => match literal_type(init) {
+                Some(found) if mismatch(ty, found) => diags.push(error_at(
+                    i,
+                    "F002",
+                    format!("Type '{found}' is not assignable to type '{ty}' of '{name}'."),
+                )),
+                _ => {}
+            },
+            Line::Other(line) => {
+                let ret = rets
+                    .iter()
+                    .rev()
+                    .find_map(|r| r.as_ref())
+                    .cloned()
+                    .flatten();
+                let returned = line.trim_start().strip_prefix("return ");
+                if let (Some(ret), Some(found)) = (ret, returned.and_then(literal_type)) {
+                    if mismatch(&ret, found) {
+                        diags.push(error_at(
+                            i,
+                            "F002",
+                            format!("Type '{found}' is not assignable to type '{ret}'."),
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+        if l.line.closes_block() {
+            rets.pop();
+        }
+        if l.line.opens_block() {
+            match &l.line {
+                Line::Fn { ret, .. } => rets.push(Some(ret.clone())),
+                _ => rets.push(None),
+            }
+        }
+    }
+    diags
+}
+
+type TypeChecker = Arc<dyn Fn(&str) -> Vec<Diagnostic> + Send + Sync>;
+
+/// A language server for the toy language of this module, that runs in-process and answers
+/// deterministically. Commands can be disabled or made to time out, to test how strategies
+/// handle limited servers, and the number of times each command was called is recorded.
+pub struct FakeLangServer {
+    any_type: String,
+    disabled: HashSet<String>,
+    timing_out: HashSet<String>,
+    type_checker: Option<TypeChecker>,
+    calls: std::sync::Mutex<HashMap<String, usize>>,
+}
+
+impl std::fmt::Debug for FakeLangServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FakeLangServer")
+            .field("any_type", &self.any_type)
+            .field("disabled", &self.disabled)
+            .field("timing_out", &self.timing_out)
+            .field("calls", &self.calls)
+            .finish()
+    }
+}
+
+/// Builder for a `FakeLangServer` with scripted behaviour.
+pub struct FakeLangServerBuilder {
+    any_type: String,
+    disabled: HashSet<String>,
+    timing_out: HashSet<String>,
+    type_checker: Option<TypeChecker>,
+}
+
+impl FakeLangServerBuilder {
+    pub fn new() -> Self {
+        Self {
+            any_type: "any".to_string(),
+            disabled: HashSet::new(),
+            timing_out: HashSet::new(),
+            type_checker: None,
+        }
+    }
+
+    /// The spelling of the any type, defaults to `any`.
+    pub fn any_type(mut self, any_type: &str) -> Self {
+        self.any_type = any_type.to_string();
+        self
+    }
+
+    /// Makes the server reject the given commands as unknown, and leave them out of its
+    /// capabilities. The command names are the ones of the socket protocol, e.g. "usages".
+    /// Disabling "capabilities" makes the server look like one that predates it.
+    pub fn disable(mut self, cmds: &[&str]) -> Self {
+        self.disabled.extend(cmds.iter().map(|cmd| cmd.to_string()));
+        self
+    }
+
+    /// Makes the given commands fail with a timeout.
+    pub fn timing_out(mut self, cmds: &[&str]) -> Self {
+        self.timing_out
+            .extend(cmds.iter().map(|cmd| cmd.to_string()));
+        self
+    }
+
+    /// Replaces the type checker of the toy language with the given function, which gets
+    /// the code and returns its diagnostics.
+    pub fn type_checker<F>(mut self, checker: F) -> Self
+    where
+        F: Fn(&str) -> Vec<Diagnostic> + Send + Sync + 'static,
+    {
+        self.type_checker = Some(Arc::new(checker));
+        self
+    }
+
+    pub fn build(self) -> FakeLangServer {
+        FakeLangServer {
+            any_type: self.any_type,
+            disabled: self.disabled,
+            timing_out: self.timing_out,
+            type_checker: self.type_checker,
+            calls: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for FakeLangServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeLangServer {
+    /// Makes a server with the default behaviour of the toy language.
+    pub fn new() -> Self {
+        FakeLangServerBuilder::new().build()
+    }
+
+    /// The number of times the given command was called, including the failed calls.
+    pub fn calls(&self, cmd: &str) -> usize {
+        *self.calls.lock().unwrap().get(cmd).unwrap_or(&0)
+    }
+
+    /// Records a call of the given command, and fails it if it was scripted to.
+    fn call(&self, cmd: &str) -> Result<(), LangServerError> {
+        *self
+            .calls
+            .lock()
+            .unwrap()
+            .entry(cmd.to_string())
+            .or_insert(0) += 1;
+        if self.disabled.contains(cmd) {
+            Err(LangServerError::LC(format!("unknown command: {cmd}")))
+        } else if self.timing_out.contains(cmd) {
+            Err(LangServerError::Timeout(std::time::Duration::ZERO))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The breakdown of the annotations of the given lines. Leaf types are the names in a type,
+    /// and the depth is the nesting of type arguments and arrays.
+    fn quality(&self, lines: &[ScopedLine]) -> TypeQuality {
+        let mut quality = TypeQuality::default();
+        let mut leaves = 0;
+        for l in lines {
+            let slots: Vec<Option<&String>> = match &l.line {
+                Line::Fn { params, ret, .. } => params
+                    .iter()
+                    .map(|(_, ty)| ty.as_ref())
+                    .chain(std::iter::once(ret.as_ref()))
+                    .collect(),
+                Line::Let { ty, .. } => vec![ty.as_ref()],
+                _ => vec![],
+            };
+            for slot in slots {
+                quality.annotations += 1;
+                let ty = match slot {
+                    Some(ty) if !ty.contains(HOLE_IDENTIFIER) => ty,
+                    _ => continue,
+                };
+                quality.filled += 1;
+
+                let mut depth: usize = 1;
+                for c in ty.chars() {
+                    match c {
+                        '<' | '[' => {
+                            depth += 1;
+                            quality.depth = std::cmp::max(quality.depth, depth);
+                        }
+                        '>' | ']' => depth = depth.saturating_sub(1),
+                        _ => {}
+                    }
+                }
+                quality.depth = std::cmp::max(quality.depth, 1);
+
+                for leaf in ty
+                    .split(|c: char| "<>[]|".contains(c) || c.is_whitespace())
+                    .filter(|leaf| !leaf.is_empty())
+                {
+                    leaves += 1;
+                    match leaf {
+                        _ if leaf == self.any_type => quality.any += 1,
+                        "unknown" => quality.unknown += 1,
+                        "Function" => quality.function += 1,
+                        "object" => quality.object += 1,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        let vague = quality.any + quality.unknown + quality.function + quality.object;
+        quality.specificity = ((leaves - vague) * 1000).checked_div(leaves).unwrap_or(0) as u16;
+        quality
+    }
+
+    fn diagnostics(&self, code: &str) -> Vec<Diagnostic> {
+        match &self.type_checker {
+            Some(checker) => checker(code),
+            None => toy_type_check(code, &self.any_type),
+        }
+    }
+}
+
+impl Default for FakeLangServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl LangServerCommands for FakeLangServer {
+    async fn capabilities(&self) -> Result<Capabilities, LangServerError> {
+        self.call("capabilities")?;
+        let mut caps = Capabilities::legacy(self.any_type.clone());
+        caps.commands
+            .extend(["strip", "audit"].iter().map(|cmd| cmd.to_string()));
+        caps.commands.retain(|cmd| !self.disabled.contains(cmd));
+        caps.protocol_version = PROTOCOL_VERSION;
+        Ok(caps)
+    }
+
+    /// Annotates the functions with `FuncDecl` and the variables with `VarDecl`, the
+    /// `Param` and `Return` positions select the slots of the functions.
+    async fn pretty_print(
+        &self,
+        code: &str,
+        type_name: &str,
+        types: &[AnnotateType],
+    ) -> Result<String, LangServerError> {
+        self.call("print")?;
+        let printed: Vec<String> = parse(code)
+            .iter()
+            .map(|l| {
+                l.line.print_selected(types, &mut |ty| {
+                    Some(ty.cloned().unwrap_or_else(|| type_name.to_string()))
+                })
+            })
+            .collect();
+        Ok(printed.join("\n"))
+    }
+
+    /// The root is named `topnode`, and every function is a child of the function (or root)
+    /// that it is declared in.
+    async fn to_tree(&self, code: &str) -> Result<CodeBlockTree, LangServerError> {
+        self.call("tree")?;
+        let lines = parse(code);
+        Ok(CodeBlockTree {
+            name: "topnode".to_string(),
+            code: code.to_string(),
+            children: fn_trees(&lines),
+        })
+    }
+
+    /// Removes the bodies of the functions that are nested in the outermost blocks.
+    async fn stub(&self, code: &str) -> Result<String, LangServerError> {
+        self.call("stub")?;
+        let lines = parse(code);
+        let mut res = vec![];
+        let mut depth = 0;
+        let mut i = 0;
+        while i < lines.len() {
+            let line = &lines[i].line;
+            if line.closes_block() {
+                depth -= 1;
+            }
+            res.push(line.print_as_is());
+            if let (Line::Fn { .. }, 1) = (line, depth) {
+                i = block_end(&lines, i);
+                res.push(lines[i].line.print_as_is());
+            } else if line.opens_block() {
+                depth += 1;
+            }
+            i += 1;
+        }
+        Ok(res.join("\n"))
+    }
+
+    async fn check_complete(
+        &self,
+        original: &str,
+        completed: &str,
+    ) -> Result<(Vec<CheckProblem>, u16), LangServerError> {
+        let (problems, score, _) = self.check_complete_detailed(original, completed).await?;
+        Ok((problems, score))
+    }
+
+    /// The score is the share of annotations that are the any type, in [0, 1000].
+    async fn check_complete_detailed(
+        &self,
+        original: &str,
+        completed: &str,
+    ) -> Result<(Vec<CheckProblem>, u16, Option<TypeQuality>), LangServerError> {
+        self.call("check")?;
+        let original = parse(original);
+        let completed = parse(completed);
+        let mut problems = vec![];
+
+        if completed.iter().any(|l| {
+            l.line
+                .annots()
+                .iter()
+                .any(|ty| ty.contains(HOLE_IDENTIFIER))
+        }) {
+            problems.push(CheckProblem::NotComplete);
+        }
+
+        let annots: Vec<&String> = completed.iter().flat_map(|l| l.line.annots()).collect();
+        if annots.iter().any(|ty| **ty == self.any_type) {
+            problems.push(CheckProblem::UsedAnyType);
+        }
+        if annots.iter().any(|ty| !is_valid_type(ty)) {
+            problems.push(CheckProblem::InvalidTypeSyntax);
+        }
+
+        let (orig_comments, orig_imports, orig_code) = split_lines(&original);
+        let (comp_comments, comp_imports, comp_code) = split_lines(&completed);
+        if orig_comments != comp_comments {
+            problems.push(CheckProblem::ChangedComments);
+        }
+        // removing imports is changing the code
+        let removed_import = orig_imports.iter().any(|i| !comp_imports.contains(i));
+        if comp_imports.iter().any(|i| !orig_imports.contains(i)) {
+            problems.push(CheckProblem::AddedImports);
+        }
+
+        let no_whitespace = |code: &str| code.split_whitespace().collect::<String>();
+        if removed_import {
+            problems.push(CheckProblem::ChangedCode);
+        } else if orig_code == comp_code {
+            // nothing changed
+        } else if no_whitespace(&orig_code) == no_whitespace(&comp_code) {
+            problems.push(CheckProblem::ChangedFormattingOnly);
+        } else if is_renaming(&orig_code, &comp_code) {
+            problems.push(CheckProblem::RenamedIdentifier);
+        } else {
+            problems.push(CheckProblem::ChangedCode);
+        }
+
+        let anys = annots.iter().filter(|ty| **ty == &self.any_type).count();
+        let score = if annots.is_empty() {
+            0
+        } else {
+            (anys * 1000 / annots.len()) as u16
+        };
+        Ok((problems, score, Some(self.quality(&completed))))
+    }
+
+    /// Fills the missing annotations of `original` with the ones of the declarations at the
+    /// same path in `nettle`, where the path of a declaration is the names of the functions
+    /// enclosing it. Paths of `nettle` match at the end, such that a nested block can be woven
+    /// back into its parent, so `level` is not needed.
+    async fn weave(
+        &self,
+        original: &str,
+        nettle: &str,
+        _level: usize,
+    ) -> Result<String, LangServerError> {
+        self.call("weave")?;
+        let nettle: Vec<(Vec<String>, Line)> = parse(nettle)
+            .into_iter()
+            .filter_map(|l| Some((l.decl_path()?, l.line)))
+            .collect();
+        let woven: Vec<String> = parse(original)
+            .into_iter()
+            .map(|l| {
+                let path = match l.decl_path() {
+                    Some(path) => path,
+                    None => return l.line.print_as_is(),
+                };
+                let source = nettle
+                    .iter()
+                    .filter(|(p, _)| path.ends_with(p))
+                    .max_by_key(|(p, _)| p.len());
+                let source = match source {
+                    Some((_, source)) => source,
+                    None => return l.line.print_as_is(),
+                };
+                let mut slots = match (&l.line, source) {
+                    (
+                        Line::Fn { params, .. },
+                        Line::Fn {
+                            params: n, ret: nr, ..
+                        },
+                    ) => {
+                        let mut slots: Vec<Option<String>> = (0..params.len())
+                            .map(|i| n.get(i).and_then(|(_, ty)| ty.clone()))
+                            .collect();
+                        slots.push(nr.clone());
+                        slots.into_iter()
+                    }
+                    (_, Line::Let { ty, .. }) => vec![ty.clone()].into_iter(),
+                    _ => vec![].into_iter(),
+                };
+                l.line.print(&mut |ty| {
+                    let woven = slots.next().flatten();
+                    ty.cloned().or(woven)
+                })
+            })
+            .collect();
+        Ok(woven.join("\n"))
+    }
+
+    async fn usages(
+        &self,
+        outer_block: &str,
+        inner_block: &str,
+    ) -> Result<(String, usize), LangServerError> {
+        self.call("usages")?;
+        let name = match parse(inner_block).first().map(|l| &l.line) {
+            Some(Line::Fn { name, .. }) => name.clone(),
+            _ => return Ok((String::new(), 0)),
+        };
+        let lines = parse(outer_block);
+        let call = format!("{name}(");
+        let mut usages = vec![];
+        let mut i = 0;
+        while i < lines.len() {
+            match &lines[i].line {
+                // the uses inside of the function itself are not usages
+                Line::Fn { name: n, .. } if n == &name => i = block_end(&lines, i),
+                Line::Fn { .. } => {}
+                line => {
+                    let printed = line.print_as_is();
+                    if printed.contains(&call) {
+                        usages.push(printed.trim().to_string());
+                    }
+                }
+            }
+            i += 1;
+        }
+        if usages.is_empty() {
+            return Ok((String::new(), 0));
+        }
+        let num = usages.len();
+        Ok((
+            format!(
+                "// Usages of {name} are shown below:\n{}",
+                usages.join("\n")
+            ),
+            num,
+        ))
+    }
+
+    /// The toy language has no objects.
+    async fn object_info(&self, _code: &str) -> Result<ObjectInfoMap, LangServerError> {
+        self.call("objectInfo")?;
+        Ok(ObjectInfoMap::new())
+    }
+
+    /// The toy language has no objects, the code is returned as it is.
+    async fn typedef_gen(&self, code: &str) -> Result<String, LangServerError> {
+        self.call("typedefGen")?;
+        Ok(code.to_string())
+    }
+
+    async fn strip(&self, code: &str, types: &[AnnotateType]) -> Result<String, LangServerError> {
+        self.call("strip")?;
+        let stripped: Vec<String> = parse(code)
+            .iter()
+            .map(|l| l.line.print_selected(types, &mut |_| None))
+            .collect();
+        Ok(stripped.join("\n"))
+    }
+
+    /// Types are only compared by name: the any type is wider than every other type, and
+    /// distinct types are incompatible.
+    async fn audit(
+        &self,
+        original: &str,
+        completed: &str,
+        types: &[AnnotateType],
+    ) -> Result<Vec<AnnotationAudit>, LangServerError> {
+        self.call("audit")?;
+        let original = parse(original);
+        let completed = parse(completed);
+        if original.len() != completed.len() {
+            return Err(LangServerError::LC(
+                "the completed code does not match the original".to_string(),
+            ));
+        }
+        let mut audits = vec![];
+        for (i, (orig, comp)) in original.iter().zip(completed.iter()).enumerate() {
+            let slots = orig.line.slots(types);
+            let annots = orig
+                .line
+                .slot_annots()
+                .into_iter()
+                .zip(comp.line.slot_annots());
+            for ((location, selected), (declared, inferred)) in slots.into_iter().zip(annots) {
+                let (declared, inferred) = match (declared, inferred) {
+                    (Some(declared), Some(inferred)) if selected => (declared, inferred),
+                    _ => continue,
+                };
+                let relation = if declared == inferred {
+                    TypeRelation::Same
+                } else if inferred == self.any_type {
+                    TypeRelation::Wider
+                } else if declared == self.any_type {
+                    TypeRelation::Narrower
+                } else {
+                    TypeRelation::Incompatible
+                };
+                audits.push(AnnotationAudit {
+                    location,
+                    line: i + 1,
+                    declared,
+                    inferred,
+                    relation,
+                });
+            }
+        }
+        Ok(audits)
+    }
+}
+
+#[async_trait]
+impl LangServer for FakeLangServer {
+    /// Makes a default server, the path is ignored.
+    async fn make(_path: &str) -> Result<Self, LangServerError> {
+        Ok(Self::new())
+    }
+
+    async fn type_check(&self, code: &str) -> Result<usize, LangServerError> {
+        Ok(self
+            .type_check_detailed(code)
+            .await?
+            .iter()
+            .filter(|d| d.is_error())
+            .count())
+    }
+
+    async fn type_check_detailed(&self, code: &str) -> Result<Vec<Diagnostic>, LangServerError> {
+        self.call("typecheck")?;
+        Ok(self.diagnostics(code))
+    }
+
+    fn any_type(&self) -> String {
+        self.any_type.clone()
+    }
+
+    fn get_type_parser(&self) -> Option<Box<dyn Fn(&str) -> Option<String> + Sync + Send>> {
+        None
+    }
+}
+
+/// A single scripted answer of the `ScriptedModel`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptedResponse {
+    /// Fills the holes of the prompt in order with the given types. The last type is used for
+    /// the remaining holes.
+    Types(Vec<String>),
+    /// Answers with the given code, regardless of the prompt.
+    Code(String),
+    /// Fails, like a model that could not complete.
+    Fail,
+    /// Fails, like a rate limited model.
+    RateLimited,
+}
+
+impl ScriptedResponse {
+    pub fn types<S: Into<String>>(types: impl IntoIterator<Item = S>) -> Self {
+        ScriptedResponse::Types(types.into_iter().map(|ty| ty.into()).collect())
+    }
+}
+
+/// Fills the holes of the given prompt in order with the given types.
+fn fill_holes(prompt: &str, types: &[String]) -> String {
+    let mut parts = prompt.split(HOLE_IDENTIFIER);
+    let mut res = parts.next().unwrap_or_default().to_string();
+    for (i, part) in parts.enumerate() {
+        match types.get(i).or(types.last()) {
+            Some(ty) => res.push_str(ty),
+            None => res.push_str(HOLE_IDENTIFIER),
+        }
+        res.push_str(part);
+    }
+    res
+}
+
+/// A model that answers each request with the next response of a script, starting over when
+/// the script runs out. The completions go through the same filtering as the ones of the
+/// real models. The prompts of the requests are recorded, in the order they were made.
+#[derive(Debug, Clone)]
+pub struct ScriptedModel {
+    script: Arc<Vec<ScriptedResponse>>,
+    next: Arc<AtomicUsize>,
+    prompts: Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl ScriptedModel {
+    pub fn new(script: Vec<ScriptedResponse>) -> Self {
+        Self {
+            script: Arc::new(script),
+            next: Arc::new(AtomicUsize::new(0)),
+            prompts: Arc::new(std::sync::Mutex::new(vec![])),
+        }
+    }
+
+    /// The prompts of the requests made so far. Each retry of a query is a request.
+    pub fn prompts(&self) -> Vec<String> {
+        self.prompts.lock().unwrap().clone()
+    }
+}
+
+impl CompletionModel for ScriptedModel {
+    fn spawn_comp(
+        &self,
+        query: &CompletionQuery,
+        engine: &dyn CompletionEngine,
+        filtered_completions: Arc<Mutex<Vec<Completion>>>,
+    ) -> JoinHandle<Result<(), ModelResponseError>> {
+        let lang_client = engine.get_ls();
+        let max_type_score = engine.get_max_type_score();
+        let problem_weights = engine.get_problem_weights();
+        let input = query.input.clone();
+        let problem_whitelist = query.problem_whitelist.clone();
+        self.prompts.lock().unwrap().push(input.clone());
+        let response = match self.script.len() {
+            0 => ScriptedResponse::Fail,
+            len => self.script[self.next.fetch_add(1, Ordering::SeqCst) % len].clone(),
+        };
+        tokio::task::spawn(async move {
+            let completion = match response {
+                ScriptedResponse::Types(types) => fill_holes(&input, &types),
+                ScriptedResponse::Code(code) => code,
+                ScriptedResponse::Fail => return Err(ModelResponseError::CouldNotComplete),
+                ScriptedResponse::RateLimited => {
+                    return Err(ModelResponseError::RateLimited("scripted".to_string()))
+                }
+            };
+            filter_comps(
+                filtered_completions,
+                lang_client,
+                &input,
+                completion,
+                problem_whitelist,
+                problem_weights,
+                max_type_score,
+            )
+            .await
+        })
+    }
+}
+
+/// Makes a completion engine out of the given language server and model, with the default
+/// options of the client.
+pub fn engine(
+    lang_server: Arc<dyn LangServer + Send + Sync>,
+    model: impl CompletionModel + Send + Sync + 'static,
+) -> ArcCompletionEngine {
+    let model: ArcCompletionModel = Arc::new(model);
+    Arc::new(CompletionClientBuilder::new(lang_server, model).build())
+}
+
+/// Makes a context for running a strategy on the given code, with the defaults of the CLI.
+pub fn main_ctx(engine: ArcCompletionEngine, file_contents: String) -> MainCtx {
+    MainCtx {
+        engine,
+        file_contents,
+        num_comps: 3,
+        retries: 1,
+        fallback: false,
+        stop_at: 1,
+        enable_type_check: true,
+        enable_defgen: false,
+        enable_usages: true,
+        enable_stubbing: true,
+        enable_parser: true,
+        enable_checkproblems: true,
+        enable_diagnostics: false,
+        depth_limit: None,
+        enable_pruning: false,
+        merge: Merger::default(),
+        seed: None,
+        types: AnnotateType::all(),
+        project_file: None,
+        checkpoint: None,
+        resume: false,
+    }
+}
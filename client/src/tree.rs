@@ -1,9 +1,12 @@
 use std::{
     cmp::Ordering,
-    collections::{HashMap, HashSet, VecDeque},
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 use serde::{Deserialize, Serialize};
 use tokio::{sync::Mutex, task::JoinHandle};
 
@@ -11,13 +14,18 @@ use crate::{
     completion::{
         ArcCompletionEngine, Completion, CompletionError, CompletionQuery, CompletionQueryBuilder,
     },
-    langserver::{AnnotateType, CheckProblem},
+    langserver::{mark_pre_existing, AnnotateType, CheckProblem},
 };
 use crate::{
     debug,
     langserver::{ArcLangServer, LangServerError},
+    socket::SocketError,
 };
 
+use self::merge::Merger;
+
+pub mod merge;
+
 use self::stats::ArcTreeAlgoStats;
 
 pub mod stats {
@@ -148,6 +156,46 @@ pub struct HyperParams {
     pub stop_at: usize,
     // the kind of types that need to be annotated
     pub types: Vec<AnnotateType>,
+    // if we want to drop the completions of a node that add type errors to its parent
+    #[serde(default)]
+    pub prune: bool,
+    // how to merge the completions of the children into the prompts
+    #[serde(default)]
+    pub merge: Merger,
+    // the seed of the random choices, such that a run can be reproduced. random if None
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+/// A snapshot of the completion levels, written after every level that `tree_complete`
+/// finishes, such that an interrupted run can be resumed from the deepest unfinished level.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// the code that the tree was made from, to check that a resumed run has the same input
+    pub input: String,
+    pub params: HyperParams,
+    pub levels: Vec<CompLevel>,
+    /// the number of levels that are not completed yet. levels are completed from the
+    /// deepest one, so the levels from `remaining` on are completed.
+    pub remaining: usize,
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint from the given file.
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        serde_json::from_str(&contents)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the checkpoint to the given file. The file is replaced at once, such that
+    /// an interruption never leaves a partially written checkpoint behind.
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string(self)?)?;
+        std::fs::rename(tmp, path)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -155,6 +203,10 @@ pub struct CompletionLevels<State = NewState> {
     levels: Vec<CompLevel>,
     params: HyperParams,
     stats: Option<ArcTreeAlgoStats>,
+    // the number of levels that still need to be completed, from the deepest one
+    remaining: usize,
+    // where to write the checkpoints, with the code that the tree was made from
+    checkpoint: Option<(PathBuf, String)>,
     // this is the state of the completion levels
     state: std::marker::PhantomData<State>,
 }
@@ -167,6 +219,24 @@ impl CompletionLevels<NewState> {
             levels: vec![],
             params: hyperparams,
             stats,
+            remaining: 0,
+            checkpoint: None,
+            state: std::marker::PhantomData,
+        }
+    }
+
+    /// Makes prepared completion levels out of the given checkpoint, such that
+    /// `tree_complete` picks up from the deepest level that was not completed.
+    pub fn resume(
+        checkpoint: Checkpoint,
+        stats: Option<ArcTreeAlgoStats>,
+    ) -> CompletionLevels<PreparedState> {
+        CompletionLevels {
+            levels: checkpoint.levels,
+            params: checkpoint.params,
+            stats,
+            remaining: checkpoint.remaining,
+            checkpoint: None,
             state: std::marker::PhantomData,
         }
     }
@@ -243,60 +313,100 @@ impl CompletionLevels<NewState> {
             p_children = new_children;
         }
         Ok(CompletionLevels {
+            remaining: levels.len(),
             levels,
             params: self.params,
             stats: self.stats,
+            checkpoint: self.checkpoint,
             state: std::marker::PhantomData,
         })
     }
 }
 
-/// Strategy for merging the level below into the level above. Utilizes all possible combinations
-/// between the level below and the level above.
-/// NOTE: This could lead to a lot of permutations, and a state explosion. We need to be careful with this.
-async fn merge_below_all_combs(
-    child: &CompNode,
-    level: usize,
-    prompts_set: &mut HashSet<String>,
-    ls: &ArcLangServer,
-) {
-    // make all possible combinations between prompt elements and
-    // child.completed elements
-    let mut new_prompts = HashSet::new();
-    for (p_i, parent_code) in prompts_set.iter().enumerate() {
-        for (c_i, child_code) in child.completed.iter().enumerate() {
-            debug!(
-                "weaving child({}) {c_i} into parent {p_i} (max p: {}, max c: {})",
-                child.name,
-                prompts_set.len(),
-                child.completed.len()
-            );
-            let comp = ls
-                // we take the min because at level 0 we have the root node
-                // and we want to weave at nettle_level 0
-                .weave(parent_code, child_code, std::cmp::min(1, level))
-                .await
-                .unwrap();
-            new_prompts.insert(comp);
+/// Turns a timed out language server command into `None`, such that the caller can
+/// treat it as producing no completions instead of waiting on it forever.
+///
+/// # Panics
+/// Panics on any other language server error.
+fn none_if_timeout<T>(res: Result<T, LangServerError>) -> Option<T> {
+    match res {
+        Ok(t) => Some(t),
+        Err(LangServerError::Timeout(d)) => {
+            debug!("language server command timed out after {d:?}, skipping");
+            None
         }
+        Err(e) => panic!("language server command failed: {e:?}"),
     }
+}
 
-    *prompts_set = new_prompts;
+/// Turns a failed language server command into `None`, such that pruning keeps the completions
+/// that it could not check instead of failing the whole run.
+fn none_if_err<T>(res: Result<T, LangServerError>) -> Option<T> {
+    match res {
+        Ok(t) => Some(t),
+        Err(e) => {
+            debug!("language server command failed while pruning: {e:?}, skipping");
+            None
+        }
+    }
 }
 
-/// Counts the number of possible combinations between the level below and the level above.
-/// If the number of combinations is too large for an usize, we return usize::MAX.
-fn count_all_possible_combs(child: &CompNode, curr_prompts: usize) -> usize {
-    let mut res: usize = 1;
-    for _ in 0..curr_prompts {
-        // make sure we don't overflow. return usize::MAX if we do
-        // and return early
-        res = match res.checked_mul(child.completed.len()) {
-            Some(mul) => mul,
-            None => return usize::MAX,
+/// Type checks each completion of a node in the context of its parent, woven in the same way as
+/// the merge into the parent at `parent_level` will do it, and drops the completions that add
+/// type errors to the parent. The parent is not stubbed, such that errors in the body of the
+/// node are seen, and errors that the parent already had, e.g. in the siblings of the node, are
+/// not counted. If every completion adds errors, the ones that add the fewest are kept, such
+/// that the level above still has something to merge. Completions that can't be checked are kept.
+async fn prune_by_type_check(
+    comps: Vec<String>,
+    parent: &str,
+    parent_level: usize,
+    ls: &ArcLangServer,
+) -> Vec<String> {
+    let baseline = match none_if_err(ls.type_check_detailed(parent).await) {
+        Some(diagnostics) => diagnostics,
+        None => return comps,
+    };
+
+    let mut checked = vec![];
+    for comp in comps {
+        let woven = none_if_err(merge::try_weave_child(ls, parent, &comp, parent_level).await);
+        let diagnostics = match woven {
+            Some(woven) => none_if_err(ls.type_check_detailed(&woven).await),
+            None => None,
         };
+        // we keep the completions that we could not check
+        let new_errors = diagnostics.map_or(0, |mut diagnostics| {
+            mark_pre_existing(&mut diagnostics, &baseline);
+            diagnostics
+                .iter()
+                .filter(|d| d.is_error() && !d.pre_existing)
+                .count()
+        });
+        checked.push((comp, new_errors));
     }
-    res
+
+    let fewest = checked.iter().map(|(_, e)| *e).min().unwrap_or(0);
+    if fewest > 0 {
+        debug!("all completions add type errors to the parent, keeping the ones adding {fewest}");
+    }
+    checked
+        .into_iter()
+        .filter(|(_, e)| *e == fewest)
+        .map(|(comp, _)| comp)
+        .collect()
+}
+
+/// Derives the seed of the merges of a node from the seed of the run, such that every node gets
+/// its own stream of random numbers, regardless of the order that the nodes are completed in.
+fn node_seed(seed: u64, level: usize, name: &str) -> u64 {
+    // FNV-1a, which unlike the hasher of std is stable across releases
+    let mut hash = 0xcbf29ce484222325 ^ seed;
+    for byte in (level as u64).to_le_bytes().iter().chain(name.as_bytes()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
 }
 
 // evenly distribute the stop_at parameter across a given number of children.
@@ -317,91 +427,34 @@ fn distribute_stop_at(stop_at: usize, num_children: usize) -> Vec<usize> {
     res
 }
 
-/// Generates all possible combinations between the prompts and the completions in pairs
-/// of (prompt, completion). The given upper bound is the maximum number of combinations
-/// that we want to generate, if any.
-fn all_combs(
-    prompts: &HashSet<String>,
-    comps: &[String],
-    upper: Option<usize>,
-) -> Vec<(String, String)> {
-    let mut upper = upper.unwrap_or(usize::MAX);
-    let mut res = Vec::new();
-    for prompt in prompts.iter() {
-        for comp in comps.iter() {
-            if upper == 0 {
-                return res;
-            }
-            res.push((prompt.clone(), comp.clone()));
-            upper -= 1;
-        }
+impl CompletionLevels<PreparedState> {
+    /// Writes a checkpoint to the given path after every completed level. `input` is the
+    /// code that the tree was made from.
+    pub fn with_checkpoint(mut self, path: impl Into<PathBuf>, input: String) -> Self {
+        self.checkpoint = Some((path.into(), input));
+        self
     }
-    res
-}
-
-/// Strategy for merging the level below into the level above. Utilizes a random permutation
-/// between the level below and the level above. Where earlier indexes in the children
-/// are more likely to be chosen. This is because they are typically better completions.
-///
-/// # Panics
-/// ASSUMES that the total number of combinations is greater than upper.
-async fn merge_below_random_poisson(
-    child: &CompNode,
-    level: usize,
-    // is our upper bound for the number of completions
-    upper: usize,
-    prompts_set: &mut HashSet<String>,
-    ls: &ArcLangServer,
-) {
-    let mut new_prompts = HashSet::new();
-
-    // 0.7 converges to this distribution:
-    // 0: 50%
-    // 1: 35%
-    // 2: 12.5%
-    // 3: 3%
-    // 4: 0.5%
-    // ...
-    let poi = rand_distr::Poisson::new(0.7).unwrap();
-
-    // we set the maximum upper bound of completions to upper * 5. it is extremely unlikely
-    // that we will ever reach this upper bound, but it is a safety net for
-    // state explosion.
-    let combs_upper = upper * 5;
-    let mut all_combs = all_combs(prompts_set, &child.completed, Some(combs_upper));
-
-    let mut dbg_i = 0;
-    while new_prompts.len() < upper && !all_combs.is_empty() {
-        let mut idx = {
-            // need to make sure we drop this before an await
-            // https://stackoverflow.com/questions/67443847/how-to-generate-random-numbers-in-async-rust
-            let mut rng = rand::thread_rng();
-            rand_distr::Distribution::sample(&poi, &mut rng) as usize
-        };
-        // adjust if we are out of bounds
-        if idx >= all_combs.len() {
-            idx = all_combs.len() - 1;
-        }
-        debug!(
-            "random weaving child({}) - iter {dbg_i}, picked idx {idx} (max iter: {upper})",
-            child.name
-        );
 
-        let (prompt, comp) = all_combs.remove(idx);
-        let comp = ls
-            // we take the min because at level 0 we have the root node
-            // and we want to weave at nettle_level 0
-            .weave(&prompt, &comp, std::cmp::min(1, level))
-            .await
-            .unwrap();
-        new_prompts.insert(comp);
-        dbg_i += 1;
+    /// The number of levels that still need to be completed.
+    pub fn remaining(&self) -> usize {
+        self.remaining
     }
 
-    *prompts_set = new_prompts;
-}
+    /// Writes the checkpoint, if enabled. Failing to write it doesn't stop the completion.
+    fn save_checkpoint(&self) {
+        if let Some((path, input)) = &self.checkpoint {
+            let checkpoint = Checkpoint {
+                input: input.clone(),
+                params: self.params.clone(),
+                levels: self.levels.clone(),
+                remaining: self.remaining,
+            };
+            if let Err(e) = checkpoint.save(path) {
+                eprintln!("Failed to write checkpoint to {}: {e}", path.display());
+            }
+        }
+    }
 
-impl CompletionLevels<PreparedState> {
     async fn retry_query_until_ok(
         engine: &ArcCompletionEngine,
         q: CompletionQuery,
@@ -409,12 +462,20 @@ impl CompletionLevels<PreparedState> {
         let mut res = engine.complete(q.clone()).await;
         let mut retries = 0;
         while res.is_err() {
-            // if it's a rate limit, print out to stderr
-            if let CompletionError::RateLimit(r) = res.unwrap_err() {
-                eprintln!(
-                    "Rate limited, but got {} canditate completions before.",
-                    r.len()
-                );
+            match res.unwrap_err() {
+                // if it's a rate limit, print out to stderr
+                CompletionError::RateLimit(r) => {
+                    eprintln!(
+                        "Rate limited, but got {} canditate completions before.",
+                        r.len()
+                    );
+                }
+                // a timed out query is likely to time out again, so we give up on it
+                CompletionError::Socket(SocketError::Timeout(d)) => {
+                    eprintln!("Query timed out after {d:?}, giving up on it.");
+                    return None;
+                }
+                _ => {}
             }
             if retries > 5 {
                 return None;
@@ -432,17 +493,26 @@ impl CompletionLevels<PreparedState> {
         level: usize,
         prev_level: Arc<Option<Vec<CompNode>>>,
         node: CompNode,
+        parent: Option<String>,
     ) -> JoinHandle<(String, Vec<String>)> {
         let num_comps = params.num_comps;
         let retries = params.retries;
         let do_fallback = params.fallback;
         let do_stub = params.stub;
+        let do_prune = params.prune;
+        let merge = params.merge.clone();
+        // ChaCha8 is a fixed algorithm, unlike `StdRng`, so a seeded run can be replayed
+        // after a dependency bump
+        let mut rng = match params.seed {
+            Some(seed) => ChaCha8Rng::seed_from_u64(node_seed(seed, level, &node.name)),
+            None => ChaCha8Rng::from_entropy(),
+        };
         // we use stop_at as our upper bound for the number of completions
         let stop_at = params.stop_at;
         let types_to_annot = params.types.clone();
 
         tokio::task::spawn(async move {
-            let mut prompts_set: HashSet<String> = HashSet::from([node.code.clone()]);
+            let mut prompts: Vec<String> = vec![node.code.clone()];
             // if we are not at a leaf, we need to patch the node with the children
             if !node.children_idxs.is_empty() {
                 let level_below: &Vec<CompNode> = prev_level.as_ref().as_ref().unwrap();
@@ -460,48 +530,40 @@ impl CompletionLevels<PreparedState> {
                             level_below.len()
                         )
                     });
-                    let all_combs_num = count_all_possible_combs(child, prompts_set.len());
-                    if all_combs_num > upper {
-                        debug!(
-                            "all_combs_num {} > upper {}, so we use random poisson",
-                            all_combs_num, upper
-                        );
-                        merge_below_random_poisson(
+                    merge
+                        .merge(
                             child,
                             level,
                             upper,
-                            &mut prompts_set,
+                            &mut prompts,
+                            &mut rng,
                             &engine.get_ls(),
                         )
                         .await;
-                    } else {
-                        debug!(
-                            "all_combs_num {} <= upper {}, so we use all combinations",
-                            all_combs_num, upper
-                        );
-                        merge_below_all_combs(child, level, &mut prompts_set, &engine.get_ls())
-                            .await;
-                    }
                 }
             }
 
-            let prompts: Vec<String> = prompts_set.into_iter().collect();
             debug!("number of level prompts: {}", prompts.len());
             match level.cmp(&0) {
                 Ordering::Greater => {
                     let ls = engine.get_ls();
-                    let mut new_comps = HashSet::new(); // we don't care about duplicates
+                    // the best score of each completion, to order them best-first
+                    let mut new_comps: HashMap<String, u16> = HashMap::new();
                     for prompt in prompts.iter() {
                         let stubbed = if do_stub {
-                            ls.stub(prompt).await.unwrap()
+                            match none_if_timeout(ls.stub(prompt).await) {
+                                Some(stubbed) => stubbed,
+                                None => continue,
+                            }
                         } else {
                             prompt.clone()
                         };
 
-                        let mut printed = ls
-                            .pretty_print(&stubbed, "_hole_", &types_to_annot)
-                            .await
-                            .unwrap();
+                        let printed = ls.pretty_print(&stubbed, "_hole_", &types_to_annot).await;
+                        let mut printed = match none_if_timeout(printed) {
+                            Some(printed) => printed,
+                            None => continue,
+                        };
 
                         // we add usages to the prompt
                         if !node.usages.is_empty() {
@@ -527,7 +589,8 @@ impl CompletionLevels<PreparedState> {
                                         .await
                                         .unwrap_or_else(|_| comp.code.clone());
                                     debug!("type-woven completion: \n{}", rewoven);
-                                    new_comps.insert(rewoven);
+                                    let score = new_comps.entry(rewoven).or_insert(comp.score);
+                                    *score = std::cmp::min(*score, comp.score);
                                 }
                             }
                             None => {
@@ -535,7 +598,25 @@ impl CompletionLevels<PreparedState> {
                             }
                         }
                     }
-                    (node.name, new_comps.into_iter().collect())
+                    let mut new_comps: Vec<(String, u16)> = new_comps.into_iter().collect();
+                    new_comps.sort_by(|(a, a_score), (b, b_score)| {
+                        a_score.cmp(b_score).then_with(|| a.cmp(b))
+                    });
+                    let new_comps: Vec<String> =
+                        new_comps.into_iter().map(|(code, _)| code).collect();
+                    match parent {
+                        Some(parent) if do_prune => {
+                            let num_comps = new_comps.len();
+                            let pruned =
+                                prune_by_type_check(new_comps, &parent, level - 1, &ls).await;
+                            debug!(
+                                "pruned {} of {num_comps} completions",
+                                num_comps - pruned.len()
+                            );
+                            (node.name, pruned)
+                        }
+                        _ => (node.name, new_comps),
+                    }
                 }
                 // if we are at root, we just want to disassemble the tree, no comps
                 Ordering::Equal => (node.name, prompts),
@@ -544,7 +625,8 @@ impl CompletionLevels<PreparedState> {
         })
     }
 
-    /// Completes the code block tree, mutating the tree in place.
+    /// Completes the code block tree, mutating the tree in place. Levels that were already
+    /// completed, i.e. when resuming from a checkpoint, are skipped.
     pub async fn tree_complete(
         mut self,
         engine: ArcCompletionEngine,
@@ -552,9 +634,19 @@ impl CompletionLevels<PreparedState> {
         // we start at the deepest level of the array, and we complete the code blocks
         // at the level.
         let num_levels = self.levels.len();
-        let mut prev_level: Arc<Option<Vec<CompNode>>> = Arc::new(None);
-        for level in (0..num_levels).rev() {
+        let mut prev_level: Arc<Option<Vec<CompNode>>> =
+            Arc::new(self.levels.get(self.remaining).map(|l| l.nodes.clone()));
+        for level in (0..self.remaining).rev() {
             println!(" --- Tree Level: {level} / {} ---", num_levels - 1);
+            // the code of the parent of each node, by the node's idx, for pruning
+            let mut parents: HashMap<usize, String> = HashMap::new();
+            if let Some(above) = level.checked_sub(1).and_then(|l| self.levels.get(l)) {
+                for parent in above.nodes.iter() {
+                    for idx in parent.children_idxs.iter() {
+                        parents.insert(*idx, parent.code.clone());
+                    }
+                }
+            }
             let nodes = &mut self.levels.get_mut(level).unwrap().nodes;
             let num_nodes = nodes.len();
             let mut handles: Vec<JoinHandle<(String, Vec<String>)>> = vec![]; // node's (name, code)
@@ -576,6 +668,7 @@ impl CompletionLevels<PreparedState> {
                     level,
                     prev_level,
                     node,
+                    parents.remove(&i),
                 ));
             }
 
@@ -597,12 +690,16 @@ impl CompletionLevels<PreparedState> {
             }
             debug!("setting prev_level");
             prev_level = Arc::new(Some(nodes.clone()));
+            self.remaining = level;
+            self.save_checkpoint();
         }
 
         CompletionLevels {
             levels: self.levels,
             params: self.params,
             stats: self.stats,
+            remaining: self.remaining,
+            checkpoint: self.checkpoint,
             state: std::marker::PhantomData,
         }
     }
@@ -613,3 +710,60 @@ impl CompletionLevels<CompletedState> {
         self.levels[0].nodes.remove(0).completed
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distributes_stop_at_evenly() {
+        assert_eq!(distribute_stop_at(10, 3), vec![4, 3, 3]);
+        assert_eq!(distribute_stop_at(10, 4), vec![3, 3, 2, 2]);
+        assert_eq!(distribute_stop_at(3, 3), vec![1, 1, 1]);
+        assert_eq!(distribute_stop_at(2, 3), vec![1, 1, 0]);
+        assert_eq!(distribute_stop_at(0, 2), vec![0, 0]);
+    }
+
+    #[tokio::test]
+    async fn prunes_completions_with_errors_in_their_body() {
+        let ls: ArcLangServer = Arc::new(crate::testing::FakeLangServer::new());
+        // the sibling has an error already, which is not blamed on the completions
+        let parent = "fn outer(a) {
+    fn inner(x) {
+        let d = 1;
+        return x;
+    }
+    fn sibling(y): number {
+        return \"s\";
+    }
+    return inner(a);
+}";
+        let comp = |ty: &str| {
+            format!("fn inner(x: number): number {{\n    let d: {ty} = 1;\n    return x;\n}}")
+        };
+        let pruned =
+            prune_by_type_check(vec![comp("string"), comp("number")], parent, 1, &ls).await;
+        assert_eq!(pruned, vec![comp("number")]);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_completions_it_cannot_check() {
+        let ls: ArcLangServer = Arc::new(
+            crate::testing::FakeLangServerBuilder::new()
+                .disable(&["typecheck"])
+                .build(),
+        );
+        let comps = vec!["fn inner(x: string) {\n}".to_string()];
+        let pruned = prune_by_type_check(comps.clone(), "fn inner(x) {\n}", 1, &ls).await;
+        assert_eq!(pruned, comps);
+    }
+
+    #[test]
+    fn node_seeds_differ_by_level_and_name() {
+        let seed = node_seed(7, 1, "f");
+        assert_eq!(seed, node_seed(7, 1, "f"));
+        assert_ne!(seed, node_seed(8, 1, "f"));
+        assert_ne!(seed, node_seed(7, 2, "f"));
+        assert_ne!(seed, node_seed(7, 1, "g"));
+    }
+}
@@ -0,0 +1,595 @@
+//! Strategies for merging the completions of a child node into the prompts of its parent.
+//! The tree algorithm calls the strategy of its hyperparameters once per child, in order, so the
+//! prompts of the parent are the combinations of all of its children in the end.
+//!
+//! Custom strategies implement `MergeStrategy`, and can use `weave_child` to combine a prompt
+//! with a completion of the child:
+//! ```ignore
+//! #[derive(Debug)]
+//! struct BestOnly;
+//!
+//! #[async_trait]
+//! impl MergeStrategy for BestOnly {
+//!     fn name(&self) -> &str {
+//!         "BestOnly"
+//!     }
+//!
+//!     async fn merge(
+//!         &self,
+//!         child: &CompNode,
+//!         level: usize,
+//!         _upper: usize,
+//!         prompts: &mut Vec<String>,
+//!         _rng: &mut ChaCha8Rng,
+//!         ls: &ArcLangServer,
+//!     ) {
+//!         let mut new_prompts = vec![];
+//!         for prompt in prompts.iter() {
+//!             if let Some(best) = child.completed.first() {
+//!                 new_prompts.extend(weave_child(ls, prompt, best, level).await);
+//!             }
+//!         }
+//!         *prompts = new_prompts;
+//!     }
+//! }
+//! ```
+//! and are plugged in with `HyperParams { merge: Merger::new(BestOnly), .. }`, or the `merge`
+//! field of the `MainCtx` for the tree strategy.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet},
+    hash::{Hash, Hasher},
+    ops::Deref,
+    str::FromStr,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use rand::RngCore;
+use rand_chacha::ChaCha8Rng;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{none_if_timeout, CompNode};
+use crate::{
+    debug,
+    langserver::{ArcLangServer, LangServerError},
+};
+
+/// A strategy for merging the completions of a child into the prompts of its parent.
+#[async_trait]
+pub trait MergeStrategy: std::fmt::Debug + Send + Sync {
+    /// The name of the strategy. Strategies with the same name are considered equal, and the
+    /// name is what is written for the strategy in a checkpoint.
+    fn name(&self) -> &str;
+
+    /// Replaces `prompts`, the prompts of the parent so far, with their combinations with the
+    /// completions of `child`, woven in at the given level of the parent. Both the prompts and
+    /// the completions of the child are ordered best-first. `upper` is the budget of the child,
+    /// the number of prompts that it should result in at most. Any randomness should come from
+    /// `rng`, which is seeded by the seed of the run, such that the merge is reproducible.
+    async fn merge(
+        &self,
+        child: &CompNode,
+        level: usize,
+        upper: usize,
+        prompts: &mut Vec<String>,
+        rng: &mut ChaCha8Rng,
+        ls: &ArcLangServer,
+    );
+}
+
+pub type ArcMergeStrategy = Arc<dyn MergeStrategy>;
+
+/// A shared merge strategy, as it is held by the hyperparameters of the tree algorithm.
+/// Strategies are compared, hashed and serialized by their name, and only the ones of
+/// `MergeMode` can be deserialized. A checkpoint of a run with any other strategy fails
+/// to load.
+#[derive(Debug, Clone)]
+pub struct Merger(pub ArcMergeStrategy);
+
+impl Merger {
+    pub fn new(strategy: impl MergeStrategy + 'static) -> Self {
+        Self(Arc::new(strategy))
+    }
+}
+
+impl Default for Merger {
+    fn default() -> Self {
+        MergeMode::default().strategy()
+    }
+}
+
+impl Deref for Merger {
+    type Target = dyn MergeStrategy;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl PartialEq for Merger {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name()
+    }
+}
+
+impl Eq for Merger {}
+
+impl Hash for Merger {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name().hash(state)
+    }
+}
+
+impl Serialize for Merger {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Merger {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(d)?;
+        MergeMode::from_str(&name)
+            .map(MergeMode::strategy)
+            .map_err(|_| {
+                D::Error::custom(format!(
+                    "unknown merge strategy {name}, only the ones of MergeMode can be loaded"
+                ))
+            })
+    }
+}
+
+/// Weaves the given completion of a child into the given prompt of its parent, which is at the
+/// given level. Returns None if the language server timed out.
+pub async fn weave_child(
+    ls: &ArcLangServer,
+    prompt: &str,
+    child_comp: &str,
+    level: usize,
+) -> Option<String> {
+    none_if_timeout(try_weave_child(ls, prompt, child_comp, level).await)
+}
+
+/// Like `weave_child`, but returns the errors of the language server.
+pub(crate) async fn try_weave_child(
+    ls: &ArcLangServer,
+    prompt: &str,
+    child_comp: &str,
+    level: usize,
+) -> Result<String, LangServerError> {
+    ls
+        // we take the min because at level 0 we have the root node
+        // and we want to weave at nettle_level 0
+        .weave(prompt, child_comp, std::cmp::min(1, level))
+        .await
+}
+
+/// The merge strategies that come with the client, by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum MergeMode {
+    /// `PoissonSampling`
+    #[default]
+    Poisson,
+    /// `BeamSearch`
+    Beam,
+    /// `AllCombinations`
+    AllCombinations,
+}
+
+impl MergeMode {
+    /// Makes the strategy of this mode.
+    pub fn strategy(self) -> Merger {
+        match self {
+            MergeMode::Poisson => Merger::new(PoissonSampling),
+            MergeMode::Beam => Merger::new(BeamSearch),
+            MergeMode::AllCombinations => Merger::new(AllCombinations),
+        }
+    }
+}
+
+impl FromStr for MergeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Poisson" => Ok(MergeMode::Poisson),
+            "Beam" => Ok(MergeMode::Beam),
+            "AllCombinations" => Ok(MergeMode::AllCombinations),
+            _ => Err(format!("Unknown merge mode: {s}")),
+        }
+    }
+}
+
+/// Utilizes all possible combinations between the level below and the level above.
+/// NOTE: This could lead to a lot of permutations, and a state explosion. We need to be careful with this.
+#[derive(Debug, Clone, Copy)]
+pub struct AllCombinations;
+
+#[async_trait]
+impl MergeStrategy for AllCombinations {
+    fn name(&self) -> &str {
+        "AllCombinations"
+    }
+
+    async fn merge(
+        &self,
+        child: &CompNode,
+        level: usize,
+        _upper: usize,
+        prompts: &mut Vec<String>,
+        _rng: &mut ChaCha8Rng,
+        ls: &ArcLangServer,
+    ) {
+        merge_below_all_combs(child, level, prompts, ls).await
+    }
+}
+
+/// Utilizes all possible combinations if they fit in the budget of the child, otherwise a random
+/// permutation where earlier indexes in the children are more likely to be chosen.
+#[derive(Debug, Clone, Copy)]
+pub struct PoissonSampling;
+
+#[async_trait]
+impl MergeStrategy for PoissonSampling {
+    fn name(&self) -> &str {
+        "Poisson"
+    }
+
+    async fn merge(
+        &self,
+        child: &CompNode,
+        level: usize,
+        upper: usize,
+        prompts: &mut Vec<String>,
+        rng: &mut ChaCha8Rng,
+        ls: &ArcLangServer,
+    ) {
+        let all_combs_num = count_all_possible_combs(child, prompts.len());
+        if all_combs_num > upper {
+            debug!(
+                "all_combs_num {} > upper {}, so we use random poisson",
+                all_combs_num, upper
+            );
+            merge_below_random_poisson(child, level, upper, prompts, rng, ls).await;
+        } else {
+            debug!(
+                "all_combs_num {} <= upper {}, so we use all combinations",
+                all_combs_num, upper
+            );
+            merge_below_all_combs(child, level, prompts, ls).await;
+        }
+    }
+}
+
+/// Weaves the combinations in best-first order, until the budget of the child is reached.
+/// A combination is ranked by the sum of the indexes of its prompt and child completion.
+#[derive(Debug, Clone, Copy)]
+pub struct BeamSearch;
+
+#[async_trait]
+impl MergeStrategy for BeamSearch {
+    fn name(&self) -> &str {
+        "Beam"
+    }
+
+    async fn merge(
+        &self,
+        child: &CompNode,
+        level: usize,
+        upper: usize,
+        prompts: &mut Vec<String>,
+        _rng: &mut ChaCha8Rng,
+        ls: &ArcLangServer,
+    ) {
+        merge_below_beam(child, level, upper, prompts, ls).await
+    }
+}
+
+/// Strategy for merging the level below into the level above. Utilizes all possible combinations
+/// between the level below and the level above.
+async fn merge_below_all_combs(
+    child: &CompNode,
+    level: usize,
+    prompts: &mut Vec<String>,
+    ls: &ArcLangServer,
+) {
+    // make all possible combinations between prompt elements and
+    // child.completed elements
+    let mut new_prompts = vec![];
+    for (p_i, parent_code) in prompts.iter().enumerate() {
+        for (c_i, child_code) in child.completed.iter().enumerate() {
+            debug!(
+                "weaving child({}) {c_i} into parent {p_i} (max p: {}, max c: {})",
+                child.name,
+                prompts.len(),
+                child.completed.len()
+            );
+            if let Some(comp) = weave_child(ls, parent_code, child_code, level).await {
+                new_prompts.push(comp);
+            }
+        }
+    }
+
+    *prompts = dedup_in_order(new_prompts);
+}
+
+/// Strategy for merging the level below into the level above. Weaves the combinations in
+/// best-first order, until `upper` prompts are made. Both the prompts and the completions of
+/// the child are ordered best-first, so a combination is ranked by the sum of their indexes.
+/// The resulting prompts are ordered best-first too, such that the next child can rank them.
+async fn merge_below_beam(
+    child: &CompNode,
+    level: usize,
+    // is our upper bound for the number of completions
+    upper: usize,
+    prompts: &mut Vec<String>,
+    ls: &ArcLangServer,
+) {
+    let mut new_prompts = vec![];
+    let mut seen = HashSet::new();
+    // the frontier of the combinations, as (rank, prompt idx, completion idx)
+    let mut frontier = BinaryHeap::new();
+    let mut visited = HashSet::new();
+    if !prompts.is_empty() && !child.completed.is_empty() {
+        frontier.push(Reverse((0, 0, 0)));
+        visited.insert((0, 0));
+    }
+
+    while new_prompts.len() < upper {
+        let (rank, p_i, c_i) = match frontier.pop() {
+            Some(Reverse(comb)) => comb,
+            None => break,
+        };
+        debug!(
+            "beam weaving child({}) {c_i} into parent {p_i} (rank {rank}, max iter: {upper})",
+            child.name
+        );
+        let comp = weave_child(ls, &prompts[p_i], &child.completed[c_i], level).await;
+        if let Some(comp) = comp {
+            if seen.insert(comp.clone()) {
+                new_prompts.push(comp);
+            }
+        }
+
+        for (p_j, c_j) in [(p_i + 1, c_i), (p_i, c_i + 1)] {
+            if p_j < prompts.len() && c_j < child.completed.len() && visited.insert((p_j, c_j)) {
+                frontier.push(Reverse((p_j + c_j, p_j, c_j)));
+            }
+        }
+    }
+
+    *prompts = new_prompts;
+}
+
+/// Removes the duplicates of the given codes, keeping the first occurrence of each.
+fn dedup_in_order(codes: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    codes
+        .into_iter()
+        .filter(|code| seen.insert(code.clone()))
+        .collect()
+}
+
+/// Counts the number of possible combinations between the level below and the level above.
+/// If the number of combinations is too large for an usize, we return usize::MAX.
+fn count_all_possible_combs(child: &CompNode, curr_prompts: usize) -> usize {
+    let mut res: usize = 1;
+    for _ in 0..curr_prompts {
+        // make sure we don't overflow. return usize::MAX if we do
+        // and return early
+        res = match res.checked_mul(child.completed.len()) {
+            Some(mul) => mul,
+            None => return usize::MAX,
+        };
+    }
+    res
+}
+
+/// Generates all possible combinations between the prompts and the completions in pairs
+/// of (prompt, completion). The given upper bound is the maximum number of combinations
+/// that we want to generate, if any.
+fn all_combs(prompts: &[String], comps: &[String], upper: Option<usize>) -> Vec<(String, String)> {
+    let mut upper = upper.unwrap_or(usize::MAX);
+    let mut res = Vec::new();
+    for prompt in prompts.iter() {
+        for comp in comps.iter() {
+            if upper == 0 {
+                return res;
+            }
+            res.push((prompt.clone(), comp.clone()));
+            upper -= 1;
+        }
+    }
+    res
+}
+
+/// Samples a Poisson distributed number with Knuth's algorithm. Unlike the distributions of
+/// `rand_distr`, the algorithm is fixed, so a seeded run picks the same indexes after a
+/// dependency bump.
+fn sample_poisson(lambda: f64, rng: &mut ChaCha8Rng) -> usize {
+    let limit = (-lambda).exp();
+    let mut k = 0;
+    let mut p = 1.0;
+    loop {
+        // a uniform number in [0, 1), from the upper 53 bits of the raw output
+        p *= (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        if p <= limit {
+            return k;
+        }
+        k += 1;
+    }
+}
+
+/// Strategy for merging the level below into the level above. Utilizes a random permutation
+/// between the level below and the level above. Where earlier indexes in the children
+/// are more likely to be chosen. This is because they are typically better completions.
+///
+/// # Panics
+/// ASSUMES that the total number of combinations is greater than upper.
+async fn merge_below_random_poisson(
+    child: &CompNode,
+    level: usize,
+    // is our upper bound for the number of completions
+    upper: usize,
+    prompts: &mut Vec<String>,
+    rng: &mut ChaCha8Rng,
+    ls: &ArcLangServer,
+) {
+    let mut new_prompts = vec![];
+    let mut seen = HashSet::new();
+
+    // 0.7 converges to this distribution:
+    // 0: 50%
+    // 1: 35%
+    // 2: 12.5%
+    // 3: 3%
+    // 4: 0.5%
+    // ...
+    let lambda = 0.7;
+
+    // we set the maximum upper bound of completions to upper * 5. it is extremely unlikely
+    // that we will ever reach this upper bound, but it is a safety net for
+    // state explosion.
+    let combs_upper = upper * 5;
+    let mut all_combs = all_combs(prompts, &child.completed, Some(combs_upper));
+
+    let mut dbg_i = 0;
+    while new_prompts.len() < upper && !all_combs.is_empty() {
+        let mut idx = sample_poisson(lambda, rng);
+        // adjust if we are out of bounds
+        if idx >= all_combs.len() {
+            idx = all_combs.len() - 1;
+        }
+        debug!(
+            "random weaving child({}) - iter {dbg_i}, picked idx {idx} (max iter: {upper})",
+            child.name
+        );
+
+        let (prompt, comp) = all_combs.remove(idx);
+        if let Some(comp) = weave_child(ls, &prompt, &comp, level).await {
+            if seen.insert(comp.clone()) {
+                new_prompts.push(comp);
+            }
+        }
+        dbg_i += 1;
+    }
+
+    *prompts = new_prompts;
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::testing::FakeLangServer;
+
+    /// A parent whose parameter is annotated with `ty`, with a nested child.
+    fn parent(ty: &str) -> String {
+        format!("fn outer(a: {ty}) {{\n    fn inner(x) {{\n        return x;\n    }}\n    return inner(a);\n}}")
+    }
+
+    /// The parent with the parameter of the child annotated with `child_ty` too.
+    fn woven(ty: &str, child_ty: &str) -> String {
+        format!("fn outer(a: {ty}) {{\n    fn inner(x: {child_ty}) {{\n        return x;\n    }}\n    return inner(a);\n}}")
+    }
+
+    fn child(types: &[&str]) -> CompNode {
+        CompNode {
+            children_idxs: vec![],
+            name: "inner".to_string(),
+            code: "fn inner(x) {\n    return x;\n}".to_string(),
+            completed: types
+                .iter()
+                .map(|ty| format!("fn inner(x: {ty}) {{\n    return x;\n}}"))
+                .collect(),
+            usages: String::new(),
+        }
+    }
+
+    async fn merge(
+        mode: MergeMode,
+        prompts: &[&str],
+        child_types: &[&str],
+        upper: usize,
+        seed: u64,
+    ) -> Vec<String> {
+        let ls: ArcLangServer = Arc::new(FakeLangServer::new());
+        let mut prompts = prompts.iter().map(|ty| parent(ty)).collect();
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        mode.strategy()
+            .merge(&child(child_types), 1, upper, &mut prompts, &mut rng, &ls)
+            .await;
+        prompts
+    }
+
+    #[tokio::test]
+    async fn all_combinations_weaves_every_pair_in_order() {
+        let prompts = merge(MergeMode::AllCombinations, &["A", "B"], &["x", "y"], 1, 0).await;
+        let expected = [
+            woven("A", "x"),
+            woven("A", "y"),
+            woven("B", "x"),
+            woven("B", "y"),
+        ];
+        assert_eq!(prompts, expected);
+    }
+
+    #[tokio::test]
+    async fn poisson_uses_all_combinations_within_the_budget() {
+        let prompts = merge(MergeMode::Poisson, &["A", "B"], &["x", "y"], 4, 0).await;
+        let expected = [
+            woven("A", "x"),
+            woven("A", "y"),
+            woven("B", "x"),
+            woven("B", "y"),
+        ];
+        assert_eq!(prompts, expected);
+    }
+
+    #[tokio::test]
+    async fn poisson_samples_the_budget_reproducibly() {
+        let prompts = merge(MergeMode::Poisson, &["A", "B"], &["x", "y", "z"], 3, 7).await;
+        assert_eq!(prompts.len(), 3);
+        for prompt in prompts.iter() {
+            assert!(
+                ["A", "B"]
+                    .iter()
+                    .any(|p| ["x", "y", "z"].iter().any(|c| *prompt == woven(p, c))),
+                "{prompt}"
+            );
+        }
+        let again = merge(MergeMode::Poisson, &["A", "B"], &["x", "y", "z"], 3, 7).await;
+        assert_eq!(prompts, again);
+    }
+
+    #[tokio::test]
+    async fn beam_weaves_best_first() {
+        let prompts = merge(MergeMode::Beam, &["A", "B", "C"], &["x", "y", "z"], 4, 0).await;
+        // rank 0, then the two of rank 1 with the better prompt first, then rank 2
+        let expected = [
+            woven("A", "x"),
+            woven("A", "y"),
+            woven("B", "x"),
+            woven("A", "z"),
+        ];
+        assert_eq!(prompts, expected);
+    }
+
+    #[test]
+    fn merge_modes_round_trip_by_name() {
+        for mode in [
+            MergeMode::Poisson,
+            MergeMode::Beam,
+            MergeMode::AllCombinations,
+        ] {
+            let merger = mode.strategy();
+            assert_eq!(MergeMode::from_str(merger.name()), Ok(mode));
+            let json = serde_json::to_string(&merger).unwrap();
+            let back: Merger = serde_json::from_str(&json).unwrap();
+            assert_eq!(back, merger);
+        }
+    }
+}
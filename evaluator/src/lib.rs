@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use opentau::{
     completion::{
@@ -6,9 +6,19 @@ use opentau::{
         ArcCompletionModel, CompletionClientBuilder, TypecheckedCompletion,
     },
     get_path_from_rootdir,
-    langserver::{ts::TsServer, AnnotateType, ArcLangServer, LangServer},
+    langserver::{
+        generic::{GenericLangServerBuilder, LangServerConfig},
+        pool::LangServerPool,
+        py::{PyServerBuilder, PyTypeChecker},
+        ts::TsServerBuilder,
+        AnnotateType, ArcLangServer, CompilerOptions, ProblemWeights,
+    },
     main_strategies::{MainCtx, MainStrategy, SimpleStrategy, TreeStrategy},
-    tree::stats::{ArcTreeAlgoStats, TreeAlgoStats},
+    socket::{SocketTimeouts, Transport},
+    tree::{
+        merge::MergeMode,
+        stats::{ArcTreeAlgoStats, TreeAlgoStats},
+    },
 };
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -27,7 +37,7 @@ pub struct EvalSpec {
     pub local_model_socket: Option<String>,
     /// This is a key for remote models, e.g. OpenAI key for OpenAI's davinci-edit
     pub remote_model_key: Option<String>,
-    /// This is the language that is being evaluated. e.g. "ts"
+    /// This is the language that is being evaluated. e.g. "ts", "py" or "js" (JSDoc types)
     pub language: String,
     /// This is the path to the results file. This is where the results will be stored.
     pub results_path: String,
@@ -70,6 +80,10 @@ pub struct EvalSpec {
     /// most of the times and disabled only for ablation.
     #[serde(default = "eval_spec_defaults::default_enable_checkproblems")]
     pub enable_checkproblems: bool,
+    /// This keeps the diagnostics of the type checker in the completions of the
+    /// results, such that the type errors can be analyzed afterwards.
+    #[serde(default)]
+    pub enable_diagnostics: bool,
     /// This depth-limits the tree strategy. It may be useful for very deep
     /// trees, but is not recommended for best results. If this is set to 1,
     /// the tree strategy will behave like the simple strategy but will split
@@ -77,12 +91,33 @@ pub struct EvalSpec {
     /// This option is set to None for best results.
     #[serde(default = "eval_spec_defaults::default_depth_limit")]
     pub depth_limit: Option<usize>,
+    /// This type checks the completions of each node in the tree strategy in
+    /// the context of its parent, and drops the ones that add type errors
+    /// before they are merged into the level above.
+    #[serde(default)]
+    pub enable_pruning: bool,
+    /// This is how the tree strategy merges the completions of the children
+    /// into their parent, one of "Poisson", "Beam" or "AllCombinations".
+    /// Defaults to "Poisson".
+    #[serde(default)]
+    pub merge: MergeMode,
+    /// This is the seed of the random choices of the tree strategy, such
+    /// that an evaluation can be reproduced with the same model outputs.
+    /// The choices are random if it's not set.
+    #[serde(default)]
+    pub seed: Option<u64>,
     /// This is the maximum type quality that is considered. Type quality here
     /// is measured by the heuristic, where a lower score is better, and the
     /// score is in the range of 0 to 1000. This should be set to 1000 for best
     /// results (disabled essentially).
     #[serde(default = "eval_spec_defaults::default_max_type_quality")]
     pub max_type_quality: u16,
+    /// These are the score penalties for the problems found by the heuristic, as
+    /// a map from the problem to its weight, e.g. {"UsedAnyType": 100}. A problem
+    /// with a weight is added to the score instead of rejecting the completion, and
+    /// a null weight rejects it. Problems that are left out keep their default.
+    #[serde(default)]
+    pub problem_weights: ProblemWeights,
     /// This is the temperature for the completion generation that is sent to
     /// the model. This should be set to 0.75 for best results, as it will
     /// provide diversity in the completions.
@@ -92,10 +127,50 @@ pub struct EvalSpec {
     /// we enabled all types except for VarDecls.
     #[serde(default = "eval_spec_defaults::default_types")]
     pub types: Vec<AnnotateType>,
+    /// This is the timeout in seconds for the type checking command of the
+    /// language server. If a candidate times out, it is dropped. None means no timeout.
+    #[serde(default)]
+    pub typecheck_timeout: Option<u64>,
+    /// This is the timeout in seconds for a single sampling request to the model.
+    /// If a request times out, the node is treated as having no completions.
+    /// None means no timeout.
+    #[serde(default)]
+    pub model_timeout: Option<u64>,
+    /// This is how the language server is talked to, either "socket" (through a
+    /// unix socket file) or "stdio" (through the stdin and stdout of the server).
+    #[serde(default)]
+    pub ls_transport: Transport,
+    /// The type checker used for Python, either "mypy" or "pyright".
+    #[serde(default)]
+    pub py_checker: PyTypeChecker,
+    /// The number of language servers to spawn, commands are distributed across them.
+    #[serde(default = "eval_spec_defaults::default_ls_pool_size")]
+    pub ls_pool_size: usize,
+    /// Runs the evaluation in project mode, with the language server started on this
+    /// project, either its root directory or a `tsconfig.json`. Elements of the dataset
+    /// then need a "path" field with the path of their file relative to the project root,
+    /// and are type checked as a replacement of that file. Elements without it are type
+    /// checked in isolation.
+    #[serde(default)]
+    pub project: Option<String>,
+    /// The compiler options that candidates are type checked with, either an object of
+    /// options (e.g. {"strict": true, "noImplicitAny": true}) or the path to a
+    /// `tsconfig.json`. They override the defaults of the language server, or the options
+    /// of the project. Not used for python.
+    #[serde(default)]
+    pub compiler_options: Option<CompilerOptions>,
+    /// This is the path to a TOML or JSON config of an external language server,
+    /// which is used instead of the built-in server of `language`.
+    #[serde(default)]
+    pub lang_config: Option<String>,
 }
 
 /// Default values for the evaluation spec deserializer.
 mod eval_spec_defaults {
+    pub(super) fn default_ls_pool_size() -> usize {
+        1
+    }
+
     pub(super) fn default_num_comps() -> usize {
         3
     }
@@ -152,13 +227,64 @@ mod eval_spec_defaults {
 
 impl EvalSpec {
     async fn get_langserver(&self) -> ArcLangServer {
+        let mut timeouts = SocketTimeouts::new();
+        if let Some(secs) = self.typecheck_timeout {
+            timeouts = timeouts.cmd_timeout("typecheck", Duration::from_secs(secs));
+        }
+        if let Some(path) = &self.lang_config {
+            let config = LangServerConfig::load(path).unwrap_or_else(|e| {
+                pue!("{e}");
+            });
+            return Arc::new(
+                LangServerPool::spawn(self.ls_pool_size, || {
+                    let mut builder = GenericLangServerBuilder::new(config.clone())
+                        .timeouts(timeouts.clone())
+                        .transport(self.ls_transport);
+                    if let Some(options) = &self.compiler_options {
+                        builder = builder.compiler_options(options.clone());
+                    }
+                    builder.build()
+                })
+                .await
+                .unwrap_or_else(|_| panic!("failed to make {} server", config.name)),
+            );
+        }
         match self.language.as_str() {
-            "ts" => {
+            "ts" | "js" => {
                 let path = get_path_from_rootdir("ts-compiler".to_string());
                 Arc::new(
-                    TsServer::make(&path)
-                        .await
-                        .expect("failed to make ts server"),
+                    LangServerPool::spawn(self.ls_pool_size, || {
+                        let mut builder = TsServerBuilder::new(&path)
+                            .timeouts(timeouts.clone())
+                            .transport(self.ls_transport)
+                            .jsdoc(self.language == "js");
+                        if let Some(project) = &self.project {
+                            builder = builder.project(project);
+                        }
+                        if let Some(options) = &self.compiler_options {
+                            builder = builder.compiler_options(options.clone());
+                        }
+                        builder.build()
+                    })
+                    .await
+                    .expect("failed to make ts server"),
+                )
+            }
+            "py" => {
+                let path = get_path_from_rootdir("py-ast".to_string());
+                Arc::new(
+                    LangServerPool::spawn(self.ls_pool_size, || {
+                        let mut builder = PyServerBuilder::new(&path)
+                            .timeouts(timeouts.clone())
+                            .transport(self.ls_transport)
+                            .checker(self.py_checker);
+                        if let Some(project) = &self.project {
+                            builder = builder.project(project);
+                        }
+                        builder.build()
+                    })
+                    .await
+                    .expect("failed to make py server"),
                 )
             }
             _ => {
@@ -173,6 +299,9 @@ impl EvalSpec {
             "santacoder" | "incoder" => {
                 let mut builder = LocalModelClientBuilder::new(self.model.clone());
                 builder = builder.socket_path(endpoint.clone());
+                if let Some(secs) = self.model_timeout {
+                    builder = builder.timeout(Duration::from_secs(secs));
+                }
 
                 Arc::new(
                     builder
@@ -188,7 +317,8 @@ impl EvalSpec {
         };
         let engine = CompletionClientBuilder::new(langserver, model)
             .temperature(self.temperature)
-            .max_type_score(self.max_type_quality);
+            .max_type_score(self.max_type_quality)
+            .problem_weights(self.problem_weights.clone());
         Arc::new(engine.build())
     }
 
@@ -232,8 +362,15 @@ impl EvalSpec {
             enable_stubbing: self.enable_stubbing,
             enable_parser: self.enable_parser,
             enable_checkproblems: self.enable_checkproblems,
+            enable_diagnostics: self.enable_diagnostics,
             depth_limit: self.depth_limit,
+            enable_pruning: self.enable_pruning,
+            merge: self.merge.strategy(),
+            seed: self.seed,
             types: self.types.clone(),
+            project_file: None,
+            checkpoint: None,
+            resume: false,
         }
     }
 
@@ -346,7 +483,13 @@ pub async fn write_results(results: &Vec<ResultElement>, path: &str) {
         let line = result_to_line(result);
         lines.push_str(&line);
     }
-    tokio::fs::write(results_path, lines)
+    // write to a temp file first and then rename it, such that the results file is never
+    // left half-written if we get interrupted
+    let tmp_path = format!("{results_path}.tmp");
+    tokio::fs::write(&tmp_path, lines)
+        .await
+        .unwrap_or_else(|_| pue!("Failed to write results"));
+    tokio::fs::rename(&tmp_path, results_path)
         .await
         .unwrap_or_else(|_| pue!("Failed to write results"));
 }
@@ -372,6 +515,11 @@ fn get_content(element: &serde_json::Value) -> String {
         .to_string()
 }
 
+/// The path of the file of the element relative to the project root, in project mode.
+fn get_project_file(element: &serde_json::Value) -> Option<String> {
+    element["path"].as_str().map(|s| s.to_string())
+}
+
 pub fn get_name(element: &serde_json::Value) -> String {
     element["hexsha"]
         .as_str()
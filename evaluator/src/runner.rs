@@ -1,8 +1,12 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use crate::{check_file_delete, get_content, get_name, write_results, EvalSpec, ResultElement};
+use crate::{
+    check_file_delete, get_content, get_name, get_project_file, write_results, EvalSpec,
+    ResultElement,
+};
 use opentau::{
     completion::{sort_completions, ArcCompletionEngine, CompletionError, TypecheckedCompletion},
+    socket::SocketError,
     tree::stats::ArcTreeAlgoStats,
 };
 use tokio::{
@@ -22,6 +26,9 @@ type MutexEngineSender = Sender<(usize, MutexEngine)>;
 /// The Receiver is ArcMutexed such that it can be read from multiple threads.
 type MutexEngineReceiver = Arc<Mutex<Receiver<(usize, MutexEngine)>>>;
 
+/// How long in-flight requests get to finish when interrupted with Ctrl-C.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
 pub struct RunnerState {
     pub results: Vec<ResultElement>,
     pub eval: EvalSpec,
@@ -114,8 +121,11 @@ impl RunnerState {
             );
 
             let content = get_content(&element);
-            let context =
+            let mut context =
                 eval.make_main_ctx(content.to_string(), mutex_engine.lock().await.clone());
+            if eval.project.is_some() {
+                context.project_file = get_project_file(&element);
+            }
             let (strategy, maybe_arc_stats) = eval.get_strategy();
 
             // wrap in a task so that we can catch panics
@@ -132,6 +142,9 @@ impl RunnerState {
                             "{}: errors = {}, score = {}, fallbacked = {}",
                             i, comp.num_type_errors, comp.score, comp.fallbacked
                         );
+                        if let Some(quality) = &comp.quality {
+                            println!("   quality: {quality}");
+                        }
                     }
 
                     sort_completions(&mut comps);
@@ -143,7 +156,8 @@ impl RunnerState {
                     println!("#### Could not complete ####");
                     (vec![], None)
                 }
-                Ok(Err(CompletionError::Socket(e))) => {
+                // timeouts are recoverable, the engine can be reused
+                Ok(Err(CompletionError::Socket(e))) if !matches!(e, SocketError::Timeout(_)) => {
                     eprintln!("Error while running strategy: {e}");
                     // don't send the engine back to the channel, these errors are typically
                     // irrecoverable
@@ -204,7 +218,19 @@ impl RunnerState {
         let mut times_taken: Vec<u128> = Vec::new();
 
         while !handles.is_empty() {
-            let done_i = self.done_rx.recv().await.unwrap();
+            let done_i = tokio::select! {
+                done_i = self.done_rx.recv() => done_i.unwrap(),
+                _ = tokio::signal::ctrl_c() => {
+                    // the results of the completed elements are already written
+                    eprintln!(
+                        "Interrupted, {} results are saved in {}. Shutting down servers...",
+                        self.results.len(),
+                        self.eval.results_path
+                    );
+                    opentau::socket::shutdown(SHUTDOWN_GRACE).await;
+                    std::process::exit(130);
+                }
+            };
             let handle = handles.remove(&done_i).unwrap();
 
             let TaskResult {
@@ -1,7 +1,8 @@
 import ast
+import re
 from redbaron import RedBaron, redbaron
 
-from typing import Tuple
+from typing import Any, Dict, List, Tuple
 
 _FAKE_TYPE = '_hole_'
 _PLACEHOLDER_TYPE = '_placeholder_'
@@ -35,43 +36,145 @@ def _handle_source(source: RedBaron) -> None:
     for a in assignments:
         _handle_assignment(a)
 
-def _strip_types(source_file: RedBaron) -> ast.AST:
+def _strip_types(source_file: RedBaron) -> str:
     _handle_source(source_file)
-    return ast.parse(source_file.dumps())
+    return source_file.dumps()
 
-def _count_nodes(source_file: RedBaron) -> int:
-    ast_without_comments = _strip_types(source_file)
+def _code_statements(tree: ast.Module) -> List[ast.stmt]:
+    # import statements are counted separately from the rest of the code, such that
+    # added imports are not reported as changed code
+    return [s for s in tree.body if not isinstance(s, (ast.Import, ast.ImportFrom))]
+
+def _count_nodes(tree: ast.Module) -> int:
     count = 1
-    for _ in ast.walk(ast_without_comments):
-        count += 1
+    for stmt in _code_statements(tree):
+        for _ in ast.walk(stmt):
+            count += 1
     return count
 
+def _get_identifiers(tree: ast.Module) -> List[str]:
+    identifiers: List[str] = []
+    for stmt in _code_statements(tree):
+        for node in ast.walk(stmt):
+            if isinstance(node, ast.Name):
+                identifiers.append(node.id)
+            elif isinstance(node, ast.arg):
+                identifiers.append(node.arg)
+            elif isinstance(node, ast.Attribute):
+                identifiers.append(node.attr)
+            elif isinstance(node, (ast.FunctionDef, ast.AsyncFunctionDef, ast.ClassDef)):
+                identifiers.append(node.name)
+    return identifiers
+
+def _get_import_count(source_file: RedBaron) -> int:
+    return len(source_file.find_all('ImportNode')) + len(source_file.find_all('FromImportNode'))
+
+def _annotation_str(annotation) -> str:
+    return '' if annotation is None else annotation.dumps().strip()
+
+def _is_valid_type(annotation: str) -> bool:
+    try:
+        ast.parse(annotation, mode='eval')
+        return True
+    except SyntaxError:
+        return False
+
+def _check_annotation(annotation: str, problems: List[str]) -> int:
+    """Adds the problems of the given annotation, and returns its score."""
+    if annotation == '':
+        return 0
+    if annotation == _FAKE_TYPE:
+        problems.append('NotComplete')
+    elif annotation in ('Any', 'typing.Any'):
+        problems.append('UsedAnyType')
+        return 5
+    elif not _is_valid_type(annotation):
+        problems.append('InvalidTypeSyntax')
+    return 0
+
+def _leaf_types(node: ast.expr, depth: int, leaves: List[Tuple[str, bool]]) -> int:
+    """Collects the leaf types of the given type as (name, has_args) pairs, and returns
+    the depth of the type."""
+    if isinstance(node, ast.Subscript):
+        leaves.append((ast.unparse(node.value), True))
+        return _leaf_types(node.slice, depth + 1, leaves)
+    if isinstance(node, ast.Tuple) or isinstance(node, ast.List):
+        return max([_leaf_types(e, depth, leaves) for e in node.elts], default=depth)
+    if isinstance(node, ast.BinOp):
+        # unions with `|`
+        return max(_leaf_types(node.left, depth, leaves), _leaf_types(node.right, depth, leaves))
+    leaves.append((ast.unparse(node), False))
+    return depth
+
+def _type_quality(annotations: List[str]) -> Dict[str, Any]:
+    quality = {'annotations': len(annotations), 'filled': 0, 'any': 0, 'unknown': 0,
+               'function': 0, 'object': 0, 'depth': 0, 'specificity': 0}
+    leaves: List[Tuple[str, bool]] = []
+    for annotation in annotations:
+        if annotation in ('', _FAKE_TYPE) or not _is_valid_type(annotation):
+            continue
+        quality['filled'] += 1
+        tree = ast.parse(annotation, mode='eval')
+        quality['depth'] = max(quality['depth'], _leaf_types(tree.body, 1, leaves))
+    for name, has_args in leaves:
+        if name in ('Any', 'typing.Any'):
+            quality['any'] += 1
+        elif name == 'object':
+            quality['object'] += 1
+        elif name in ('Callable', 'typing.Callable', 'callable') and not has_args:
+            quality['function'] += 1
+    vague = quality['any'] + quality['function'] + quality['object']
+    if leaves:
+        quality['specificity'] = round((len(leaves) - vague) / len(leaves) * 1000)
+    return quality
+
 # FIXME: recursion depth error
-def check_completed(original_ast: RedBaron, completed_ast: RedBaron) -> Tuple[bool, int]:
-    is_completed: bool = True
+def check_completed(original_ast: RedBaron,
+                    completed_ast: RedBaron) -> Tuple[List[str], int, Dict[str, Any]]:
+    problems: List[str] = []
     score: int = 0
+    annotations: List[str] = []
 
     completed_funcs = completed_ast.find_all('DefNode')
     for func in completed_funcs:
         for arg in func.arguments:
-            if arg.annotation == _FAKE_TYPE:
-                is_completed = False
-            elif arg.annotation == 'Any':
-                score += 5
-
-    if not is_completed:
-        return False, score
+            if arg.target.value != 'self':
+                annotations.append(_annotation_str(arg.annotation))
+            score += _check_annotation(_annotation_str(arg.annotation), problems)
+        # the return type is not scored
+        annotations.append(_annotation_str(func.return_annotation))
+        _check_annotation(_annotation_str(func.return_annotation), problems)
+    quality = _type_quality(annotations)
 
     original_comments = _get_comment_count(original_ast)
     completed_commments = _get_comment_count(completed_ast)
 
     if original_comments != completed_commments:
-        return False, score
+        problems.append('ChangedComments')
+
+    # removing imports is changing the code
+    original_imports = _get_import_count(original_ast)
+    completed_imports = _get_import_count(completed_ast)
+
+    if completed_imports > original_imports:
+        problems.append('AddedImports')
+
+    original_stripped = _strip_types(original_ast)
+    completed_stripped = _strip_types(completed_ast)
+    original_tree = ast.parse(original_stripped)
+    completed_tree = ast.parse(completed_stripped)
 
-    original_count = _count_nodes(original_ast)
-    completed_count = _count_nodes(completed_ast)
+    if _count_nodes(original_tree) != _count_nodes(completed_tree) \
+            or completed_imports < original_imports:
+        problems.append('ChangedCode')
+    elif _get_identifiers(original_tree) != _get_identifiers(completed_tree):
+        problems.append('RenamedIdentifier')
+    elif original_stripped != completed_stripped \
+            and re.sub(r'\s+', '', original_stripped) == re.sub(r'\s+', '', completed_stripped):
+        problems.append('ChangedFormattingOnly')
 
-    return original_count == completed_count, score
+    # NOTE: we report each problem only once
+    return list(dict.fromkeys(problems)), score, quality
 
 
 """ Test
@@ -80,6 +183,6 @@ if __name__ == '__main__':
         original_ast = RedBaron(f_orig.read())
     with open('./__example_typed.py', 'r') as f_comp:
         completed_ast = RedBaron(f_comp.read())
-    status, score = check_completed(original_ast, completed_ast)
-    print(status, score)
+    problems, score, quality = check_completed(original_ast, completed_ast)
+    print(problems, score, quality)
 """
@@ -0,0 +1,74 @@
+import ast
+
+from typing import Dict, List, Optional, Tuple
+
+# the statements that we can show as they are, the others are too big
+_SIMPLE_STMTS = (ast.Expr, ast.Return, ast.Assign, ast.AugAssign, ast.AnnAssign)
+
+
+# finds the name that the inner block declares, or the first name used in it
+def _find_ident(inner_block: ast.Module) -> Optional[str]:
+    for node in ast.walk(inner_block):
+        if isinstance(node, (ast.FunctionDef, ast.AsyncFunctionDef, ast.ClassDef)):
+            return node.name
+        if isinstance(node, ast.Name):
+            return node.id
+    return None
+
+
+def _parent_map(tree: ast.AST) -> Dict[ast.AST, ast.AST]:
+    parents = {}
+    for node in ast.walk(tree):
+        for child in ast.iter_child_nodes(node):
+            parents[child] = node
+    return parents
+
+
+def find_usages(outer_block: str, inner_block: str) -> Tuple[str, int]:
+    """
+    Finds the statements in the outer block where the definition in the inner block is used,
+    and returns them in a comment block, together with the number of usages.
+    """
+    # TODO: do alpha-renaming, like in the typescript server
+    ident = _find_ident(ast.parse(inner_block))
+    if ident is None:
+        return '', 0
+
+    outer = ast.parse(outer_block)
+    parents = _parent_map(outer)
+    usages: List[str] = []
+
+    def is_usage(node: ast.AST) -> bool:
+        return (isinstance(node, ast.Name) and node.id == ident) \
+            or (isinstance(node, ast.Attribute) and node.attr == ident)
+
+    # in order of appearance
+    found = sorted(
+        (n for n in ast.walk(outer) if is_usage(n)),
+        key=lambda n: (n.lineno, n.col_offset),
+    )
+    for node in found:
+        # go up the tree until we find a statement
+        expr = node
+        while not isinstance(parents[expr], ast.stmt):
+            expr = parents[expr]
+        stmt = parents[expr]
+        if isinstance(stmt, _SIMPLE_STMTS):
+            usage = stmt
+        elif isinstance(expr, ast.expr):
+            usage = ast.Expr(value=expr)
+        else:
+            # e.g. used in an annotation or an except clause
+            continue
+
+        printed = ast.unparse(usage)
+        # if we already have this usage, don't add it again
+        if printed not in usages:
+            usages.append(printed)
+
+    if len(usages) == 0:
+        return '', 0
+
+    prelude = f"# Example usages of '{ident}' are shown below:\n"
+    body = ''.join(f'#   {line}\n' for usage in usages for line in usage.splitlines())
+    return prelude + body, len(usages)
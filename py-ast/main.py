@@ -3,39 +3,41 @@ import sys
 import ast
 import time
 import json
-import sched
 import base64
 import socket
 import signal
+import traceback
 from threading import Thread
 from redbaron import RedBaron
-from functools import partial
 
+from tree import make_tree
 from printer import print_source
 from check import check_completed
+from weave import weave_programs
+from typecheck import type_check
+from find_usages import find_usages
 from stub_printer import stub_source
 
-from typing import Any, Union
+from typing import Any, Dict, Union
 
 
-if len(sys.argv) != 2:
-    print(f'usage: [path to socket]')
+if len(sys.argv) not in (3, 4):
+    print('usage: [path to socket | --stdio] [pid of rust proc] [project root (optional)]')
     sys.exit(1)
 
-SERVER_ADDR = sys.argv[2]
+# with --stdio, requests come from stdin and responses go to stdout, instead of a socket
+USE_STDIO = sys.argv[1] == '--stdio'
+SERVER_ADDR = sys.argv[1]
+RUST_PID = int(sys.argv[2])
+# in project mode, the root of the package that typecheck requests with a file are checked in
+PROJECT_ROOT = sys.argv[3] if len(sys.argv) == 4 else None
 BUFF_SIZE = 4096
+END_TOKEN = '??END??'
 
-# checks if in use
-try:
-    os.unlink(SERVER_ADDR)
-except OSError:
-    if os.path.exists(SERVER_ADDR):
-        print(f'{SERVER_ADDR} already exists')
-        sys.exit(1)
-
-rust_pid = int(sys.argv[3])
-
-s = sched.scheduler(time.time, time.sleep)
+STDOUT = sys.stdout
+if USE_STDIO:
+    # stdout is reserved for responses, so all other output has to go to stderr
+    sys.stdout = sys.stderr
 
 # determines if rust proc is still running
 def is_pid_running(pid: int) -> bool:
@@ -46,136 +48,203 @@ def is_pid_running(pid: int) -> bool:
     else:
         return True
 
-# periodically check rust proc
-def run_func(sc):
-    is_pid_running(rust_pid)
-    sc.enter(3, 1, run_func, (sc,))
-
-s.enter(3, 1, run_func, (s,))
-s.run()
+# every 3 seconds, check if the rust proc is still alive, if not we quit
+def watch_rust_pid() -> None:
+    while is_pid_running(RUST_PID):
+        time.sleep(3)
+    cleanup()
+    os._exit(0)
 
-# used to store and close all sockets before exit
-class SocketManager:
-    def __init__(self) -> None:
-        self._sockets = set()
+def cleanup() -> None:
+    if not USE_STDIO and os.path.exists(SERVER_ADDR):
+        os.unlink(SERVER_ADDR)
 
-    def __call__(self, c: socket.socket) -> None:
-        self._sockets.add(c)
+def b64decode(text: str) -> str:
+    return base64.b64decode(text).decode('utf-8')
 
-    def close_all(self) -> None:
-        while len(self._sockets) > 0:
-            s = self._sockets.pop()
-            s.close()
-
-# an unbounded recv
-def recvall(s: socket.socket) -> bytes:
-    data = b''
-    while True:
-        part = s.recv(BUFF_SIZE)
-        data += part
-        if len(part) < BUFF_SIZE:
-            break
-    return data
+def b64encode(text: str) -> str:
+    return base64.b64encode(text.encode('utf-8')).decode('utf-8')
 
 def gen_source_file(decoded_text: str, with_comments: bool = False) -> Union[ast.AST, RedBaron]:
     if with_comments:
         return RedBaron(decoded_text)
     return ast.parse(decoded_text)
 
-def handle_print(decoded_text: str) -> str:
+def handle_print(decoded_text: str, req: Dict[str, Any]) -> str:
     source_file = gen_source_file(decoded_text, with_comments=True)
     assert isinstance(source_file, RedBaron)
-    res = print_source(source_file)
-    # FIXME: figure out output format
-    return json.dumps({"type": "printResponse", "text": res})
+    type_name = req.get('typeName') or '_hole_' # default to _hole_
+    res = print_source(source_file, type_name, req.get('types'))
+    return json.dumps({'type': 'printResponse', 'text': b64encode(res)})
 
-# TODO: implement
 def handle_tree(decoded_text: str) -> str:
-    source_file = gen_source_file(decoded_text)
-    assert isinstance(source_file, ast.AST)
-    res = ...
-    # FIXME: figure out output format
-    return json.dumps({"type": "treeResponse", "text": res})
+    res = make_tree(decoded_text)
+    return json.dumps({'type': 'treeResponse', 'text': b64encode(json.dumps(res))})
 
 def handle_stub(decoded_text: str) -> str:
     source_file = gen_source_file(decoded_text)
     assert isinstance(source_file, ast.AST)
     res = stub_source(source_file)
-    # FIXME: figure out output format
-    return json.dumps({"type": "stubResponse", "text": res})
+    return json.dumps({'type': 'stubResponse', 'text': b64encode(res)})
 
-def handle_check(decoded_text: str, req: Any) -> str:
-    decoded_original = str(base64.b64decode(req.original))
-    original_file = gen_source_file(decoded_original)
-    completed_file = gen_source_file(decoded_text)
+def handle_check(decoded_text: str, req: Dict[str, Any]) -> str:
+    decoded_original = b64decode(req['original'])
+    original_file = gen_source_file(decoded_original, with_comments=True)
+    completed_file = gen_source_file(decoded_text, with_comments=True)
     assert isinstance(original_file, RedBaron)
     assert isinstance(completed_file, RedBaron)
-    # FIXME: fix type return
-    text, score = check_completed(
+    problems, score, quality = check_completed(
         original_ast=original_file,
         completed_ast=completed_file,
     )
-    return json.dumps({"type": "checkResponse", "text": text, "score": score})
-
-# TODO: implement
-def handle_weave() -> str:
-    NotImplemented()
-    return ''
-
-# handles a single client
+    return json.dumps({'type': 'checkResponse', 'problems': problems, 'score': score,
+                       'quality': quality})
+
+def handle_weave(decoded_text: str, req: Dict[str, Any]) -> str:
+    decoded_nettle = b64decode(req['nettle'])
+    level = req.get('level')
+    if level is None:
+        print('Weaving level not specified, defaulting to 0', file=sys.stderr)
+        level = 0
+    res = weave_programs(decoded_text, decoded_nettle, level)
+    return json.dumps({'type': 'weaveResponse', 'text': b64encode(res)})
+
+def handle_usages(decoded_text: str, req: Dict[str, Any]) -> str:
+    decoded_inner = b64decode(req['innerBlock'])
+    usages, num_usages = find_usages(decoded_text, decoded_inner)
+    return json.dumps({
+        'type': 'usagesResponse',
+        'text': b64encode(usages),
+        'numUsages': num_usages,
+    })
+
+def handle_type_check(decoded_text: str, req: Dict[str, Any]) -> str:
+    errors, diagnostics = type_check(
+        decoded_text,
+        req.get('checker', 'mypy'),
+        root=PROJECT_ROOT,
+        file=req.get('file'),
+    )
+    return json.dumps({
+        'type': 'typeCheckResponse',
+        'errors': errors,
+        'diagnostics': diagnostics,
+    })
+
+# the version of the socket protocol that this server speaks
+PROTOCOL_VERSION = 1
+
+def handle_capabilities() -> str:
+    # NOTE: only list the commands that actually work
+    return json.dumps({
+        'type': 'capabilitiesResponse',
+        'commands': [
+            'capabilities',
+            'print',
+            'tree',
+            'stub',
+            'check',
+            'weave',
+            'usages',
+            'typecheck',
+        ],
+        'protocolVersion': PROTOCOL_VERSION,
+        'anyType': 'Any',
+        'holeToken': '_hole_',
+    })
+
+# handles a complete request (without the end token), returns the response
+def handle_request(complete_data: str) -> str:
+    try:
+        # in the format of {cmd: "the-cmd", text: "the-text", ...}
+        req = json.loads(complete_data)
+        decoded_text = b64decode(req['text'])
+        cmd = req['cmd']
+        if cmd == 'capabilities':
+            return handle_capabilities()
+        elif cmd == 'print':
+            return handle_print(decoded_text, req)
+        elif cmd == 'tree':
+            return handle_tree(decoded_text)
+        elif cmd == 'stub':
+            return handle_stub(decoded_text)
+        elif cmd == 'check':
+            return handle_check(decoded_text, req)
+        elif cmd == 'weave':
+            return handle_weave(decoded_text, req)
+        elif cmd == 'usages':
+            return handle_usages(decoded_text, req)
+        elif cmd == 'typecheck':
+            return handle_type_check(decoded_text, req)
+        return json.dumps({'type': 'error', 'message': f'unknown command {cmd}'})
+    # yeah, pretty bad to catch all, but we want this to work no matter what.
+    except Exception as e:
+        return json.dumps({
+            'type': 'error',
+            'message': f'{e}\ntrace: \n{traceback.format_exc()}',
+        })
+
+# handles a single client, which sends one request
 def on_client(c: socket.socket) -> None:
     try:
-        while True:
-            data = recvall(c)
-            req = json.loads(data)
-            decoded_text = str(base64.b64decode(req.text))
-            if req.cmd == 'print':
-                res = handle_print(decoded_text)
-                c.send(bytes(res, 'utf-8'))
-            elif req.cmd == 'tree':
-                res = handle_tree(decoded_text)
-                c.send(bytes(res, 'utf-8'))
-            elif req.cmd == 'stub':
-                res = handle_stub(decoded_text)
-                c.send(bytes(res, 'utf-8'))
-            elif req.cmd == 'check':
-                res = handle_check(decoded_text, req)
-                c.send(bytes(res, 'utf-8'))
-            elif req.cmd == 'weave':
-                res = handle_weave()
-                c.send(bytes(res, 'utf-8'))
-            else:
-                c.send(json.dumps({
-                    'type': 'error',
-                    'message': f'unknown command {req.cmd}'
-                }).encode())
-
+        data = b''
+        while not data.endswith(END_TOKEN.encode()):
+            part = c.recv(BUFF_SIZE)
+            if not part:
+                break
+            data += part
+        complete_data = data.decode('utf-8').removesuffix(END_TOKEN)
+        c.sendall((handle_request(complete_data) + '\n').encode('utf-8'))
     finally:
         c.close()
 
-# listen for clients
-def init_wait(s: socket.socket, sm: SocketManager) -> None:
+def serve_socket() -> None:
+    # checks if in use
+    try:
+        os.unlink(SERVER_ADDR)
+    except OSError:
+        if os.path.exists(SERVER_ADDR):
+            print(f'{SERVER_ADDR} already exists')
+            sys.exit(1)
+
+    sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    sock.bind(SERVER_ADDR)
+    sock.listen()
+    print(f'Listening on {SERVER_ADDR}\n', flush=True)
+    while True:
+        c, _ = sock.accept()
+        Thread(target=on_client, args=(c,), daemon=True).start()
+
+# serves requests from stdin, writing a response per line to stdout. responses end with the
+# end token too, such that output of libraries on stdout is not taken for a response
+def serve_stdio() -> None:
+    STDOUT.write('Listening on stdio\n')
+    STDOUT.flush()
+    end_token = END_TOKEN.encode()
+    pending = b''
     while True:
-        c, _ = s.accept()
-        sm(c)
-        Thread(target=on_client, args=(c,))
+        chunk = os.read(sys.stdin.fileno(), BUFF_SIZE)
+        # the client went away, nothing left to do
+        if not chunk:
+            break
+        pending += chunk
+        while end_token in pending:
+            complete_data, pending = pending.split(end_token, 1)
+            STDOUT.write(handle_request(complete_data.decode('utf-8')) + END_TOKEN + '\n')
+            STDOUT.flush()
 
 # called on exit signal
-def close(_, __, sm: SocketManager) -> None:
-    print(f'Closing {SERVER_ADDR}')
-    sm.close_all()
+def close(*_) -> None:
+    # NOTE: stdout may be closed by now
+    print(f'Closing {"stdio" if USE_STDIO else SERVER_ADDR}', file=sys.stderr)
+    cleanup()
     sys.exit(0)
 
-# init socket manager
-sm = SocketManager()
-sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
-sock.bind(SERVER_ADDR)
-sock.listen(1)
-# store socket for future close
-sm(sock)
-
-# this should work but should be tested
-# other way is to use a lambdas
-signal.signal(signal.SIGINT, partial(close, sm)) # type: ignore
-print(f'Listening on {SERVER_ADDR}\n')
-init_wait(sock, sm)
+signal.signal(signal.SIGINT, close)
+signal.signal(signal.SIGTERM, close)
+Thread(target=watch_rust_pid, daemon=True).start()
+
+if USE_STDIO:
+    serve_stdio()
+else:
+    serve_socket()
@@ -1,38 +1,60 @@
 from redbaron import RedBaron, redbaron
 
+from typing import List, Optional
+
 _FAKE_TYPE = '_hole_'
+_POSITIONS = ['Param', 'Return', 'Destructuring']
 
 
-def _handle_func(func) -> None:
-    _handle_arguments(func)
-    _handle_return(func)
+def _handle_func(func, type_name: str, types: List[str]) -> None:
+    if 'Param' in types:
+        _handle_arguments(func, type_name)
+    if 'Return' in types:
+        _handle_return(func, type_name)
 
-def _handle_arguments(func) -> None:
+def _handle_arguments(func, type_name: str) -> None:
     for arg in func.arguments:
         if arg.annotation is None and arg.target.value != 'self':
-            arg.annotation = _FAKE_TYPE
+            arg.annotation = type_name
 
-def _handle_return(func) -> None:
+def _handle_return(func, type_name: str) -> None:
     if func.return_annotation is None:
-        func.return_annotation = _FAKE_TYPE
+        func.return_annotation = type_name
 
-def _handle_assignment(a) -> None:
+def _handle_assignment(a, type_name: str) -> None:
     if not isinstance(a.target, redbaron.nodes.TupleNode) \
         and a.operator == '' \
         and a.annotation is None:
-        a.annotation = _FAKE_TYPE
+        a.annotation = type_name
+
+def _with_legacy_positions(types: List[str]) -> List[str]:
+    # a list that names none of the positions was written before they were added,
+    # all of them are annotated then. same as `AnnotateType::with_legacy_positions`
+    if any(p in types for p in _POSITIONS):
+        return types
+    return types + _POSITIONS
 
-def _handle_source(source: RedBaron, handle_assignments: bool) -> None:
+def _handle_source(source: RedBaron, type_name: str, types: List[str]) -> None:
+    types = _with_legacy_positions(types)
     funcs = source.find_all('DefNode')
     assignments = source.find_all('AssignmentNode')
     for func in funcs:
-        _handle_func(func)
-    if handle_assignments:
+        is_method = isinstance(func.parent, redbaron.nodes.ClassNode)
+        if ('ClassMethod' if is_method else 'FuncDecl') in types:
+            _handle_func(func, type_name, types)
+    if 'VarDecl' in types:
         for a in assignments:
-            _handle_assignment(a)
+            _handle_assignment(a, type_name)
 
-def print_source(source_file: RedBaron, handle_assignments: bool = False) -> str:
-    _handle_source(source_file, handle_assignments)
+def print_source(
+    source_file: RedBaron,
+    type_name: str = _FAKE_TYPE,
+    types: Optional[List[str]] = None,
+) -> str:
+    # by default, we annotate only functions and methods
+    if types is None:
+        types = ['FuncDecl', 'ClassMethod']
+    _handle_source(source_file, type_name, types)
     out = source_file.dumps()
     return out
 
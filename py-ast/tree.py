@@ -0,0 +1,86 @@
+import ast
+import textwrap
+
+from typing import Any, Dict, List
+
+CodeBlockTree = Dict[str, Any]  # {name: str, code: str, children: List[CodeBlockTree]}
+
+# the fields of compound statements that hold other statements
+_BODY_FIELDS = ('body', 'orelse', 'finalbody')
+
+
+def child_stmts(node: ast.AST) -> List[ast.stmt]:
+    """Returns the statements directly nested in a compound statement (if, for, with, ...)."""
+    stmts = []
+    for field in _BODY_FIELDS:
+        body = getattr(node, field, None)
+        if isinstance(body, list):
+            stmts.extend(s for s in body if isinstance(s, ast.stmt))
+    for handler in getattr(node, 'handlers', []):
+        stmts.extend(handler.body)
+    for case in getattr(node, 'cases', []):
+        stmts.extend(case.body)
+    return stmts
+
+
+def node_source(lines: List[str], node: ast.stmt) -> str:
+    """Returns the dedented source of the given statement, including its decorators."""
+    start = node.lineno
+    for decorator in getattr(node, 'decorator_list', []):
+        start = min(start, decorator.lineno)
+    return textwrap.dedent(''.join(lines[start - 1:node.end_lineno]))
+
+
+# returns true if the given toplevel statement can include a type annotation
+def _is_typable(node: ast.stmt) -> bool:
+    return isinstance(node, ast.AnnAssign) or (
+        isinstance(node, ast.Assign)
+        and len(node.targets) == 1
+        and isinstance(node.targets[0], ast.Name)
+    )
+
+
+def make_tree(source: str) -> CodeBlockTree:
+    lines = source.splitlines(keepends=True)
+    counter = 0
+
+    def symgen(prefix: str) -> str:
+        nonlocal counter
+        counter += 1
+        return f'{prefix}${counter}'
+
+    def traverse(node: ast.stmt, parent: CodeBlockTree, at_top: bool = False) -> None:
+        if isinstance(node, (ast.FunctionDef, ast.AsyncFunctionDef, ast.ClassDef)):
+            this_node = {
+                'name': symgen(node.name),
+                'code': node_source(lines, node),
+                'children': [],
+            }
+            for child in node.body:
+                traverse(child, this_node)
+            parent['children'].append(this_node)
+            return
+
+        if at_top and _is_typable(node):
+            code = node_source(lines, node)
+            # we aggregate all adjacent toplevel nodes into one
+            if len(parent['children']) > 0:
+                last_node = parent['children'][-1]
+                if last_node['name'].startswith('topnode'):
+                    last_node['code'] += '\n' + code
+                    return
+            parent['children'].append({
+                'name': symgen('topnode'),
+                'code': code,
+                'children': [],
+            })
+            return
+
+        for child in child_stmts(node):
+            traverse(child, parent)
+
+    # NOTE: the & is to make sure we don't have a name collision with some other function
+    tree: CodeBlockTree = {'name': '&root$', 'code': source, 'children': []}
+    for node in ast.parse(source).body:
+        traverse(node, tree, at_top=True)
+    return tree
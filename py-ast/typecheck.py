@@ -0,0 +1,167 @@
+import os
+import re
+import json
+import tempfile
+import threading
+import subprocess
+
+from typing import Any, Dict, List, Optional, Tuple
+
+# shared between the runs of a thread, such that mypy doesn't re-analyze the standard library
+# every time. each client is handled on its own thread, and concurrent runs can't share a cache.
+_MYPY_CACHE_DIR = os.path.join(tempfile.gettempdir(), 'opentau-mypy-cache')
+
+
+def _mypy_cache_dir() -> str:
+    return os.path.join(_MYPY_CACHE_DIR, f'{os.getpid()}-{threading.get_ident()}')
+
+# e.g. `comp.py:3:5:3:10: error: Incompatible types in assignment  [assignment]`
+_MYPY_LINE = re.compile(
+    r'^(?P<file>[^:]+):(?P<line>\d+):(?P<col>\d+)(?::(?P<end_line>\d+):(?P<end_col>\d+))?: '
+    r'(?P<severity>error|warning|note): (?P<message>.*?)(?:  \[(?P<code>[\w-]+)\])?$'
+)
+
+_SEVERITY_TO_CATEGORY = {
+    'error': 'error',
+    'warning': 'warning',
+    'note': 'message',
+    'information': 'message',
+}
+
+# a diagnostic in the format of the typecheck response. lines and columns are 1-based.
+Diagnostic = Dict[str, Any]
+
+
+def _make_diagnostic(
+    span: Optional[Tuple[int, int, int, int]],
+    code: Optional[str],
+    message: str,
+    severity: str,
+) -> Diagnostic:
+    return {
+        'span': None if span is None else {
+            'startLine': span[0],
+            'startCol': span[1],
+            'endLine': span[2],
+            'endCol': span[3],
+        },
+        'code': code or '',
+        'message': message,
+        'category': _SEVERITY_TO_CATEGORY.get(severity, 'error'),
+    }
+
+
+def _run_mypy(path: str, cwd: Optional[str] = None, shadow: Optional[str] = None) -> List[Diagnostic]:
+    """
+    Runs mypy on the given path. With `shadow`, the contents of that file are used in place
+    of the ones of `path`, and only the diagnostics of `path` are kept.
+    """
+    cmd = [
+        'mypy', path,
+        '--cache-dir', _mypy_cache_dir(),
+        '--ignore-missing-imports',
+        '--no-error-summary',
+        '--hide-error-context',
+        '--no-color-output',
+        '--show-column-numbers',
+        '--show-error-end',
+        '--show-error-codes',
+    ]
+    if shadow is not None:
+        cmd += ['--shadow-file', path, shadow]
+    sp = subprocess.run(cmd, capture_output=True, text=True, cwd=cwd)
+    # 0 is no errors, 1 is type errors, anything else is mypy failing
+    if sp.returncode not in (0, 1):
+        raise Exception(sp.stderr or sp.stdout)
+    diagnostics = []
+    for line in sp.stdout.splitlines():
+        m = _MYPY_LINE.match(line)
+        if m is None:
+            continue
+        if shadow is not None and os.path.normpath(m['file']) != os.path.normpath(path):
+            continue
+        start = (int(m['line']), int(m['col']))
+        end = (int(m['end_line']), int(m['end_col'])) if m['end_line'] else start
+        diagnostics.append(_make_diagnostic((*start, *end), m['code'], m['message'], m['severity']))
+    return diagnostics
+
+
+def _run_pyright(path: str, cwd: Optional[str] = None) -> List[Diagnostic]:
+    cmd = ['pyright', '--outputjson', path]
+    sp = subprocess.run(cmd, capture_output=True, text=True, cwd=cwd)
+    # 0 is no errors, 1 is type errors, anything else is pyright failing
+    if sp.returncode not in (0, 1):
+        raise Exception(sp.stderr or sp.stdout)
+    diagnostics = []
+    target = os.path.abspath(os.path.join(cwd or '', path))
+    for d in json.loads(sp.stdout)['generalDiagnostics']:
+        # in a project, pyright may also report on the files that are imported
+        if 'file' in d and os.path.abspath(d['file']) != target:
+            continue
+        span = None
+        if 'range' in d:
+            # pyright's positions are 0-based
+            start, end = d['range']['start'], d['range']['end']
+            span = (start['line'] + 1, start['character'] + 1, end['line'] + 1, end['character'] + 1)
+        diagnostics.append(_make_diagnostic(span, d.get('rule'), d['message'], d['severity']))
+    return diagnostics
+
+
+def _type_check_in_project(code: str, checker: str, root: str, file: str) -> List[Diagnostic]:
+    """
+    Type checks the given code as a replacement of `file`, relative to the project `root`.
+    The checker runs from the root, such that imports of the other modules resolve.
+    """
+    if checker == 'mypy':
+        with tempfile.TemporaryDirectory() as tmp_dir:
+            shadow = os.path.join(tmp_dir, os.path.basename(file))
+            with open(shadow, 'w') as f:
+                f.write(code)
+            return _run_mypy(file, cwd=root, shadow=shadow)
+    elif checker == 'pyright':
+        # pyright has no shadow files, so the candidate is written next to the original,
+        # such that relative imports resolve the same way. the name is unique per request,
+        # as candidates of the same file are checked concurrently.
+        dirname, basename = os.path.split(file)
+        with tempfile.NamedTemporaryFile(
+            'w',
+            dir=os.path.join(root, dirname),
+            prefix='.opentau_',
+            suffix=f'_{basename}',
+            delete=False,
+        ) as f:
+            f.write(code)
+        try:
+            return _run_pyright(os.path.relpath(f.name, root), cwd=root)
+        finally:
+            os.remove(f.name)
+    else:
+        raise ValueError(f'unknown type checker {checker}')
+
+
+def type_check(
+    code: str,
+    checker: str,
+    root: Optional[str] = None,
+    file: Optional[str] = None,
+) -> Tuple[int, List[Diagnostic]]:
+    """
+    Type checks the given code with the given checker, returns the number of errors
+    and all the diagnostics. If both `root` and `file` are given, the code is checked
+    as a replacement of `file` of the project at `root`.
+    """
+    if root is not None and file is not None:
+        diagnostics = _type_check_in_project(code, checker, root, file)
+    else:
+        with tempfile.TemporaryDirectory() as tmp_dir:
+            path = os.path.join(tmp_dir, 'comp.py')
+            with open(path, 'w') as f:
+                f.write(code)
+            if checker == 'mypy':
+                diagnostics = _run_mypy(path)
+            elif checker == 'pyright':
+                diagnostics = _run_pyright(path)
+            else:
+                raise ValueError(f'unknown type checker {checker}')
+    errors = sum(1 for d in diagnostics if d['category'] == 'error')
+    return errors, diagnostics
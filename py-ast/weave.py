@@ -0,0 +1,178 @@
+import io
+import ast
+import bisect
+import tokenize
+
+from tree import child_stmts
+
+from typing import Dict, List, Optional, Tuple, Union
+
+_FAKE_TYPE = '_hole_'
+
+# the types of a function: (param name -> annotation, return annotation)
+FuncTypes = Tuple[Dict[str, Optional[str]], Optional[str]]
+TypeMap = Dict[str, Union[str, FuncTypes]]
+FuncNode = Union[ast.FunctionDef, ast.AsyncFunctionDef]
+
+
+def _all_args(func: FuncNode) -> List[ast.arg]:
+    args = func.args
+    res = [*args.posonlyargs, *args.args, *args.kwonlyargs]
+    if args.vararg:
+        res.append(args.vararg)
+    if args.kwarg:
+        res.append(args.kwarg)
+    return res
+
+
+# the name used in the type map for the target of an assignment, e.g. `x` or `self.x`
+def _target_name(target: ast.expr) -> Optional[str]:
+    if isinstance(target, ast.Name):
+        return target.id
+    if isinstance(target, ast.Attribute) and isinstance(target.value, ast.Name):
+        return f'{target.value.id}.{target.attr}'
+    return None
+
+
+def _build_type_map(source: str) -> TypeMap:
+    type_map: TypeMap = {}
+
+    def annotation(node: Optional[ast.expr]) -> Optional[str]:
+        if node is None:
+            return None
+        ann = ast.get_source_segment(source, node)
+        # holes that were not filled are not worth transplanting
+        if ann is None or _FAKE_TYPE in ann:
+            return None
+        return ann
+
+    def build(nodes: List[ast.stmt], scope: str) -> None:
+        for node in nodes:
+            if isinstance(node, (ast.FunctionDef, ast.AsyncFunctionDef)):
+                params = {arg.arg: annotation(arg.annotation) for arg in _all_args(node)}
+                type_map[scope + node.name] = (params, annotation(node.returns))
+                build(node.body, scope + node.name + '$')
+            elif isinstance(node, ast.ClassDef):
+                build(node.body, scope + node.name + '$')
+            elif isinstance(node, ast.AnnAssign):
+                name = _target_name(node.target)
+                ann = annotation(node.annotation)
+                if name is not None and ann is not None:
+                    type_map[scope + name] = ann
+            else:
+                build(child_stmts(node), scope)
+
+    build(ast.parse(source).body, '')
+    return type_map
+
+
+class _Source:
+    """Converts the positions given by ast and tokenize into offsets of the source string."""
+
+    def __init__(self, source: str) -> None:
+        self.source = source
+        self.lines = source.splitlines(keepends=True)
+        self.line_starts = [0]
+        for line in self.lines:
+            self.line_starts.append(self.line_starts[-1] + len(line))
+        self.tokens = list(tokenize.generate_tokens(io.StringIO(source).readline))
+        self.token_starts = [self.offset(*t.start) for t in self.tokens]
+
+    # NOTE: tokenize gives columns in characters
+    def offset(self, lineno: int, col: int) -> int:
+        return self.line_starts[lineno - 1] + col
+
+    # NOTE: ast gives columns in utf-8 bytes
+    def node_start(self, node: ast.AST) -> int:
+        line = self.lines[node.lineno - 1]
+        return self.offset(node.lineno, len(line.encode()[:node.col_offset].decode()))
+
+    def node_end(self, node: ast.AST) -> int:
+        line = self.lines[node.end_lineno - 1]
+        return self.offset(node.end_lineno, len(line.encode()[:node.end_col_offset].decode()))
+
+    # finds the colon that ends the header of the given function definition
+    def header_colon(self, func: FuncNode) -> int:
+        depth = 0
+        i = bisect.bisect_left(self.token_starts, self.node_start(func))
+        for tok in self.tokens[i:]:
+            if tok.type != tokenize.OP:
+                continue
+            if tok.string in '([{':
+                depth += 1
+            elif tok.string in ')]}':
+                depth -= 1
+            elif tok.string == ':' and depth == 0:
+                return self.offset(*tok.start)
+        raise ValueError(f'could not find the end of the header of {func.name}')
+
+
+def weave_programs(target: str, nettle: str, nettle_level: int) -> str:
+    """
+    Transplants the type annotations of the `nettle` code into the `target` code. Definitions
+    are matched by their name and the names of the functions and classes they are nested in.
+    The `nettle_level` is the level of the nettle in the tree relative to the target, 0 being
+    the target itself. Unlike reprinting the ast, this keeps the formatting and comments of
+    the target intact.
+    """
+    type_map = _build_type_map(nettle)
+    src = _Source(target)
+    edits: List[Tuple[int, int, str]] = []  # (start, end, replacement)
+
+    def replace(node: ast.expr, text: str) -> None:
+        edits.append((src.node_start(node), src.node_end(node), text))
+
+    def insert(at: int, text: str) -> None:
+        edits.append((at, at, text))
+
+    def weave_func(func: FuncNode, types: FuncTypes) -> None:
+        params, returns = types
+        for arg in _all_args(func):
+            ann = params.get(arg.arg)
+            if ann is None:
+                continue
+            end = src.node_end(arg)
+            if arg.annotation is not None:
+                replace(arg.annotation, ann)
+            elif src.source.startswith('=', end):
+                # a default value without spaces, `x=1` becomes `x: int = 1`
+                edits.append((end, end + 1, f': {ann} = '))
+            else:
+                insert(end, f': {ann}')
+        if returns is not None:
+            if func.returns is not None:
+                replace(func.returns, returns)
+            else:
+                insert(src.header_colon(func), f' -> {returns}')
+
+    def weave(nodes: List[ast.stmt], scope: str, level: int) -> None:
+        for node in nodes:
+            if isinstance(node, (ast.FunctionDef, ast.AsyncFunctionDef, ast.ClassDef)):
+                if not isinstance(node, ast.ClassDef):
+                    types = type_map.get(scope + node.name)
+                    if isinstance(types, tuple):
+                        weave_func(node, types)
+                # we change the scope, if we are at the nettle level
+                if level >= nettle_level:
+                    weave(node.body, scope + node.name + '$', level + 1)
+                else:
+                    weave(node.body, scope, level + 1)
+            elif isinstance(node, ast.Assign):
+                name = _target_name(node.targets[0]) if len(node.targets) == 1 else None
+                ann = type_map.get(scope + name) if name is not None else None
+                if isinstance(ann, str):
+                    insert(src.node_end(node.targets[0]), f': {ann}')
+            elif isinstance(node, ast.AnnAssign):
+                name = _target_name(node.target)
+                ann = type_map.get(scope + name) if name is not None else None
+                if isinstance(ann, str):
+                    replace(node.annotation, ann)
+            else:
+                weave(child_stmts(node), scope, level)
+
+    weave(ast.parse(target).body, '', 0)
+
+    res = target
+    for start, end, text in sorted(edits, reverse=True):
+        res = res[:start] + text + res[end:]
+    return res
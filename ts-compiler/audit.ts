@@ -0,0 +1,152 @@
+import ts from "typescript";
+import { allTypes, AnnotateType } from "./printer";
+import { codePrinter, typeTraversal } from "./utils";
+
+// from langserver.rs in the Rust client
+type TypeRelation = "Same" | "Narrower" | "Wider" | "Incompatible" | "Unknown";
+
+// how an inferred annotation relates to the one that was written in the original code
+type AnnotationAudit = {
+  location: string;
+  line: number;
+  declared: string;
+  inferred: string;
+  relation: TypeRelation;
+};
+
+// the prefix of the type aliases that are appended to the code to compare the types
+const PROBE_PREFIX = "__audit_";
+
+// removes the existing types of the given kinds, the inverse of `insertHoles`
+export const stripTypes = (
+  sourceFile: ts.SourceFile,
+  annotateTypes: AnnotateType[] = allTypes
+) => {
+  sourceFile.forEachChild((child) => {
+    typeTraversal(child, () => undefined, annotateTypes);
+  });
+};
+
+// collects the type slots of the given kinds, in the order of the traversal
+const collectSlots = (
+  sourceFile: ts.SourceFile,
+  annotateTypes: AnnotateType[]
+): [ts.TypeNode | undefined, ts.Node][] => {
+  const slots: [ts.TypeNode | undefined, ts.Node][] = [];
+  sourceFile.forEachChild((child) => {
+    typeTraversal(
+      child,
+      (ty, node) => {
+        slots.push([ty, node]);
+        return ty;
+      },
+      annotateTypes
+    );
+  });
+  return slots;
+};
+
+// a readable name for the slot, e.g. `x` for a parameter or `foo (return)` for a function
+const slotName = (node: ts.Node, sourceFile: ts.SourceFile): string => {
+  const target =
+    ts.isCatchClause(node) && node.variableDeclaration
+      ? node.variableDeclaration
+      : node;
+  const name = ts.getNameOfDeclaration(target as ts.Declaration);
+  const text = name ? name.getText(sourceFile) : "<anonymous>";
+  return ts.isFunctionLike(target) ? `${text} (return)` : text;
+};
+
+const printType = (ty: ts.TypeNode, sourceFile: ts.SourceFile): string =>
+  codePrinter
+    .printNode(ts.EmitHint.Unspecified, ty, sourceFile)
+    .replace(/\s*\n\s*/g, " ");
+
+// a type that resolves to the relation of `inferred` to `declared` as a string literal.
+// any is assignable both ways, so it's compared separately.
+const probeType = (declared: string, inferred: string): string => {
+  const isAny = (ty: string) => `0 extends (1 & (${ty}))`;
+  const assignable = (from: string, to: string) => `[${from}] extends [${to}]`;
+  return (
+    `${isAny(inferred)} ? (${isAny(declared)} ? "Same" : "Wider") : ` +
+    `${isAny(declared)} ? "Narrower" : ` +
+    `${assignable(inferred, declared)} ? ` +
+    `(${assignable(declared, inferred)} ? "Same" : "Narrower") : ` +
+    `(${assignable(declared, inferred)} ? "Wider" : "Incompatible")`
+  );
+};
+
+// pairs the annotations of the original code with the ones of the completed code, which
+// must be the same code with different types. returns the audits without their relation,
+// and the probe that has to be appended to the completed code to find the relations.
+export const pairAnnotations = (
+  original: ts.SourceFile,
+  completed: ts.SourceFile,
+  annotateTypes: AnnotateType[] = allTypes
+): [Omit<AnnotationAudit, "relation">[], string] => {
+  const originalSlots = collectSlots(original, annotateTypes);
+  const completedSlots = collectSlots(completed, annotateTypes);
+  if (originalSlots.length !== completedSlots.length) {
+    throw new Error(
+      `the completed code has ${completedSlots.length} type slots, the original has ${originalSlots.length}`
+    );
+  }
+
+  const audits: Omit<AnnotationAudit, "relation">[] = [];
+  let probe = "";
+  originalSlots.forEach(([declaredTy, node], i) => {
+    const inferredTy = completedSlots[i][0];
+    // only the annotations that were written in the original are audited
+    if (!declaredTy || !inferredTy) {
+      return;
+    }
+    const declared = printType(declaredTy, original);
+    const inferred = printType(inferredTy, completed);
+    const start = node.getStart(original);
+    audits.push({
+      location: slotName(node, original),
+      line: original.getLineAndCharacterOfPosition(start).line + 1,
+      declared,
+      inferred,
+    });
+    probe += `\ntype ${PROBE_PREFIX}${audits.length - 1} = ${probeType(
+      declared,
+      inferred
+    )};`;
+  });
+  return [audits, probe];
+};
+
+// reads the relations of the probe that was appended to the code of the given program.
+// types that can't be resolved at the top level, e.g. type parameters, are `Unknown`.
+export const readRelations = (
+  program: ts.Program,
+  sourceFile: ts.SourceFile,
+  audits: Omit<AnnotationAudit, "relation">[]
+): AnnotationAudit[] => {
+  const checker = program.getTypeChecker();
+  const diagnostics = program.getSemanticDiagnostics(sourceFile);
+  const relations = new Map<string, TypeRelation>();
+  sourceFile.statements.forEach((stmt) => {
+    if (
+      !ts.isTypeAliasDeclaration(stmt) ||
+      !stmt.name.text.startsWith(PROBE_PREFIX)
+    ) {
+      return;
+    }
+    const hasErrors = diagnostics.some(
+      (d) => d.start !== undefined && d.start >= stmt.pos && d.start < stmt.end
+    );
+    const ty = checker.getTypeFromTypeNode(stmt.type);
+    const relation =
+      !hasErrors && ty.flags & ts.TypeFlags.StringLiteral
+        ? ((ty as ts.StringLiteralType).value as TypeRelation)
+        : "Unknown";
+    relations.set(stmt.name.text, relation);
+  });
+
+  return audits.map((audit, i) => ({
+    ...audit,
+    relation: relations.get(`${PROBE_PREFIX}${i}`) ?? "Unknown",
+  }));
+};
@@ -34,16 +34,127 @@ const get_comment_count = (s: ts.SourceFile): number => {
   return count;
 };
 
-type CheckProblem = "NotComplete" | "ChangedCode" | "ChangedComments";
+// import statements are counted separately from the rest of the code, such that added
+// imports are not reported as changed code
+const isImport = (node: ts.Node): boolean =>
+  ts.isImportDeclaration(node) || ts.isImportEqualsDeclaration(node);
+
+// counts the nodes of the source file, without the import statements
+const count_code_nodes = (s: ts.SourceFile): number => {
+  let count = 1;
+  s.forEachChild((c) => {
+    if (!isImport(c)) {
+      count += count_nodes(c);
+    }
+  });
+  return count;
+};
+
+// counts the import statements and the import types (e.g. `import("./foo").Foo`)
+const count_imports = (s: ts.SourceFile): number => {
+  let count = 0;
+  const visit = (node: ts.Node) => {
+    if (isImport(node) || ts.isImportTypeNode(node)) {
+      count += 1;
+    }
+    node.forEachChild(visit);
+  };
+  s.forEachChild(visit);
+  return count;
+};
+
+// gets the identifiers of the source file in order, without the import statements
+const get_identifiers = (s: ts.SourceFile): string[] => {
+  const identifiers: string[] = [];
+  const visit = (node: ts.Node) => {
+    if (ts.isIdentifier(node)) {
+      identifiers.push(node.text);
+    }
+    node.forEachChild(visit);
+  };
+  s.forEachChild((c) => {
+    if (!isImport(c)) {
+      visit(c);
+    }
+  });
+  return identifiers;
+};
+
+// gets the text of the source file with the annotated types cut out
+const get_text_without_types = (s: ts.SourceFile): string => {
+  const ranges: [number, number][] = [];
+  s.forEachChild((child) => {
+    typeTraversal(child, (ty) => {
+      if (ty && ty.pos >= 0) {
+        ranges.push([ty.pos, ty.end]);
+      }
+      return ty;
+    });
+  });
+  ranges.sort((a, b) => a[0] - b[0]);
+
+  const text = s.getFullText();
+  let res = "";
+  let last = 0;
+  ranges.forEach(([pos, end]) => {
+    // nested types are already cut out with their parent
+    if (pos < last) {
+      return;
+    }
+    res += text.slice(last, pos);
+    last = end;
+  });
+  return res + text.slice(last);
+};
+
+// the number of syntax errors the parser found in the source file
+const get_parse_error_count = (s: ts.SourceFile): number =>
+  ((s as any).parseDiagnostics ?? []).length;
+
+type CheckProblem =
+  | "NotComplete"
+  | "ChangedCode"
+  | "ChangedComments"
+  | "UsedAnyType"
+  | "RenamedIdentifier"
+  | "AddedImports"
+  | "ChangedFormattingOnly"
+  | "InvalidTypeSyntax";
+
+// the breakdown of the score, counted over the leaf types of the annotations
+export type TypeQuality = {
+  annotations: number;
+  filled: number;
+  any: number;
+  unknown: number;
+  function: number;
+  object: number;
+  depth: number;
+  // the share of leaf types that are not any, unknown, Function or object, in [0, 1000]
+  specificity: number;
+};
 
 export const checkCompleted = (
   original: ts.SourceFile,
   completed: ts.SourceFile,
   completedChecker: ts.TypeChecker
-): [CheckProblem[], number] => {
+): [CheckProblem[], number, TypeQuality] => {
   let isCompleted = true;
+  let usedAny = false;
   let problems: CheckProblem[] = [];
   let rawScore = 0;
+  const quality: TypeQuality = {
+    annotations: 0,
+    filled: 0,
+    any: 0,
+    unknown: 0,
+    function: 0,
+    object: 0,
+    depth: 0,
+    specificity: 0,
+  };
+  // the number of leaf types, for the specificity
+  let numLeafTypes = 0;
 
   // this is the number of type nodes (only leaf nodes)
   // encountered in the completed code. used for the final score
@@ -61,15 +172,22 @@ export const checkCompleted = (
   // checks completed types and scores them
   completed.forEachChild((toplevelChild) => {
     typeTraversal(toplevelChild, (ty, child) => {
-      // means that the model removed the type, or could be a vardecl-bound function
+      // means that the model removed the type, or could be a vardecl-bound function.
+      // type parameters and catch clauses are fine without a type
       if (!ty) {
-        if (!isVarDeclBoundFunction(child)) {
+        if (
+          !isVarDeclBoundFunction(child) &&
+          !ts.isTypeParameterDeclaration(child) &&
+          !ts.isCatchClause(child)
+        ) {
           rawScore += 0.5;
           numTypeNodes += 1;
+          quality.annotations += 1;
           return;
         }
         return ty;
       }
+      quality.annotations += 1;
 
       const tsType = completedChecker.getTypeFromTypeNode(ty);
 
@@ -88,8 +206,11 @@ export const checkCompleted = (
         isCompleted = false;
         return ty;
       }
+      quality.filled += 1;
 
-      const checkType = (tsType: ts.Type) => {
+      // depth is the nesting of type arguments and function signatures
+      const checkType = (tsType: ts.Type, depth: number = 1) => {
+        quality.depth = Math.max(quality.depth, depth);
         const typeFlags = tsType.getFlags();
         if (typeFlags & ts.TypeFlags.Any) {
           // any is a special case, because it is a "catch all" type, we may need to check if it's
@@ -111,12 +232,17 @@ export const checkCompleted = (
 
           if (printed === "any" && !isUnresolved(tsType)) {
             rawScore += 0.5;
+            usedAny = true;
+            quality.any += 1;
           }
           numTypeNodes += 1;
+          numLeafTypes += 1;
         } else if (typeFlags & ts.TypeFlags.Unknown) {
           // console.log("got unknown");
           rawScore += 1;
           numTypeNodes += 1;
+          numLeafTypes += 1;
+          quality.unknown += 1;
         } else if (
           typeFlags & ts.TypeFlags.Undefined ||
           typeFlags & ts.TypeFlags.Null
@@ -124,6 +250,7 @@ export const checkCompleted = (
           // console.log("got undefined or null");
           rawScore += 0.2;
           numTypeNodes += 1;
+          numLeafTypes += 1;
         } else if (typeFlags & ts.TypeFlags.Object) {
           // we may have type arguments, so we need to check those
           const objType = tsType as ts.ObjectType;
@@ -133,10 +260,11 @@ export const checkCompleted = (
             const typeArgs = refType.typeArguments;
             if (typeArgs) {
               typeArgs.forEach((arg) => {
-                checkType(arg);
+                checkType(arg, depth + 1);
               });
             }
             numTypeNodes += 1;
+            numLeafTypes += 1;
           } else if (objType.objectFlags & ts.ObjectFlags.Interface) {
             // get symbol, if it's Function, we need to give a bad score, as it is a catch all
             // interface for functions
@@ -145,14 +273,25 @@ export const checkCompleted = (
               const name = sym.getName();
               if (name === "Function") {
                 rawScore += 0.5;
+                quality.function += 1;
               }
             }
             numTypeNodes += 1;
+            numLeafTypes += 1;
           } else if (objType.objectFlags & ts.ObjectFlags.Anonymous) {
             // console.log("got anonymous");
             // anonymous objects are usually functions, so we need to check return and argument types
             const funcType = objType as ts.ObjectType;
             const callSignatures = funcType.getCallSignatures();
+            if (
+              callSignatures.length === 0 &&
+              funcType.getProperties().length === 0
+            ) {
+              // the empty object type `{}`, which is as vague as `object`
+              quality.object += 1;
+              numLeafTypes += 1;
+              return;
+            }
             try {
               const returnType = callSignatures[0].getReturnType();
               const args = callSignatures[0]
@@ -160,9 +299,9 @@ export const checkCompleted = (
                 .map((sym) =>
                   completedChecker.getTypeOfSymbolAtLocation(sym, child)
                 );
-              checkType(returnType);
+              checkType(returnType, depth + 1);
               args.forEach((arg) => {
-                checkType(arg);
+                checkType(arg, depth + 1);
               });
             } catch (e) {
               console.log("error: ", e);
@@ -172,11 +311,16 @@ export const checkCompleted = (
           // console.log("got union");
           const unionType = tsType as ts.UnionType;
           unionType.types.forEach((arg) => {
-            checkType(arg);
+            checkType(arg, depth);
           });
         } else {
           // NOTE: in our abstract interpretation, this is considered a leaf node
           numTypeNodes += 1;
+          numLeafTypes += 1;
+          if (typeFlags & ts.TypeFlags.NonPrimitive) {
+            // the `object` type
+            quality.object += 1;
+          }
         }
       };
 
@@ -190,6 +334,15 @@ export const checkCompleted = (
     problems.push("NotComplete");
   }
 
+  if (usedAny) {
+    problems.push("UsedAnyType");
+  }
+
+  // the model only writes types, so new syntax errors are in the types
+  if (get_parse_error_count(completed) > get_parse_error_count(original)) {
+    problems.push("InvalidTypeSyntax");
+  }
+
   // now, strip types out of the original and completed
   const originalStripped = getDeepMutableClone(original);
   const completedStripped = getDeepMutableClone(completed);
@@ -202,6 +355,14 @@ export const checkCompleted = (
     problems.push("ChangedComments");
   }
 
+  // check if it added imports, removing imports is changing the code
+  const originalImports = count_imports(original);
+  const completedImports = count_imports(completed);
+
+  if (completedImports > originalImports) {
+    problems.push("AddedImports");
+  }
+
   // now strip types
   const fake = createFakeType("bleh");
   const stripTypes = (_: ts.TypeNode | undefined) => fake;
@@ -214,12 +375,37 @@ export const checkCompleted = (
   });
 
   // now, compare the number of nodes in the original and completed
-  const originalCount = count_nodes(originalStripped);
-  const completedCount = count_nodes(completedStripped);
+  const originalCount = count_code_nodes(originalStripped);
+  const completedCount = count_code_nodes(completedStripped);
 
-  if (originalCount !== completedCount) {
+  if (originalCount !== completedCount || completedImports < originalImports) {
     problems.push("ChangedCode");
+  } else {
+    // same shape of code, but the names may have changed
+    const originalIds = get_identifiers(originalStripped);
+    const completedIds = get_identifiers(completedStripped);
+    if (originalIds.some((id, i) => id !== completedIds[i])) {
+      problems.push("RenamedIdentifier");
+    } else {
+      // same code, but the layout may have changed
+      const originalText = get_text_without_types(original);
+      const completedText = get_text_without_types(completed);
+      const noWhitespace = (text: string) => text.replace(/\s+/g, "");
+      if (
+        originalText !== completedText &&
+        noWhitespace(originalText) === noWhitespace(completedText)
+      ) {
+        problems.push("ChangedFormattingOnly");
+      }
+    }
   }
 
-  return [problems, computeScore(rawScore, numTypeNodes)];
+  const numVague =
+    quality.any + quality.unknown + quality.function + quality.object;
+  quality.specificity =
+    numLeafTypes === 0
+      ? 0
+      : Math.round(((numLeafTypes - numVague) / numLeafTypes) * 1000);
+
+  return [problems, computeScore(rawScore, numTypeNodes), quality];
 };
@@ -0,0 +1,233 @@
+import ts from "typescript";
+import { codePrinter, typeTraversal } from "./utils";
+
+// the tags that carry types, these are regenerated from the inline types when lowering
+const TYPE_TAGS = [
+  "param",
+  "arg",
+  "argument",
+  "returns",
+  "return",
+  "type",
+  "template",
+];
+
+// lifts the types of the JSDoc comments into the inline type slots of the AST, such that
+// the rest of the compiler infrastructure (which only looks at inline types) can be used
+// on JavaScript files. the source file must be parsed with parent nodes.
+// the JSDoc type nodes are moved as they are, so they can be given to the type checker of
+// the program that the source file belongs to.
+export const liftJsdocTypes = (sourceFile: ts.SourceFile) => {
+  sourceFile.forEachChild((child) => {
+    typeTraversal(child, (ty, node) => {
+      if (ty) {
+        return ty;
+      }
+      if (ts.isParameter(node)) {
+        return ts.getJSDocType(node);
+      } else if (ts.isFunctionLike(node)) {
+        return ts.getJSDocReturnType(node);
+      } else if (
+        ts.isVariableDeclaration(node) ||
+        ts.isPropertyDeclaration(node)
+      ) {
+        return ts.getJSDocType(node);
+      }
+      return ty;
+    });
+  });
+};
+
+// prints the given type on a single line, such that it can be put in a tag
+const printType = (ty: ts.TypeNode, sourceFile: ts.SourceFile): string =>
+  codePrinter
+    .printNode(ts.EmitHint.Unspecified, ty, sourceFile)
+    .replace(/\s*\n\s*/g, " ");
+
+// makes the @param, @returns and @template tags of the given function, and removes
+// its inline types.
+const functionTags = (
+  func: ts.SignatureDeclaration,
+  sourceFile: ts.SourceFile
+): string[] => {
+  const tags: string[] = [];
+  if (func.typeParameters) {
+    func.typeParameters.forEach((tp) => {
+      const constraint = tp.constraint
+        ? `{${printType(tp.constraint, sourceFile)}} `
+        : "";
+      tags.push(`@template ${constraint}${tp.name.text}`);
+    });
+    func.typeParameters = undefined;
+  }
+  func.parameters.forEach((param) => {
+    // destructuring patterns have no name to refer to, so their types are dropped
+    if (param.type && ts.isIdentifier(param.name)) {
+      let ty = printType(param.type, sourceFile);
+      if (param.dotDotDotToken && ts.isArrayTypeNode(param.type)) {
+        ty = "..." + printType(param.type.elementType, sourceFile);
+      }
+      const name = param.questionToken
+        ? `[${param.name.text}]`
+        : param.name.text;
+      tags.push(`@param {${ty}} ${name}`);
+    }
+    param.type = undefined;
+  });
+  if (func.type) {
+    tags.push(`@returns {${printType(func.type, sourceFile)}}`);
+    func.type = undefined;
+  }
+  return tags;
+};
+
+// the lines of the JSDoc comments of the given node that are not about types
+const preservedLines = (
+  host: ts.Node,
+  sourceFile: ts.SourceFile
+): string[] => {
+  const jsDocs: ts.JSDoc[] = (host as any).jsDoc ?? [];
+  const lines: string[] = [];
+  jsDocs.forEach((doc) => {
+    if (doc.comment) {
+      lines.push(...doc.comment.split("\n"));
+    }
+    (doc.tags ?? []).forEach((tag) => {
+      if (TYPE_TAGS.includes(tag.tagName.text)) {
+        return;
+      }
+      const text = sourceFile.text
+        .slice(tag.pos, tag.end)
+        .replace(/\*\/\s*$/, "")
+        .split("\n")
+        .map((l) => l.replace(/^\s*\*?\s?/, "").trimRight())
+        .filter((l) => l.length > 0);
+      lines.push(...text);
+    });
+  });
+  return lines;
+};
+
+// replaces the JSDoc comments of the given node with one that has the given tags,
+// keeping the description and the other tags of the original comments.
+const setJsdoc = (
+  host: ts.Node,
+  sourceFile: ts.SourceFile,
+  tags: string[]
+) => {
+  const jsDocs: ts.JSDoc[] = (host as any).jsDoc ?? [];
+  if (jsDocs.length === 0 && tags.length === 0) {
+    return;
+  }
+  const lines = [...preservedLines(host, sourceFile), ...tags];
+
+  // the other leading comments have to be re-attached, as we drop all of them
+  if (host.pos >= 0) {
+    const text = sourceFile.text;
+    let ranges = ts.getLeadingCommentRanges(text, host.pos) ?? [];
+    if (sourceFile.statements[0] === host) {
+      // comments at the top of the file that are followed by a blank line are emitted
+      // with the file, not with the first statement
+      const lastDetached = ranges
+        .map((r, i) => ({ r, i }))
+        .filter(({ r }) =>
+          /\n\s*\n/.test(text.slice(r.end, host.getStart(sourceFile)))
+        )
+        .pop();
+      if (lastDetached) {
+        ranges = ranges.slice(lastDetached.i + 1);
+      }
+    }
+    ts.setEmitFlags(host, ts.EmitFlags.NoLeadingComments);
+    ranges
+      .filter((r) => !jsDocs.some((doc) => doc.pos === r.pos))
+      .forEach((r) => {
+        const comment = text.slice(r.pos, r.end);
+        const inner =
+          r.kind === ts.SyntaxKind.SingleLineCommentTrivia
+            ? comment.slice(2)
+            : comment.slice(2, -2);
+        ts.addSyntheticLeadingComment(
+          host,
+          r.kind,
+          inner,
+          r.hasTrailingNewLine
+        );
+      });
+  }
+
+  if (lines.length > 0) {
+    const body =
+      "*\n" + lines.map((l) => ` * ${l}`.trimRight()).join("\n") + "\n ";
+    ts.addSyntheticLeadingComment(
+      host,
+      ts.SyntaxKind.MultiLineCommentTrivia,
+      body,
+      true
+    );
+  }
+};
+
+// the node that the JSDoc of the given function expression is attached to
+const functionHost = (
+  func: ts.FunctionExpression | ts.ArrowFunction
+): ts.Node => {
+  const parent = func.parent;
+  if (parent && ts.isPropertyAssignment(parent)) {
+    return parent;
+  }
+  return func;
+};
+
+// lowers the inline types of the AST into JSDoc comments, the inverse of
+// `liftJsdocTypes`. types that JSDoc can't express are dropped, i.e. the types of
+// destructuring patterns and of variable statements with multiple declarations.
+export const lowerToJsdoc = (sourceFile: ts.SourceFile) => {
+  const visit = (node: ts.Node) => {
+    if (ts.isVariableStatement(node)) {
+      const decls = node.declarationList.declarations;
+      if (decls.length === 1) {
+        const decl = decls[0];
+        const init = decl.initializer;
+        let tags: string[] = [];
+        const isFunc =
+          !!init && (ts.isArrowFunction(init) || ts.isFunctionExpression(init));
+        if (isFunc) {
+          // functions bound to a variable are documented on the statement
+          tags = functionTags(init as ts.ArrowFunction, sourceFile);
+        }
+        if (decl.type) {
+          if (tags.length === 0) {
+            tags.push(`@type {${printType(decl.type, sourceFile)}}`);
+          }
+          decl.type = undefined;
+        }
+        setJsdoc(node, sourceFile, tags);
+        if (isFunc) {
+          init!.forEachChild(visit);
+          return;
+        }
+      } else {
+        decls.forEach((decl) => {
+          decl.type = undefined;
+        });
+      }
+    } else if (
+      ts.isFunctionDeclaration(node) ||
+      ts.isMethodDeclaration(node) ||
+      ts.isConstructorDeclaration(node)
+    ) {
+      setJsdoc(node, sourceFile, functionTags(node, sourceFile));
+    } else if (ts.isFunctionExpression(node) || ts.isArrowFunction(node)) {
+      setJsdoc(functionHost(node), sourceFile, functionTags(node, sourceFile));
+    } else if (ts.isPropertyDeclaration(node)) {
+      const tags = node.type
+        ? [`@type {${printType(node.type, sourceFile)}}`]
+        : [];
+      node.type = undefined;
+      setJsdoc(node, sourceFile, tags);
+    }
+    node.forEachChild(visit);
+  };
+  sourceFile.forEachChild(visit);
+};
@@ -1,21 +1,44 @@
 import ts from "typescript";
 import * as net from "net";
-import { printSource } from "./printer";
+import { insertHoles, printSource } from "./printer";
 import { makeTree } from "./tree";
 import { stubSource } from "./stubPrinter";
 import { checkCompleted } from "./check";
-import { weavePrograms } from "./weave";
+import { weavePrograms, weaveTypes } from "./weave";
+import { liftJsdocTypes, lowerToJsdoc } from "./jsdoc";
+import { codePrinter } from "./utils";
 import { findUsages } from "./findUsages";
 import { objectInfo } from "./objectInfo";
 import assert from "assert";
+import * as path from "path";
 import { alphaRenameTransformer } from "./aRename";
 import { typedefGen } from "./typedefGen";
+import { pairAnnotations, readRelations, stripTypes } from "./audit";
 
-if (process.argv.length != 4) {
-  console.log("usage: [path to socket] [pid of rust proc]");
+if (process.argv.length < 4 || process.argv.length > 6) {
+  console.log(
+    "usage: [path to socket | --stdio] [pid of rust proc] [--jsdoc (optional)] [project dir or tsconfig.json (optional)]"
+  );
   process.exit(1);
 }
 
+// the optional arguments after the pid
+const extraArgs = process.argv.slice(4);
+// with --jsdoc, the code is javascript and types are written in JSDoc comments
+const useJsdoc = extraArgs.includes("--jsdoc");
+const projectArg = extraArgs.find((arg) => arg !== "--jsdoc");
+// the name of the file that the code of the requests is put in
+const compFile = useJsdoc ? "comp.js" : "comp.ts";
+const compKind = useJsdoc ? ts.ScriptKind.JS : ts.ScriptKind.TS;
+
+// with --stdio, requests come from stdin and responses go to stdout, instead of a socket
+const useStdio = process.argv[2] === "--stdio";
+const stdoutWrite = process.stdout.write.bind(process.stdout);
+if (useStdio) {
+  // stdout is reserved for responses, so all other output has to go to stderr
+  console.log = console.error;
+}
+
 // every 3 seconds, check if the rust proc is still alive, if not we quit
 const rustpid = process.argv[3];
 setInterval(function () {
@@ -49,7 +72,8 @@ const defaultCompilerHost = ts.createCompilerHost(compilerOptions);
 
 const makeCompilerHost = (
   filename: string,
-  sourceFile: ts.SourceFile
+  sourceFile: ts.SourceFile,
+  options: ts.CompilerOptions = compilerOptions
 ): ts.CompilerHost => ({
   getSourceFile: (name, languageVersion) => {
     if (name === filename) {
@@ -60,7 +84,7 @@ const makeCompilerHost = (
   },
   writeFile: (_filename, _data) => {},
   getDefaultLibFileName: () =>
-    defaultCompilerHost.getDefaultLibFileName(compilerOptions),
+    defaultCompilerHost.getDefaultLibFileName(options),
   useCaseSensitiveFileNames: () => false,
   getCanonicalFileName: (filename) => filename,
   getCurrentDirectory: () => "",
@@ -70,35 +94,103 @@ const makeCompilerHost = (
   readFile: () => "",
 });
 
-const createProgram = (code: string, setParentNodes = false): ts.Program => {
+const createProgram = (
+  code: string,
+  setParentNodes = false,
+  options: ts.CompilerOptions = compilerOptions
+): ts.Program => {
   const prog = ts.createProgram({
-    rootNames: ["comp.ts"],
-    options: compilerOptions,
+    rootNames: [compFile],
+    options,
     host: makeCompilerHost(
-      "comp.ts",
+      compFile,
       ts.createSourceFile(
-        "comp.ts",
+        compFile,
         code,
         ts.ScriptTarget.Latest,
         setParentNodes,
-        ts.ScriptKind.TS
-      )
+        compKind
+      ),
+      options
     ),
   });
   return prog;
 };
 
+// the compiler options of typecheck requests, by the options (or config path) of the request
+const requestOptionsCache = new Map<string, ts.CompilerOptions>();
+
+// puts the compiler options of a typecheck request on top of the given options. the request
+// gives either the path to a tsconfig.json, or the options themselves as written in one.
+const withRequestOptions = (
+  base: ts.CompilerOptions,
+  reqOptions: string | object | undefined
+): ts.CompilerOptions => {
+  if (reqOptions === undefined) {
+    return base;
+  }
+  const key = JSON.stringify([base, reqOptions]);
+  const cached = requestOptionsCache.get(key);
+  if (cached) {
+    return cached;
+  }
+
+  let parsed: ts.CompilerOptions;
+  let errors: readonly ts.Diagnostic[];
+  if (typeof reqOptions === "string") {
+    const config = ts.readConfigFile(reqOptions, ts.sys.readFile);
+    if (config.error) {
+      throw new Error(
+        ts.flattenDiagnosticMessageText(config.error.messageText, "\n")
+      );
+    }
+    // the whole config is parsed, such that `extends` is followed
+    const res = ts.parseJsonConfigFileContent(
+      config.config,
+      ts.sys,
+      path.dirname(reqOptions)
+    );
+    parsed = res.options;
+    // the files of the config don't matter, we only check the code of the request
+    errors = res.errors.filter((e) => e.code !== 18003);
+  } else {
+    const res = ts.convertCompilerOptionsFromJson(reqOptions, process.cwd());
+    parsed = res.options;
+    errors = res.errors;
+  }
+  if (errors.length > 0) {
+    throw new Error(
+      errors
+        .map((e) => ts.flattenDiagnosticMessageText(e.messageText, "\n"))
+        .join("\n")
+    );
+  }
+
+  const options = { ...base, ...parsed, noEmit: true };
+  requestOptionsCache.set(key, options);
+  return options;
+};
+
 const handlePrint = (decodedText: string, req: any): string => {
   // create the source file
   const sourceFile = ts.createSourceFile(
     "bleh.ts", // name does not matter until we save, which we don't from here
     decodedText,
     ts.ScriptTarget.Latest,
-    false, // for setParentNodes
-    ts.ScriptKind.TS
+    useJsdoc, // for setParentNodes, needed to find the JSDoc of the nodes
+    compKind
   );
   req.typeName = req.typeName || "_hole_"; // default to _hole_
-  const res = printSource(sourceFile, req.typeName, req.types);
+  let res: string;
+  if (useJsdoc) {
+    // existing JSDoc types are lifted, such that they don't get holes
+    liftJsdocTypes(sourceFile);
+    insertHoles(sourceFile, req.typeName, req.types);
+    lowerToJsdoc(sourceFile);
+    res = codePrinter.printFile(sourceFile);
+  } else {
+    res = printSource(sourceFile, req.typeName, req.types);
+  }
   const base64 = Buffer.from(res).toString("base64");
   return JSON.stringify({
     type: "printResponse",
@@ -140,6 +232,66 @@ const handleStub = (decodedText: string): string => {
   });
 };
 
+const handleStrip = (decodedText: string, req: any): string => {
+  const sourceFile = ts.createSourceFile(
+    "bleh.ts",
+    decodedText,
+    ts.ScriptTarget.Latest,
+    useJsdoc, // for setParentNodes, needed to find the JSDoc of the nodes
+    compKind
+  );
+  if (useJsdoc) {
+    liftJsdocTypes(sourceFile);
+    stripTypes(sourceFile, req.types);
+    lowerToJsdoc(sourceFile);
+  } else {
+    stripTypes(sourceFile, req.types);
+  }
+  const base64 = Buffer.from(codePrinter.printFile(sourceFile)).toString(
+    "base64"
+  );
+  return JSON.stringify({
+    type: "stripResponse",
+    text: base64,
+  });
+};
+
+const handleAudit = (decodedText: string, req: any): string => {
+  const decodedOriginal = Buffer.from(req.original, "base64").toString("utf8");
+  const originalFile = ts.createSourceFile(
+    "bleh.ts",
+    decodedOriginal,
+    ts.ScriptTarget.Latest,
+    true, // for setParentNodes
+    compKind
+  );
+  const completedFile = ts.createSourceFile(
+    "bleh.ts",
+    decodedText,
+    ts.ScriptTarget.Latest,
+    true, // for setParentNodes
+    compKind
+  );
+
+  // the relations are found by the type checker, with a probe appended to the completed code
+  const [audits, probe] = pairAnnotations(
+    originalFile,
+    completedFile,
+    req.types
+  );
+  const probeProgram = createProgram(decodedText + "\n" + probe);
+  const annotations = readRelations(
+    probeProgram,
+    probeProgram.getSourceFile(compFile)!,
+    audits
+  );
+
+  return JSON.stringify({
+    type: "auditResponse",
+    annotations,
+  });
+};
+
 const handleCheck = (decodedText: string, req: any): string => {
   const decodedOriginal = Buffer.from(req.original, "base64").toString("utf8");
   // create the source file
@@ -147,12 +299,17 @@ const handleCheck = (decodedText: string, req: any): string => {
     "bleh.ts", // name does not matter until we save, which we don't from here
     decodedOriginal,
     ts.ScriptTarget.Latest,
-    false, // for setParentNodes
-    ts.ScriptKind.TS
+    useJsdoc, // for setParentNodes, needed to find the JSDoc of the nodes
+    compKind
   );
 
-  const completedProgram = createProgram(decodedText);
-  const completedFile = completedProgram.getSourceFile("comp.ts")!;
+  const completedProgram = createProgram(decodedText, useJsdoc);
+  const completedFile = completedProgram.getSourceFile(compFile)!;
+  if (useJsdoc) {
+    // the heuristic works on inline types, the checker understands the lifted JSDoc types
+    liftJsdocTypes(originalFile);
+    liftJsdocTypes(completedFile);
+  }
 
   const res = checkCompleted(
     originalFile,
@@ -164,6 +321,7 @@ const handleCheck = (decodedText: string, req: any): string => {
     type: "checkResponse",
     problems: res[0],
     score: res[1],
+    quality: res[2],
   });
 };
 
@@ -179,7 +337,21 @@ const handleWeave = (decodedText: string, req: any): string => {
   const originalProgram = createProgram(decodedText, true);
   const nettleProgram = createProgram(decodedNettle, true);
 
-  const res = weavePrograms(originalProgram, nettleProgram, req.level);
+  let res: string;
+  if (useJsdoc) {
+    liftJsdocTypes(originalProgram.getSourceFile(compFile)!);
+    liftJsdocTypes(nettleProgram.getSourceFile(compFile)!);
+    const woven = weaveTypes(
+      originalProgram,
+      nettleProgram,
+      req.level,
+      compFile
+    );
+    lowerToJsdoc(woven);
+    res = codePrinter.printFile(woven);
+  } else {
+    res = weavePrograms(originalProgram, nettleProgram, req.level);
+  }
 
   const base64 = Buffer.from(res).toString("base64");
 
@@ -265,125 +437,319 @@ const handleTypedefGen = (decodedText: string): string => {
   });
 };
 
-const handleTypeCheck = (decodedText: string): string => {
-  const completedProgram = createProgram(decodedText, false);
-  const completedFile = completedProgram.getSourceFile("comp.ts")!;
-  const diag = ts.getPreEmitDiagnostics(completedProgram, completedFile);
+// converts a diagnostic of the compiler into the format of the typecheck response.
+// lines and columns are 1-based.
+const toDiagnostic = (d: ts.Diagnostic) => {
+  let span = null;
+  if (d.file && d.start !== undefined) {
+    const start = d.file.getLineAndCharacterOfPosition(d.start);
+    const end = d.file.getLineAndCharacterOfPosition(d.start + (d.length ?? 0));
+    span = {
+      startLine: start.line + 1,
+      startCol: start.character + 1,
+      endLine: end.line + 1,
+      endCol: end.character + 1,
+    };
+  }
+  return {
+    span,
+    code: "TS" + d.code,
+    message: ts.flattenDiagnosticMessageText(d.messageText, "\n"),
+    category: ts.DiagnosticCategory[d.category].toLowerCase(),
+  };
+};
+
+// in project mode, the files and options of the project that candidates are checked against
+type Project = {
+  // the directory that the files of the requests are relative to
+  root: string;
+  rootNames: string[];
+  options: ts.CompilerOptions;
+  host: ts.CompilerHost;
+  // the program of the last check, reused to avoid re-parsing the unchanged files
+  oldProgram?: ts.Program;
+};
+
+// loads the project from the given directory or tsconfig.json. if there is no tsconfig.json,
+// all the source files of the directory are part of the project, with the default options.
+const loadProject = (projectPath: string): Project => {
+  let configPath: string | undefined = undefined;
+  if (ts.sys.fileExists(projectPath)) {
+    configPath = projectPath;
+  } else if (ts.sys.fileExists(path.join(projectPath, "tsconfig.json"))) {
+    configPath = path.join(projectPath, "tsconfig.json");
+  }
+
+  let rootNames: string[];
+  let options: ts.CompilerOptions;
+  if (configPath) {
+    const config = ts.readConfigFile(configPath, ts.sys.readFile);
+    if (config.error) {
+      console.log(ts.flattenDiagnosticMessageText(config.error.messageText, "\n"));
+      process.exit(1);
+    }
+    const parsed = ts.parseJsonConfigFileContent(
+      config.config,
+      ts.sys,
+      path.dirname(configPath)
+    );
+    rootNames = parsed.fileNames;
+    options = parsed.options;
+  } else {
+    rootNames = ts.sys.readDirectory(
+      projectPath,
+      [".ts", ".tsx", ".js", ".jsx"],
+      ["node_modules"]
+    );
+    options = { ...compilerOptions };
+  }
+  options.noEmit = true;
+
+  // the unchanged files of the project are parsed only once
+  const base = ts.createCompilerHost(options);
+  const cache = new Map<string, ts.SourceFile | undefined>();
+  const host: ts.CompilerHost = {
+    ...base,
+    getSourceFile: (name, languageVersion) => {
+      if (!cache.has(name)) {
+        cache.set(name, base.getSourceFile(name, languageVersion));
+      }
+      return cache.get(name);
+    },
+  };
+
+  const root = configPath ? path.dirname(configPath) : projectPath;
+  return { root, rootNames, options, host };
+};
+
+const project: Project | undefined = projectArg
+  ? loadProject(path.resolve(projectArg))
+  : undefined;
+
+// type checks the given code as a replacement of `file` of the project, only the
+// diagnostics of that file are reported.
+const typeCheckInProject = (
+  project: Project,
+  code: string,
+  file: string,
+  options: ts.CompilerOptions = project.options
+): readonly ts.Diagnostic[] => {
+  const fileName = path.resolve(project.root, file);
+  const candidate = ts.createSourceFile(
+    fileName,
+    code,
+    options.target ?? ts.ScriptTarget.Latest
+  );
+  const host: ts.CompilerHost = {
+    ...project.host,
+    getSourceFile: (name, languageVersion) =>
+      path.resolve(name) === fileName
+        ? candidate
+        : project.host.getSourceFile(name, languageVersion),
+    fileExists: (name) =>
+      path.resolve(name) === fileName || project.host.fileExists(name),
+  };
+  const rootNames = project.rootNames.includes(fileName)
+    ? project.rootNames
+    : [...project.rootNames, fileName];
+  const program = ts.createProgram({
+    rootNames,
+    options,
+    host,
+    oldProgram: project.oldProgram,
+  });
+  project.oldProgram = program;
+  return ts.getPreEmitDiagnostics(program, program.getSourceFile(fileName));
+};
+
+const handleTypeCheck = (decodedText: string, req: any): string => {
+  let diag: readonly ts.Diagnostic[];
+  if (project && req.file) {
+    const options = withRequestOptions(project.options, req.compilerOptions);
+    diag = typeCheckInProject(project, decodedText, req.file, options);
+  } else {
+    const options = withRequestOptions(compilerOptions, req.compilerOptions);
+    const completedProgram = createProgram(decodedText, false, options);
+    const completedFile = completedProgram.getSourceFile(compFile)!;
+    diag = ts.getPreEmitDiagnostics(completedProgram, completedFile);
+  }
   return JSON.stringify({
     type: "typeCheckResponse",
-    errors: diag.length,
+    // only errors are counted, like the `is_error` diagnostics of the client
+    errors: diag.filter((d) => d.category === ts.DiagnosticCategory.Error)
+      .length,
+    diagnostics: diag.map(toDiagnostic),
   });
 };
 
-var unixServer = net.createServer(function (client) {
-  let completeData = "";
-  const END_TOKEN = "??END??";
-  client.on("data", function (data) {
-    // try to parse the data as a json object
+// the version of the socket protocol that this server speaks
+const PROTOCOL_VERSION = 1;
+
+const handleCapabilities = (): string => {
+  return JSON.stringify({
+    type: "capabilitiesResponse",
+    commands: [
+      "capabilities",
+      "print",
+      "tree",
+      "stub",
+      "check",
+      "weave",
+      "usages",
+      "objectInfo",
+      "typedefGen",
+      "typecheck",
+      "strip",
+      // the probe of the audit is written in typescript
+      ...(useJsdoc ? [] : ["audit"]),
+    ],
+    protocolVersion: PROTOCOL_VERSION,
+    anyType: "any",
+    holeToken: "_hole_",
+  });
+};
+
+// handles a complete request (without the end token), returns the response
+const handleRequest = (completeData: string): string => {
+  var req; // in the format of {cmd: "the-cmd", text: "the-text", ...}
+  var decodedText;
+  try {
+    req = JSON.parse(completeData);
+    decodedText = Buffer.from(req.text, "base64").toString("utf8");
+  } catch (e) {
+    assert(e instanceof Error);
+    return JSON.stringify({ type: "error", message: e.message });
+  }
+
+  try {
+    switch (req.cmd) {
+      // report the supported commands, protocol version, any type and hole token
+      // req: {cmd: "capabilities", text: ""}
+      case "capabilities": {
+        return handleCapabilities();
+      }
+      // simply print out the text (and puts unknown types).
+      // req: {cmd: "print", text: "the-text", typeName: "the-type"}
+      case "print": {
+        return handlePrint(decodedText, req);
+      }
+      // generate the text tree from the given text (and puts unknown types)
+      case "tree": {
+        return handleTree(decodedText);
+      }
+      // generate a stub for the given node (that is type-annotated)
+      case "stub": {
+        return handleStub(decodedText);
+      }
+      // check if the given text is complete
+      // req: {cmd: "check", text: "the-completed-text", original: "the-original-text"}
+      // additionally, returns a score for the completion.
+      case "check": {
+        return handleCheck(decodedText, req);
+      }
+      // weaves the given text (has to be type-complete, could be stubbed) into the original text
+      // req: {cmd: "weage", text: "original text", nettle: "the text to weave in", level: 0}
+      case "weave": {
+        return handleWeave(decodedText, req);
+      }
+      // finds usages of the given inner block in the outer block
+      // req: {cmd: "usages", text: "outer block", innerBlock: "inner block"}
+      case "usages": {
+        return handleUsages(decodedText, req);
+      }
+      // generate the object info map for the given file contents
+      case "objectInfo": {
+        return handleObjectInfo(decodedText);
+      }
+      // construct the type definition template
+      case "typedefGen": {
+        return handleTypedefGen(decodedText);
+      }
+      // typecheck the given file contents, returns the number of errors and the diagnostics.
+      // in project mode, req.file is the file of the project that the contents replace.
+      // req.compilerOptions are either the options or a path to a tsconfig.json
+      case "typecheck": {
+        return handleTypeCheck(decodedText, req);
+      }
+      // removes the existing types of the given kinds, the inverse of print
+      // req: {cmd: "strip", text: "the-text", types: ["FuncDecl", ...]}
+      case "strip": {
+        return handleStrip(decodedText, req);
+      }
+      // compares the types of the original text with the ones of the completed text
+      // req: {cmd: "audit", text: "the-completed-text", original: "the-original-text", types: [...]}
+      case "audit": {
+        return handleAudit(decodedText, req);
+      }
+      default: {
+        return JSON.stringify({
+          type: "error",
+          message: `unknown command ${req.cmd}`,
+        });
+      }
+    }
+    // yeah, pretty bad to catch all, but we want this to work no matter what.
+  } catch (e) {
+    assert(e instanceof Error);
+    return JSON.stringify({
+      type: "error",
+      message: e.message + "\ntrace: \n" + e.stack,
+    });
+  }
+};
+
+const END_TOKEN = "??END??";
 
-    var req; // in the format of {cmd: "the-cmd", text: "the-text", ...}
-    var decodedText;
-    try {
+const serveSocket = (socket: string) => {
+  var unixServer = net.createServer(function (client) {
+    let completeData = "";
+    client.on("data", function (data) {
       const strData = data.toString();
       if (!strData.endsWith(END_TOKEN)) {
         completeData += strData;
         return;
       }
       completeData += strData.substring(0, strData.length - END_TOKEN.length);
+      client.write(handleRequest(completeData));
+    });
+  });
 
-      req = JSON.parse(completeData);
-      decodedText = Buffer.from(req.text, "base64").toString("utf8");
-    } catch (e) {
-      assert(e instanceof Error);
-      client.write(JSON.stringify({ type: "error", message: e.message }));
-      return;
-    }
+  unixServer.listen(socket);
+  console.log("Listening on " + socket + "\n");
 
-    try {
-      switch (req.cmd) {
-        // simply print out the text (and puts unknown types).
-        // req: {cmd: "print", text: "the-text", typeName: "the-type"}
-        case "print": {
-          client.write(handlePrint(decodedText, req));
-          break;
-        }
-        // generate the text tree from the given text (and puts unknown types)
-        case "tree": {
-          client.write(handleTree(decodedText));
-          break;
-        }
-        // generate a stub for the given node (that is type-annotated)
-        case "stub": {
-          client.write(handleStub(decodedText));
-          break;
-        }
-        // check if the given text is complete
-        // req: {cmd: "check", text: "the-completed-text", original: "the-original-text"}
-        // additionally, returns a score for the completion.
-        case "check": {
-          client.write(handleCheck(decodedText, req));
-          break;
-        }
-        // weaves the given text (has to be type-complete, could be stubbed) into the original text
-        // req: {cmd: "weage", text: "original text", nettle: "the text to weave in", level: 0}
-        case "weave": {
-          client.write(handleWeave(decodedText, req));
-          break;
-        }
-        // finds usages of the given inner block in the outer block
-        // req: {cmd: "usages", text: "outer block", innerBlock: "inner block"}
-        case "usages": {
-          client.write(handleUsages(decodedText, req));
-          break;
-        }
-        // generate the object info map for the given file contents
-        case "objectInfo": {
-          client.write(handleObjectInfo(decodedText));
-          break;
-        }
-        // construct the type definition template
-        case "typedefGen": {
-          client.write(handleTypedefGen(decodedText));
-          break;
-        }
-        // typecheck the given file contents, returns the number of errors
-        case "typecheck": {
-          client.write(handleTypeCheck(decodedText));
-          break;
-        }
-        default: {
-          client.write(
-            JSON.stringify({
-              type: "error",
-              message: `unknown command ${req.cmd}`,
-            })
-          );
-        }
-      }
-      // yeah, pretty bad to catch all, but we want this to work no matter what.
-    } catch (e) {
-      assert(e instanceof Error);
-      client.write(
-        JSON.stringify({
-          type: "error",
-          message: e.message + "\ntrace: \n" + e.stack,
-        })
-      );
+  // handlers to close the server, or the socket will remain open forever
+  process.on("exit", close);
+  process.on("SIGINT", close);
+  process.on("SIGTERM", close);
+
+  function close() {
+    console.log("Closing " + socket);
+    unixServer.close();
+    process.exit(0);
+  }
+};
+
+// serves requests from stdin, writing a response per line to stdout. responses end with the
+// end token too, such that output of libraries on stdout is not taken for a response
+const serveStdio = () => {
+  let pending = "";
+  process.stdin.on("data", function (data) {
+    pending += data.toString();
+    let idx;
+    while ((idx = pending.indexOf(END_TOKEN)) !== -1) {
+      const completeData = pending.substring(0, idx);
+      pending = pending.substring(idx + END_TOKEN.length);
+      stdoutWrite(handleRequest(completeData) + END_TOKEN + "\n");
     }
   });
-});
-
-const socket = process.argv[2];
-unixServer.listen(socket);
-console.log("Listening on " + socket + "\n");
+  // the client went away, nothing left to do
+  process.stdin.on("end", () => process.exit(0));
 
-// handlers to close the server, or the socket will remain open forever
-process.on("exit", close);
-process.on("SIGINT", close);
-process.on("SIGTERM", close);
+  stdoutWrite("Listening on stdio\n");
+};
 
-function close() {
-  console.log("Closing " + socket);
-  unixServer.close();
-  process.exit(0);
+if (useStdio) {
+  serveStdio();
+} else {
+  serveSocket(process.argv[2]);
 }
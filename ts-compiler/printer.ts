@@ -6,14 +6,22 @@ import {
   typeTraversal,
 } from "./utils";
 
-// from langserver.rs in the Rust client
+// from langserver.rs in the Rust client. "Param", "Return" and "Destructuring" are
+// positions, they select which slots of the other kinds are annotated.
 export type AnnotateType =
   | "VarDecl"
   | "FuncDecl"
   | "FuncExpr"
   | "ClassProp"
   | "ClassMethod"
-  | "TypeDecl";
+  | "TypeDecl"
+  | "ObjectLiteralMember"
+  | "TypeParam"
+  | "CatchClause"
+  | "Param"
+  | "Return"
+  | "Destructuring";
+// the types that are annotated by default. the ones in `optionalTypes` have to be asked for.
 export const allTypes: AnnotateType[] = [
   "VarDecl",
   "FuncDecl",
@@ -21,7 +29,34 @@ export const allTypes: AnnotateType[] = [
   "ClassProp",
   "ClassMethod",
   "TypeDecl",
+  "ObjectLiteralMember",
+  "Param",
+  "Return",
+  "Destructuring",
 ];
+// type parameter constraints change the signature of generics, and catch clause variables
+// can only be typed with `any` or `unknown` (TS1196), so they are opt-in.
+export const optionalTypes: AnnotateType[] = ["TypeParam", "CatchClause"];
+export const allWithOptionalTypes: AnnotateType[] = [
+  ...allTypes,
+  ...optionalTypes,
+];
+// the positions, which select the slots of the other kinds
+export const positionTypes: AnnotateType[] = ["Param", "Return", "Destructuring"];
+
+// a list that names none of the positions was written before they were added: every position
+// is annotated, and the methods of object literals are annotated along with "ClassMethod".
+// same as `AnnotateType::with_legacy_positions` in the Rust client.
+export const withLegacyPositions = (list: AnnotateType[]): AnnotateType[] => {
+  if (positionTypes.some((p) => list.includes(p))) {
+    return list;
+  }
+  const legacy = [...list, ...positionTypes];
+  if (list.includes("ClassMethod") && !list.includes("ObjectLiteralMember")) {
+    legacy.push("ObjectLiteralMember");
+  }
+  return legacy;
+};
 
 export const printSource = (
   sourceFile: ts.SourceFile,
@@ -29,6 +64,19 @@ export const printSource = (
   annotateTypes: AnnotateType[]
 ): string => {
   console.log(annotateTypes);
+  insertHoles(sourceFile, typeName, annotateTypes);
+
+  // Print the new code
+  return codePrinter.printFile(sourceFile);
+};
+
+// puts a hole of the given name in every missing type of the given kinds
+export const insertHoles = (
+  sourceFile: ts.SourceFile,
+  typeName: string,
+  annotateTypes: AnnotateType[]
+) => {
+  annotateTypes = withLegacyPositions(annotateTypes);
   // Update the source file statements
   sourceFile.forEachChild((child) => {
     typeTraversal(
@@ -49,11 +97,20 @@ export const printSource = (
               declaration.initializer as ts.ArrowFunction;
 
             // get the arguments
-            aFunc.parameters.map((p) => {
-              p.type = p.type ?? createFakeType(typeName);
-            });
+            if (annotateTypes.includes("Param")) {
+              aFunc.parameters.forEach((p) => {
+                if (
+                  ts.isIdentifier(p.name) ||
+                  annotateTypes.includes("Destructuring")
+                ) {
+                  p.type = p.type ?? createFakeType(typeName);
+                }
+              });
+            }
 
-            aFunc.type = aFunc.type ?? createFakeType(typeName);
+            if (annotateTypes.includes("Return")) {
+              aFunc.type = aFunc.type ?? createFakeType(typeName);
+            }
 
             // NOTE: remember, this is the vardecl, not the function,
             // we want to keep the vardecl type as undefined in this case
@@ -67,7 +124,4 @@ export const printSource = (
       annotateTypes
     );
   });
-
-  // Print the new code
-  return codePrinter.printFile(sourceFile);
 };
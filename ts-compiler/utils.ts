@@ -1,5 +1,9 @@
 import ts from "typescript";
-import { allTypes, AnnotateType } from "./printer";
+import {
+  allWithOptionalTypes,
+  AnnotateType,
+  withLegacyPositions,
+} from "./printer";
 
 // unions two sets into a new set
 export const setUnion = <T>(a: Set<T>, b: Set<T>): Set<T> => {
@@ -70,32 +74,54 @@ export const typeTraversal = (
     inner_child: ts.Node
   ) => ts.TypeNode | undefined,
   // default to all types
-  visit_list: AnnotateType[] = allTypes
+  visit_list: AnnotateType[] = allWithOptionalTypes,
+  // the parent of the node, as the source file may not have parent nodes set
+  parent: ts.Node | undefined = node.parent
 ) => {
+  visit_list = withLegacyPositions(visit_list);
+  // the positions of the selected kinds that are visited
+  const destructuring = visit_list.includes("Destructuring");
+  const visitParams = (
+    parameters: ts.NodeArray<ts.ParameterDeclaration>
+  ) => {
+    if (!visit_list.includes("Param")) {
+      return;
+    }
+    parameters.forEach((parameter) => {
+      if (destructuring || ts.isIdentifier(parameter.name)) {
+        parameter.type = visitor(parameter.type, parameter);
+      }
+    });
+  };
+  const visitReturn = (node: ts.SignatureDeclarationBase) => {
+    if (visit_list.includes("Return")) {
+      node.type = visitor(node.type, node); // NOTE: return type
+    }
+  };
+
   if (
     (ts.isFunctionExpression(node) || ts.isArrowFunction(node)) &&
     visit_list.includes("FuncExpr")
   ) {
-    node.type = visitor(node.type, node); // NOTE: return type
-    node.parameters.forEach((parameter) => {
-      parameter.type = visitor(parameter.type, parameter);
-    });
+    visitReturn(node);
+    visitParams(node.parameters);
   } else if (
     ts.isFunctionDeclaration(node) &&
     visit_list.includes("FuncDecl")
   ) {
-    node.type = visitor(node.type, node); // NOTE: return type
-    node.parameters.forEach((parameter) => {
-      parameter.type = visitor(parameter.type, parameter);
-    });
+    visitReturn(node);
+    visitParams(node.parameters);
   } else if (
     ts.isMethodDeclaration(node) &&
-    visit_list.includes("ClassMethod")
+    // methods of object literals are also method declarations
+    visit_list.includes(
+      parent && ts.isObjectLiteralExpression(parent)
+        ? "ObjectLiteralMember"
+        : "ClassMethod"
+    )
   ) {
-    node.type = visitor(node.type, node);
-    node.parameters.forEach((parameter) => {
-      parameter.type = visitor(parameter.type, parameter);
-    });
+    visitReturn(node);
+    visitParams(node.parameters);
   } else if (
     ts.isPropertyDeclaration(node) &&
     visit_list.includes("ClassProp")
@@ -103,7 +129,9 @@ export const typeTraversal = (
     node.type = visitor(node.type, node);
   } else if (ts.isVariableStatement(node) && visit_list.includes("VarDecl")) {
     node.declarationList.declarations.forEach((declaration) => {
-      declaration.type = visitor(declaration.type, declaration);
+      if (destructuring || ts.isIdentifier(declaration.name)) {
+        declaration.type = visitor(declaration.type, declaration);
+      }
     });
   } else if (ts.isPropertySignature(node) && visit_list.includes("TypeDecl")) {
     node.type = visitor(node.type, node);
@@ -112,9 +140,7 @@ export const typeTraversal = (
     // this falls under FuncExpr
     visit_list.includes("FuncExpr")
   ) {
-    node.parameters.forEach((parameter) => {
-      parameter.type = visitor(parameter.type, parameter);
-    });
+    visitParams(node.parameters);
     node.type = visitor(node.type, node) ?? node.type;
   } else if (
     ts.isConstructorDeclaration(node) &&
@@ -122,12 +148,25 @@ export const typeTraversal = (
     visit_list.includes("FuncDecl")
   ) {
     // no need for return type for constructors, why does typescript even declare it?
-    node.parameters.forEach((parameter) => {
-      parameter.type = visitor(parameter.type, parameter);
-    });
+    visitParams(node.parameters);
+  } else if (
+    ts.isTypeParameterDeclaration(node) &&
+    visit_list.includes("TypeParam")
+  ) {
+    node.constraint = visitor(node.constraint, node);
+  } else if (
+    ts.isCatchClause(node) &&
+    node.variableDeclaration &&
+    visit_list.includes("CatchClause")
+  ) {
+    // the clause is given to the visitor, as the declaration may not know its parent
+    const declaration = node.variableDeclaration;
+    if (destructuring || ts.isIdentifier(declaration.name)) {
+      declaration.type = visitor(declaration.type, node);
+    }
   }
 
-  node.forEachChild((c) => typeTraversal(c, visitor, visit_list));
+  node.forEachChild((c) => typeTraversal(c, visitor, visit_list, node));
 };
 
 export function getDeepMutableClone<T extends ts.Node>(node: T): T {
@@ -139,8 +139,19 @@ export const weavePrograms = (
   nettle: ts.Program,
   nettleLevel: number // the level of the nettle in the tree, 0 is the root.
 ): string => {
-  let sourceFile = target.getSourceFile("comp.ts")!;
-  let nettleFile = nettle.getSourceFile("comp.ts")!;
+  return codePrinter.printFile(weaveTypes(target, nettle, nettleLevel));
+};
+
+// weaves the types of the nettle into the target, returns the woven source file of the target.
+// `fileName` is the name of the source file in both programs.
+export const weaveTypes = (
+  target: ts.Program,
+  nettle: ts.Program,
+  nettleLevel: number,
+  fileName: string = "comp.ts"
+): ts.SourceFile => {
+  let sourceFile = target.getSourceFile(fileName)!;
+  let nettleFile = nettle.getSourceFile(fileName)!;
   target.getTypeChecker();
   const nettleChecker = nettle.getTypeChecker();
 
@@ -304,5 +315,5 @@ export const weavePrograms = (
 
   sourceFile.forEachChild((child) => weaveNode(child, "", 0));
 
-  return codePrinter.printFile(sourceFile);
+  return sourceFile;
 };